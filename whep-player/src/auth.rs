@@ -0,0 +1,317 @@
+use anyhow::{Result, anyhow};
+use axum::{
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+use tracing::{info, warn};
+use whep_player::AuthArgs;
+
+/// WHIP 推流端使用的 JWT 中的 claims
+/// * 还必须有 exp (过期时间), 由 jsonwebtoken 检查, 限制 token 被截获后可以使用的时间
+#[derive(Debug, Deserialize)]
+pub struct WhipClaims {
+    pub whip_url: String, // 允许推流的 WHIP URL, 只比较其中的路径
+    pub jti: String,      // 同一时刻只能有一个会话使用这个 token
+}
+
+/// 鉴权失败的原因
+#[derive(Debug)]
+pub enum AuthError {
+    Missing,                 // 没有 Bearer token, 401
+    Invalid(String),         // token 无效或者已经过期, 401
+    Forbidden(&'static str), // token 有效但不允许这个请求, 403
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        // RFC 6750: 通过 WWW-Authenticate 说明需要什么样的 Bearer token
+        let (status, challenge, reason) = match self {
+            AuthError::Missing => (
+                StatusCode::UNAUTHORIZED,
+                "Bearer",
+                "missing token".to_string(),
+            ),
+            AuthError::Invalid(reason) => (
+                StatusCode::UNAUTHORIZED,
+                "Bearer error=\"invalid_token\"",
+                reason,
+            ),
+            AuthError::Forbidden(reason) => (
+                StatusCode::FORBIDDEN,
+                "Bearer error=\"insufficient_scope\"",
+                reason.to_string(),
+            ),
+        };
+        (status, [(header::WWW_AUTHENTICATE, challenge)], reason).into_response()
+    }
+}
+
+/// WHIP 服务端的 Bearer token 鉴权
+/// * 接受命令行指定的固定 token, 或者使用 HS256 / RS256 签名的 JWT
+/// * JWT 的 whip_url 必须与请求的路径一致, 同一个 jti 同时只能有一个会话, 防止 token 被截获后重放
+/// * 会话结束后 jti 被释放, 推流端断线重连时可以继续使用同一个 token, 直到它过期
+pub struct Auth {
+    tokens: Vec<String>,
+    jwt: Option<(DecodingKey, Validation)>,
+    active: Arc<Mutex<HashSet<String>>>, // 正在被会话使用的 jti
+}
+
+/// 通过鉴权的请求使用的 token
+/// * JWT 的 jti 被占用到 Grant 释放 (会话结束) 为止
+#[derive(Debug)]
+pub struct Grant {
+    token: String,
+    jti: Option<JtiLease>,
+}
+
+#[derive(Debug)]
+struct JtiLease {
+    jti: String,
+    active: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Grant {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Drop for JtiLease {
+    fn drop(&mut self) {
+        self.active.lock().unwrap().remove(&self.jti);
+    }
+}
+
+impl Auth {
+    /// 没有配置任何 token 时返回 None, 不做鉴权
+    pub fn new(args: &AuthArgs) -> Result<Option<Self>> {
+        let jwt = match (&args.jwt_secret, &args.jwt_public_key) {
+            (Some(secret), _) => Some((
+                DecodingKey::from_secret(secret.as_bytes()),
+                Validation::new(Algorithm::HS256),
+            )),
+            (None, Some(path)) => {
+                let pem = std::fs::read(path)
+                    .map_err(|err| anyhow!("read {}: {}", path.display(), err))?;
+                Some((
+                    DecodingKey::from_rsa_pem(&pem)?,
+                    Validation::new(Algorithm::RS256),
+                ))
+            }
+            (None, None) => None,
+        };
+        if args.auth_tokens.is_empty() && jwt.is_none() {
+            warn!("WHIP server accepts requests without authentication");
+            return Ok(None);
+        }
+
+        let jwt = jwt.map(|(key, mut validation)| {
+            // 默认要求 exp 并检查是否过期, 没有 exp 的 token 会一直有效, 不接受
+            validation.validate_aud = false;
+            (key, validation)
+        });
+        info!(
+            "WHIP server authentication: {} static tokens, JWT: {}",
+            args.auth_tokens.len(),
+            jwt.is_some()
+        );
+
+        Ok(Some(Self {
+            tokens: args.auth_tokens.clone(),
+            jwt,
+            active: Arc::new(Mutex::new(HashSet::new())),
+        }))
+    }
+
+    /// 检查创建会话的请求, 返回请求使用的 token
+    /// * 会话创建后, 对它的 PATCH / DELETE 只需要带上同一个 token
+    /// * 会话需要一直持有返回的 Grant, 释放后 JWT 才能再次用来创建会话
+    pub fn authorize(&self, headers: &HeaderMap, path: &str) -> Result<Grant, AuthError> {
+        let token = bearer(headers).ok_or(AuthError::Missing)?;
+        if self.tokens.iter().any(|t| constant_time_eq(t, token)) {
+            return Ok(Grant {
+                token: token.to_string(),
+                jti: None,
+            });
+        }
+
+        let Some((key, validation)) = &self.jwt else {
+            return Err(AuthError::Invalid("unknown token".into()));
+        };
+        let claims = jsonwebtoken::decode::<WhipClaims>(token, key, validation)
+            .map_err(|err| AuthError::Invalid(err.to_string()))?
+            .claims;
+
+        // whip_url 可以是完整的 URL, 也可以只是路径
+        let whip_path = reqwest::Url::parse(&claims.whip_url)
+            .map(|url| url.path().to_string())
+            .unwrap_or(claims.whip_url);
+        if whip_path.trim_end_matches('/') != path.trim_end_matches('/') {
+            warn!("token for {} used on {}", whip_path, path);
+            return Err(AuthError::Forbidden("whip_url doesn't match"));
+        }
+
+        if !self.active.lock().unwrap().insert(claims.jti.clone()) {
+            warn!("token jti {} is used by another session", claims.jti);
+            return Err(AuthError::Forbidden("token is in use by another session"));
+        }
+
+        Ok(Grant {
+            token: token.to_string(),
+            jti: Some(JtiLease {
+                jti: claims.jti,
+                active: self.active.clone(),
+            }),
+        })
+    }
+}
+
+/// Authorization 头中的 Bearer token
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+/// 比较 token 时不因为第一个不同的字节提前返回, 避免通过响应时间逐字节猜出 token
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    const SECRET: &str = "secret";
+
+    fn auth() -> Auth {
+        Auth::new(&AuthArgs {
+            auth_tokens: vec!["static".to_string()],
+            jwt_secret: Some(SECRET.to_string()),
+            jwt_public_key: None,
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    fn jwt(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn no_auth_without_tokens() {
+        let args = AuthArgs {
+            auth_tokens: vec![],
+            jwt_secret: None,
+            jwt_public_key: None,
+        };
+        assert!(Auth::new(&args).unwrap().is_none());
+    }
+
+    #[test]
+    fn static_token() {
+        let auth = auth();
+        let grant = auth.authorize(&headers("static"), "/whip").unwrap();
+        assert_eq!(grant.token(), "static");
+        // 固定 token 可以同时用于多个会话
+        assert!(auth.authorize(&headers("static"), "/whip").is_ok());
+
+        assert!(matches!(
+            auth.authorize(&HeaderMap::new(), "/whip"),
+            Err(AuthError::Missing)
+        ));
+        assert!(matches!(
+            auth.authorize(&headers("other"), "/whip"),
+            Err(AuthError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn jwt_claims() {
+        let auth = auth();
+        let token = jwt(serde_json::json!({
+            "whip_url": "https://example.com/whip/stream/",
+            "jti": "a",
+            "exp": now() + 3600,
+        }));
+        assert!(matches!(
+            auth.authorize(&headers(&token), "/whip/other"),
+            Err(AuthError::Forbidden(_))
+        ));
+        let grant = auth.authorize(&headers(&token), "/whip/stream").unwrap();
+        assert_eq!(grant.token(), token);
+
+        // 没有 exp 或者已经过期的 token 无效
+        let token = jwt(serde_json::json!({"whip_url": "/whip", "jti": "b"}));
+        assert!(matches!(
+            auth.authorize(&headers(&token), "/whip"),
+            Err(AuthError::Invalid(_))
+        ));
+        let token = jwt(serde_json::json!({"whip_url": "/whip", "jti": "c", "exp": now() - 3600}));
+        assert!(matches!(
+            auth.authorize(&headers(&token), "/whip"),
+            Err(AuthError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn jti_released_when_session_ends() {
+        let auth = auth();
+        let token = jwt(serde_json::json!({"whip_url": "/whip", "jti": "a", "exp": now() + 3600}));
+        let grant = auth.authorize(&headers(&token), "/whip").unwrap();
+
+        // 会话还在时重放同一个 token 被拒绝, 其他 jti 不受影响
+        assert!(matches!(
+            auth.authorize(&headers(&token), "/whip"),
+            Err(AuthError::Forbidden(_))
+        ));
+        let other = jwt(serde_json::json!({"whip_url": "/whip", "jti": "b", "exp": now() + 3600}));
+        let _other = auth.authorize(&headers(&other), "/whip").unwrap();
+
+        // 会话结束后重连可以使用同一个 token
+        drop(grant);
+        let grant = auth.authorize(&headers(&token), "/whip").unwrap();
+        assert!(matches!(
+            auth.authorize(&headers(&token), "/whip"),
+            Err(AuthError::Forbidden(_))
+        ));
+        drop(grant);
+        assert_eq!(auth.active.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,268 @@
+use crate::encoder::EncoderControl;
+use std::time::{Duration, Instant};
+use str0m::bwe::BweKind;
+use tracing::info;
+use whep_player::StepDown;
+
+/// 目标码率占带宽估计的比例, 剩下的留给音频, RTP/RTCP 头和重传
+const BWE_HEADROOM: f64 = 0.85;
+
+/// 目标码率变化小于这个比例时不更新编码器, 避免频繁重配置
+const MIN_BITRATE_CHANGE: f64 = 0.05;
+
+/// 估计持续低于最低码率多久后降一级
+const STEP_DOWN_AFTER: Duration = Duration::from_secs(5);
+
+/// 估计持续高于恢复阈值 (最低码率的 2 倍) 多久后恢复一级
+const STEP_UP_AFTER: Duration = Duration::from_secs(15);
+
+/// 最多降级的次数, 每一级分辨率或者帧率减半
+const MAX_STEP_DOWN_LEVEL: u32 = 2;
+
+/// 码率控制的配置, 单位为 bit/s
+#[derive(Debug, Clone, Copy)]
+pub struct BitrateConfig {
+    pub min: u64,
+    pub max: u64,
+    pub start: u64, // 收到第一个带宽估计之前使用的码率
+    pub step_down: StepDown,
+}
+
+/// 根据带宽估计 (TWCC 和/或 REMB) 调整编码器的码率
+/// * 两种估计都有时使用较小的一个
+/// * 估计持续低于最低码率时按照 step_down 降低分辨率或者帧率, 估计恢复后逐级恢复
+pub struct BitrateController {
+    config: BitrateConfig,
+    twcc: Option<u64>,
+    remb: Option<u64>,
+    target: u64,
+    level: u32, // 当前的降级级别, 0 表示没有降级
+    low_since: Option<Instant>,
+    high_since: Option<Instant>,
+}
+
+impl BitrateController {
+    pub fn new(config: BitrateConfig) -> Self {
+        Self {
+            config,
+            twcc: None,
+            remb: None,
+            target: config.start.clamp(config.min, config.max),
+            level: 0,
+            low_since: None,
+            high_since: None,
+        }
+    }
+
+    /// 当前的目标码率
+    pub fn target(&self) -> u64 {
+        self.target
+    }
+
+    /// 带宽估计向上探测的目标
+    pub fn max(&self) -> u64 {
+        self.config.max
+    }
+
+    /// 新会话开始时编码器需要恢复的设置
+    pub fn initial(&self) -> Vec<EncoderControl> {
+        let mut controls = vec![EncoderControl::Bitrate(self.target)];
+        controls.extend(self.step_down_control());
+        controls
+    }
+
+    /// 处理一次带宽估计, 返回需要发给编码线程的命令
+    pub fn estimate(&mut self, estimate: BweKind) -> Vec<EncoderControl> {
+        self.estimate_at(estimate, Instant::now())
+    }
+
+    /// 在 now 时刻收到带宽估计, 降级和恢复按照 now 计算估计持续的时间
+    fn estimate_at(&mut self, estimate: BweKind, now: Instant) -> Vec<EncoderControl> {
+        match estimate {
+            BweKind::Twcc(bitrate) => self.twcc = Some(bitrate.as_u64()),
+            BweKind::Remb(_, bitrate) => self.remb = Some(bitrate.as_u64()),
+        }
+        let Some(estimate) = self.twcc.into_iter().chain(self.remb).min() else {
+            return vec![];
+        };
+
+        let mut controls = vec![];
+        let target =
+            ((estimate as f64 * BWE_HEADROOM) as u64).clamp(self.config.min, self.config.max);
+        if (target as f64 - self.target as f64).abs() >= self.target as f64 * MIN_BITRATE_CHANGE {
+            info!(
+                "bandwidth estimate {} bps, video bitrate {} -> {} bps",
+                estimate, self.target, target
+            );
+            self.target = target;
+            controls.push(EncoderControl::Bitrate(target));
+        }
+
+        if self.config.step_down != StepDown::None && self.update_level(estimate, now) {
+            controls.extend(self.step_down_control());
+        }
+
+        controls
+    }
+
+    /// 根据估计持续的时间调整降级级别, 返回级别是否变化
+    fn update_level(&mut self, estimate: u64, now: Instant) -> bool {
+        if estimate < self.config.min {
+            self.high_since = None;
+            let low_since = *self.low_since.get_or_insert(now);
+            if self.level < MAX_STEP_DOWN_LEVEL && now - low_since >= STEP_DOWN_AFTER {
+                self.level += 1;
+                self.low_since = None;
+                info!("bandwidth too low, step down to level {}", self.level);
+                return true;
+            }
+        } else if estimate >= self.config.min * 2 {
+            self.low_since = None;
+            let high_since = *self.high_since.get_or_insert(now);
+            if self.level > 0 && now - high_since >= STEP_UP_AFTER {
+                self.level -= 1;
+                self.high_since = None;
+                info!("bandwidth recovered, step up to level {}", self.level);
+                return true;
+            }
+        } else {
+            self.low_since = None;
+            self.high_since = None;
+        }
+
+        false
+    }
+
+    fn step_down_control(&self) -> Option<EncoderControl> {
+        let divisor = 1 << self.level;
+        match self.config.step_down {
+            StepDown::None => None,
+            StepDown::Resolution => Some(EncoderControl::ResolutionDivisor(divisor)),
+            StepDown::Framerate => Some(EncoderControl::FramerateDivisor(divisor)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use str0m::{bwe::Bitrate, media::Mid};
+
+    fn controller(step_down: StepDown) -> BitrateController {
+        BitrateController::new(BitrateConfig {
+            min: 500_000,
+            max: 4_000_000,
+            start: 2_000_000,
+            step_down,
+        })
+    }
+
+    fn twcc(bps: u64) -> BweKind {
+        BweKind::Twcc(Bitrate::bps(bps))
+    }
+
+    #[test]
+    fn target_follows_estimate() {
+        let mut bitrate = controller(StepDown::None);
+        assert_eq!(bitrate.initial(), vec![EncoderControl::Bitrate(2_000_000)]);
+
+        // 留出 15% 的余量
+        assert_eq!(
+            bitrate.estimate(twcc(1_000_000)),
+            vec![EncoderControl::Bitrate(850_000)]
+        );
+        // 变化不足 5% 时不更新编码器
+        assert_eq!(bitrate.estimate(twcc(1_030_000)), vec![]);
+        assert_eq!(bitrate.target(), 850_000);
+        // 限制在 [min, max] 之间
+        assert_eq!(
+            bitrate.estimate(twcc(10_000_000)),
+            vec![EncoderControl::Bitrate(4_000_000)]
+        );
+        assert_eq!(
+            bitrate.estimate(twcc(100_000)),
+            vec![EncoderControl::Bitrate(500_000)]
+        );
+    }
+
+    #[test]
+    fn lower_of_twcc_and_remb() {
+        let mut bitrate = controller(StepDown::None);
+        let remb = |bps| BweKind::Remb(Mid::from("0"), Bitrate::bps(bps));
+        assert_eq!(
+            bitrate.estimate(remb(3_000_000)),
+            vec![EncoderControl::Bitrate(2_550_000)]
+        );
+        assert_eq!(
+            bitrate.estimate(twcc(2_000_000)),
+            vec![EncoderControl::Bitrate(1_700_000)]
+        );
+        // TWCC 升高后仍然受 REMB 限制
+        assert_eq!(
+            bitrate.estimate(twcc(5_000_000)),
+            vec![EncoderControl::Bitrate(2_550_000)]
+        );
+    }
+
+    #[test]
+    fn step_down_and_recover() {
+        let mut bitrate = controller(StepDown::Resolution);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        bitrate.estimate_at(twcc(300_000), at(0));
+        assert_eq!(bitrate.estimate_at(twcc(300_000), at(4)), vec![]);
+        assert_eq!(
+            bitrate.estimate_at(twcc(300_000), at(5)),
+            vec![EncoderControl::ResolutionDivisor(2)]
+        );
+        // 每一级重新计时, 最多降两级
+        assert_eq!(bitrate.estimate_at(twcc(300_000), at(9)), vec![]);
+        assert_eq!(bitrate.estimate_at(twcc(300_000), at(13)), vec![]);
+        assert_eq!(
+            bitrate.estimate_at(twcc(300_000), at(14)),
+            vec![EncoderControl::ResolutionDivisor(4)]
+        );
+        assert_eq!(bitrate.estimate_at(twcc(300_000), at(30)), vec![]);
+        assert_eq!(
+            bitrate.initial(),
+            vec![
+                EncoderControl::Bitrate(500_000),
+                EncoderControl::ResolutionDivisor(4)
+            ]
+        );
+
+        // 估计在 [min, 2 * min) 之间时不恢复, 并且重新计时
+        let controls = bitrate.estimate_at(twcc(3_000_000), at(31));
+        assert_eq!(controls, vec![EncoderControl::Bitrate(2_550_000)]);
+        bitrate.estimate_at(twcc(900_000), at(40));
+        assert_eq!(
+            bitrate.estimate_at(twcc(3_000_000), at(41)),
+            vec![EncoderControl::Bitrate(2_550_000)]
+        );
+        assert_eq!(bitrate.estimate_at(twcc(3_000_000), at(55)), vec![]);
+        assert_eq!(
+            bitrate.estimate_at(twcc(3_000_000), at(56)),
+            vec![EncoderControl::ResolutionDivisor(2)]
+        );
+    }
+
+    #[test]
+    fn framerate_step_down() {
+        let mut bitrate = controller(StepDown::Framerate);
+        let start = Instant::now();
+        bitrate.estimate_at(twcc(300_000), start);
+        assert_eq!(
+            bitrate.estimate_at(twcc(300_000), start + STEP_DOWN_AFTER),
+            vec![EncoderControl::FramerateDivisor(2)]
+        );
+
+        // 不降级时只调整码率
+        let mut bitrate = controller(StepDown::None);
+        bitrate.estimate_at(twcc(300_000), start);
+        assert_eq!(
+            bitrate.estimate_at(twcc(300_000), start + Duration::from_secs(60)),
+            vec![]
+        );
+    }
+}
@@ -1,35 +1,50 @@
+use crate::ice_server::{self, IceServer, IceServerKind, TurnAllocation};
+use crate::net::{self, InterfaceFilter, NatMapping, Sockets, UdpMux};
+use crate::simulcast;
+use crate::stats::StatsEvent;
+use crate::tcp::{self, TcpTransport};
 use bytes::Bytes;
 use local_ip_address::list_afinet_netifas;
-use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, HeaderValue, USER_AGENT};
-use serde::Deserialize;
+use reqwest::header::{
+    ACCEPT, AUTHORIZATION, CONTENT_TYPE, ETAG, HeaderValue, IF_MATCH, LINK, USER_AGENT,
+};
 use std::{
     error::Error,
-    io::ErrorKind,
-    net::{IpAddr, SocketAddr, SocketAddrV4},
+    io::{self, ErrorKind},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
     time::{Duration, Instant},
 };
 use str0m::{
-    Candidate, Event, IceConnectionState, Input, Output, Rtc,
-    change::{SdpAnswer, SdpOffer},
-    format::Codec,
-    media::{Direction as RtcDirection, MediaData, MediaKind, MediaTime, Mid},
+    Candidate, Event, IceConnectionState, IceCreds, Input, Output, Rtc,
+    bwe::{Bitrate, BweKind},
+    change::{SdpAnswer, SdpOffer, SdpPendingOffer},
+    channel::{ChannelData, ChannelId},
+    format::{Codec, PayloadParams},
+    media::{
+        Direction as RtcDirection, KeyframeRequest, KeyframeRequestKind, MediaData, MediaKind,
+        MediaTime, Mid, Rid,
+    },
     net::{Protocol, Receive},
+    rtp::Ssrc,
+};
+use tokio::sync::{
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    oneshot::{self, error::TryRecvError},
+    watch,
 };
-use tokio::net::UdpSocket;
 use tracing::{debug, error, info, trace, warn};
-
-#[allow(dead_code)]
-#[derive(Debug, Deserialize)]
-pub struct WhipClaims {
-    pub whip_url: String,
-    pub jti: String,
-}
+use whep_player::{IceTransport, IceTransportPolicy, SocketMode, VideoCodec};
 
 #[derive(Debug)]
 pub enum WebrtcEvent {
     Continue,
+    Connected,
     Media(MediaData),
+    KeyframeRequest(KeyframeRequest), // 对端 (服务端或者观众) 通过 PLI/FIR 请求关键帧
+    BandwidthEstimate(BweKind),       // 发送方向的带宽估计 (TWCC 或者对端的 REMB)
+    ChannelData(ChannelData),         // 数据通道上收到的消息
+    Stats(StatsEvent),                // 每 2 秒产生一次的连接和媒体统计
     Disconnected,
 }
 
@@ -42,74 +57,504 @@ pub enum WebrtcError {
     NetworkError(Box<dyn Error + Send + Sync>),
     SendError(String),
     NoCandidates,
+    NoCompatibleCodec(String),
+}
+
+/// Trickle ICE / ICE restart 使用的 SDP 片段类型 (RFC 8840)
+pub const SDP_FRAG_CONTENT_TYPE: &str = "application/trickle-ice-sdpfrag";
+
+/// 连接断开后最多连续尝试 ICE restart 的次数
+const MAX_ICE_RESTARTS: u32 = 3;
+
+/// ICE restart 后在这个时间内没有重新连上时再次 restart
+const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 等待 ICE restart 的 PATCH 响应时 recv 最长的等待时间
+const ICE_RESTART_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// WHIP/WHEP 会话资源
+/// * POST 成功后服务端会在 Location 中返回会话的资源 URL, 结束会话时需要对它发送 DELETE
+/// * Trickle ICE 和 ICE restart 通过对资源 URL 发送 PATCH 完成 (RFC 9725)
+struct Session {
+    url: reqwest::Url,
+    token: Option<String>,
+    etag: Option<String>,  // 服务端返回的 ETag, PATCH 时通过 If-Match 带上
+    trickle: bool,         // 服务端是否支持 PATCH, 返回 405/501 后不再尝试
+    local_creds: IceCreds, // 当前的本地 ice-ufrag / ice-pwd
+    answer: String, // 最近一次接受的 answer, ICE restart 时基于它替换远端的 ice-ufrag / ice-pwd
+    layer_url: Option<reqwest::Url>, // WHEP layer 扩展的 URL, 用于请求 SFU 切换 simulcast 层
+}
+
+impl Session {
+    /// 构造对会话资源的 PATCH 请求
+    fn patch(&self, frag: String, if_match: &str) -> reqwest::RequestBuilder {
+        info!(
+            "PATCH session: {}, If-Match: {}\n{}",
+            self.url, if_match, frag
+        );
+        let mut request = reqwest::Client::new()
+            .patch(self.url.clone())
+            .header(CONTENT_TYPE, SDP_FRAG_CONTENT_TYPE)
+            .header(USER_AGENT, "bitwhip")
+            .header(IF_MATCH, if_match)
+            .body(frag);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        request
+    }
+
+    /// 处理 PATCH 的响应, 成功时记录新的 ETag, 返回响应的 SDP 片段 (可能为空)
+    fn on_patch_response(&mut self, response: PatchResponse) -> Result<String, WebrtcError> {
+        info!("PATCH status: {}", response.status);
+        match response.status {
+            reqwest::StatusCode::METHOD_NOT_ALLOWED
+            | reqwest::StatusCode::NOT_IMPLEMENTED
+            | reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                warn!("server doesn't support trickle ICE");
+                self.trickle = false;
+                return Err(WebrtcError::ServerError(
+                    format!("PATCH not supported: {}", response.status).into(),
+                ));
+            }
+            // 412: ETag 不匹配, 说明服务端已经发生了 ICE restart
+            code if !code.is_success() => {
+                return Err(WebrtcError::ServerError(
+                    format!("PATCH failed with status: {}", response.status).into(),
+                ));
+            }
+            _ => {}
+        }
+
+        if let Some(etag) = response.etag {
+            self.etag = Some(etag);
+        }
+
+        Ok(response.body)
+    }
+}
+
+/// PATCH 的响应
+#[derive(Debug)]
+struct PatchResponse {
+    status: reqwest::StatusCode,
+    etag: Option<String>,
+    body: String,
+}
+
+impl PatchResponse {
+    async fn send(request: reqwest::RequestBuilder) -> Result<Self, WebrtcError> {
+        let res = request
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+        let status = res.status();
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let body = res
+            .text()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+
+        Ok(Self { status, etag, body })
+    }
+}
+
+/// 进行中的 ICE restart
+/// * PATCH 在单独的任务中发送, 不阻塞 recv 中的媒体收发, 响应在 recv 中处理
+/// * 超过 deadline 还没有重新连上时再次 restart
+struct IceRestart {
+    pending: Option<SdpPendingOffer>, // ICE restart 的 offer, 收到响应后被接受
+    local_creds: IceCreds,            // 新的本地 ice-ufrag / ice-pwd
+    response: Option<oneshot::Receiver<Result<PatchResponse, WebrtcError>>>,
+    deadline: Instant,
+}
+
+/// Client 的配置
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub ice_servers: Vec<IceServer>, // 用于收集 server reflexive / relay 候选者的 STUN/TURN 服务器
+    pub socket_mode: SocketMode,     // 双栈 socket 还是 IPv4 / IPv6 各一个 socket
+    pub include_loopback: bool,      // 是否为回环地址创建候选者, 用于在同一台机器上测试
+    pub allow_interfaces: Vec<InterfaceFilter>, // 只为匹配的网卡创建候选者 (为空时不限制)
+    pub deny_interfaces: Vec<InterfaceFilter>, // 不为匹配的网卡创建候选者, 比如 docker0 / veth*
+    pub public_ips: Vec<NatMapping>, // NAT 1:1 映射, 为网卡地址额外发布公网地址的 host 候选者
+    pub public_ip_only: bool,        // 有公网地址时不再发布被映射的私有地址
+    pub ice_transport: IceTransport, // 创建 UDP 和/或 TCP 候选者
+    pub ice_transport_policy: IceTransportPolicy, // relay 时只使用 TURN 中继候选者
+    pub video_codecs: Vec<VideoCodec>, // 协商的视频编解码器, 按优先级排序 (为空时只使用 H264)
+    pub h264_profile_level_id: Option<u32>, // 编码器输出的 H264 profile-level-id, 为空时接受任意 profile
+    pub start_bitrate: Option<u64>,         // 启用 TWCC 带宽估计时的初始估计 (bit/s), 为空时不启用
+    pub simulcast_rids: Vec<String>, // 推流时发送的 simulcast 层, 从高到低 (少于 2 层时不使用 simulcast)
+    pub simulcast_layer: Option<String>, // 拉流时播放的 simulcast 层, 为空时根据丢包率自动选择
+    pub data_channel: Option<String>, // 作为 WHIP/WHEP 客户端时打开的数据通道的 label, 为空时不打开
+    pub udp_mux: Option<UdpMux>, // 作为服务端时所有会话共享的 UDP 端口, 为空时每个会话绑定自己的端口
+}
+
+/// 数据通道上要发送的一条消息
+#[derive(Debug, Clone)]
+pub struct ChannelMessage {
+    pub binary: bool, // false 时为 UTF-8 文本
+    pub data: Vec<u8>,
+}
+
+impl ClientConfig {
+    /// 是否为网卡地址创建 host 候选者
+    fn allow_host_candidate(&self, name: &str, ip: IpAddr) -> bool {
+        let allowed_kind = match ip {
+            IpAddr::V4(ip4) => {
+                !ip4.is_link_local() && (self.include_loopback || !ip4.is_loopback())
+            }
+            // IPv6 链路本地地址 (fe80::/10) 需要 scope id 才能使用, 跳过
+            IpAddr::V6(ip6) => {
+                (ip6.segments()[0] & 0xffc0) != 0xfe80
+                    && (self.include_loopback || !ip6.is_loopback())
+            }
+        };
+
+        allowed_kind
+            && (self.allow_interfaces.is_empty()
+                || self.allow_interfaces.iter().any(|f| f.matches(name, ip)))
+            && !self.deny_interfaces.iter().any(|f| f.matches(name, ip))
+    }
+}
+
+/// recv 中从 UDP socket 或 TCP 连接收到的数据
+enum Received {
+    Udp(io::Result<(usize, SocketAddr)>),
+    Tcp((SocketAddr, SocketAddr, Vec<u8>)),
+    Wake(bool), // 调用者有数据要发送, 提前结束等待, false 时唤醒通道的发送端已经释放
 }
 
 pub struct Client {
     rtc: Rtc, // WebRTC 连接的核心对象
-    socket: UdpSocket,
-    local_socket_addr: SocketAddr,
+    sockets: Sockets,
+    tcp: Option<TcpTransport>, // ICE-TCP, 只在启用 TCP 候选者时创建
+    ice_transport: IceTransport,
+    relay_only: bool,            // 只使用 relay 候选者, 不发布 host / srflx 候选者
+    host_addrs: Vec<SocketAddr>, // host 候选者的地址
+    nat: Vec<(IpAddr, IpAddr)>,  // NAT 1:1 映射的 (私有地址, 公网地址)
+    nat_only: bool,              // 是否只发布了公网地址
     buf: [u8; 1500], // udp 数据包缓冲区 (1500 字节, 标准 MTU (Maximum Transmission Unit) )
     video_mid: Option<Mid>, // 媒体视频流的标识符
-    _audio_mid: Option<Mid>,
+    audio_mid: Option<Mid>, // 媒体音频流的标识符
+    video_params: Option<PayloadParams>, // 协商得到的发送视频使用的参数, answer 被接受后确定
+    audio_params: Option<PayloadParams>, // 协商得到的发送音频使用的 Opus 参数, answer 被接受后确定
+    h264_profile_level_id: Option<u32>,
+    simulcast_rids: Vec<Rid>,         // 配置的 simulcast 层
+    send_rids: Vec<Rid>,              // 对端接受了 simulcast 后实际发送的层, 为空时只发送第一层
+    data_channel: Option<String>,     // 发起 WHIP/WHEP 请求时打开的数据通道的 label
+    channel: Option<ChannelId>,       // 已经打开的数据通道 (本地打开的, 或者对端打开的第一个)
+    session: Option<Session>,         // 作为 WHIP/WHEP 客户端时的会话资源
+    local_candidates: Vec<Candidate>, // 已经添加的本地候选者, ICE restart 时需要重新发送
+    ice_restarts: u32,                // 连续 ICE restart 的次数, 连接成功后清零
+    ice_restart: Option<IceRestart>,  // 进行中的 ICE restart, 连接成功后清除
+    ice_servers: Vec<IceServer>,      // 已经收集过候选者的 STUN/TURN 服务器
+    turn: Vec<TurnAllocation>,        // TURN 分配, relay 候选者的数据通过 TURN 服务器中转
+    remote_candidates: Option<UnboundedReceiver<Candidate>>, // 作为服务端时对端通过 PATCH trickle 过来的候选者
+    wake: Option<watch::Receiver<()>>, // 变化时 recv 不再等待 socket, 比如 restream 的观众有媒体数据要转发
 }
 
 impl Client {
-    pub async fn new() -> Result<Self, WebrtcError> {
-        // 在系统上分配一个 UDP socket, 并绑定到所有网卡的任意可用端口
-        let socket = UdpSocket::bind("0.0.0.0:0".parse::<SocketAddrV4>().unwrap())
-            .await
-            .expect("Should bind udp socket");
+    pub async fn new(config: &ClientConfig) -> Result<Self, WebrtcError> {
+        // 在系统上分配 UDP socket, 并绑定到所有网卡的任意可用端口
+        // 配置了共享端口时使用共享端口, 由 UdpMux 把属于这个会话的数据分发过来
+        let sockets = match &config.udp_mux {
+            Some(mux) => mux.sockets(),
+            None => Sockets::bind(config.socket_mode)
+                .map_err(|e| WebrtcError::NetworkError(e.into()))?,
+        };
 
         // 构建一个 WebRTC 对象
-        let mut rtc = Rtc::builder()
-            .clear_codecs() // 清除默认的音视频编解码器列表, 后续可以只启用你需要的编解码器, 避免不必要的协商
-            .enable_h264(true) // 启用 H264 视频编解码器
+        let mut rtc_config = Rtc::builder().clear_codecs(); // 清除默认的音视频编解码器列表, 后续可以只启用你需要的编解码器, 避免不必要的协商
+
+        // 按优先级顺序启用视频编解码器, offer 中 payload type 的顺序与启用的顺序一致
+        let video_codecs = match config.video_codecs.as_slice() {
+            [] => &[VideoCodec::H264][..],
+            codecs => codecs,
+        };
+        for codec in video_codecs {
+            rtc_config = match codec {
+                VideoCodec::H264 => rtc_config.enable_h264(true),
+                VideoCodec::H265 => rtc_config.enable_h265(true),
+                VideoCodec::Vp8 => rtc_config.enable_vp8(true),
+                VideoCodec::Vp9 => rtc_config.enable_vp9(true),
+                VideoCodec::Av1 => rtc_config.enable_av1(true),
+            };
+        }
+
+        // 推流时根据 TWCC 反馈估计可用带宽, 用来调整编码器的码率
+        if let Some(start) = config.start_bitrate {
+            rtc_config = rtc_config.enable_bwe(Some(Bitrate::bps(start)));
+        }
+
+        let mut rtc = rtc_config
+            .enable_opus(true) // 启用 Opus 音频编解码器
             .set_stats_interval(Some(Duration::from_secs(2))) // 设置每 2 秒手机一次连接的统计数据
             .set_reordering_size_video(1) // 设置视频流的乱序缓冲区为 1 (保证低延迟, 但是网络不佳时会丢帧)
             .set_reordering_size_audio(1) // 设置音频流的乱序缓冲区为 1
             .build();
 
-        // 本地监听的 UDP 端口
-        info!("local socket address: {:?}", socket.local_addr());
+        // relay 策略下只通过 TURN 中继收发数据, 本地地址不出现在任何候选者里
+        let relay_only = config.ice_transport_policy == IceTransportPolicy::Relay;
+
+        // UDP 被屏蔽的网络只能使用 ICE-TCP (ICE-TCP 只有 host 候选者, relay 策略下不使用)
+        let tcp = if config.ice_transport.tcp() && !relay_only {
+            Some(
+                TcpTransport::listen(config.socket_mode)
+                    .map_err(|e| WebrtcError::NetworkError(e.into()))?,
+            )
+        } else {
+            None
+        };
 
         // Discover host candidates
-        // 获取系统的网络接口列表, 为每个有效的 IPV4 接口创建 WebRTC ICE 候选者
-        let mut local_socket_addr = None;
+        // 获取系统的网络接口列表, 为每个通过过滤规则的 IPv4 / IPv6 地址创建 WebRTC ICE 候选者
+        let mut host_addrs = vec![];
+        let mut nat = vec![];
+        let mut local_candidates = vec![];
         if let Ok(network_interfaces) = list_afinet_netifas() {
             for (name, ip) in network_interfaces {
                 debug!("iface: {} / {:?}", name, ip);
-                match ip {
-                    IpAddr::V4(ip4) => {
-                        if !ip4.is_loopback() && !ip4.is_link_local() {
-                            let socket_addr =
-                                SocketAddr::new(ip, socket.local_addr().unwrap().port());
-                            local_socket_addr = Some(socket_addr.clone());
-                            info!("Discover local candidate: [{} / {:?}]", name, ip);
-                            rtc.add_local_candidate(
-                                Candidate::host(socket_addr, str0m::net::Protocol::Udp)
-                                    .expect("Fail to create local candidate"),
-                            );
-                        }
+                if !config.allow_host_candidate(&name, ip) {
+                    debug!("skip iface: {} / {:?}", name, ip);
+                    continue;
+                }
+                // 没有对应地址族的 socket (比如系统禁用了 IPv6)
+                let Some(port) = sockets.port(ip) else {
+                    continue;
+                };
+
+                host_addrs.push(SocketAddr::new(ip, port));
+                if relay_only {
+                    continue;
+                }
+
+                // 在云主机或者容器里, 网卡地址是对端访问不到的私有地址, 使用 NAT 映射的公网地址
+                let public_ips: Vec<IpAddr> = config
+                    .public_ips
+                    .iter()
+                    .filter(|mapping| mapping.maps(ip))
+                    .map(|mapping| mapping.public)
+                    .collect();
+                nat.extend(public_ips.iter().map(|public| (ip, *public)));
+                let advertised_ips = if config.public_ip_only && !public_ips.is_empty() {
+                    public_ips
+                } else {
+                    [vec![ip], public_ips].concat()
+                };
+
+                let tcp_port = tcp.as_ref().and_then(|tcp| tcp.port(ip));
+                let mut candidates = vec![];
+                for ip in advertised_ips {
+                    if config.ice_transport.udp() {
+                        candidates.push((SocketAddr::new(ip, port), Protocol::Udp));
+                    }
+                    if let Some(tcp_port) = tcp_port {
+                        // passive 候选者使用监听端口, active 候选者的端口固定为 9
+                        candidates.push((SocketAddr::new(ip, tcp_port), Protocol::Tcp));
+                        candidates.push((SocketAddr::new(ip, tcp::ACTIVE_PORT), Protocol::Tcp));
                     }
-                    IpAddr::V6(_ip6) => {}
+                }
+
+                for (addr, proto) in candidates {
+                    info!(
+                        "Discover local candidate: [{} / {:?} / {}]",
+                        name, addr, proto
+                    );
+                    let candidate =
+                        Candidate::host(addr, proto).expect("Fail to create local candidate");
+                    rtc.add_local_candidate(candidate.clone());
+                    local_candidates.push(candidate);
                 }
             }
         } else {
             return Err(WebrtcError::NoCandidates);
         }
 
-        let Some(local_socket_addr) = local_socket_addr else {
+        if host_addrs.is_empty() {
             return Err(WebrtcError::NoCandidates);
-        };
+        }
 
-        Ok(Self {
-            socket,
-            local_socket_addr,
+        let mut client = Self {
+            sockets,
+            tcp,
+            ice_transport: config.ice_transport,
+            relay_only,
+            host_addrs,
+            nat,
+            nat_only: config.public_ip_only,
             rtc,
             buf: [0; 1500],
             video_mid: None,
-            _audio_mid: None,
-        })
+            audio_mid: None,
+            video_params: None,
+            audio_params: None,
+            h264_profile_level_id: config.h264_profile_level_id,
+            simulcast_rids: if config.simulcast_rids.len() > 1 {
+                config
+                    .simulcast_rids
+                    .iter()
+                    .map(|rid| Rid::from(rid.as_str()))
+                    .collect()
+            } else {
+                vec![]
+            },
+            send_rids: vec![],
+            data_channel: config.data_channel.clone(),
+            channel: None,
+            session: None,
+            local_candidates,
+            ice_restarts: 0,
+            ice_restart: None,
+            ice_servers: vec![],
+            turn: vec![],
+            remote_candidates: None,
+            wake: None,
+        };
+
+        // 命令行指定的 STUN/TURN 服务器在生成 offer 之前收集, 候选者直接放在 offer 里
+        client.gather_candidates(&config.ice_servers).await;
+
+        if client.local_candidates.is_empty() {
+            return Err(WebrtcError::NoCandidates);
+        }
+
+        Ok(client)
+    }
+
+    /// 与远端地址同一地址族的 host 地址
+    /// * socket 绑定在通配地址上, 拿不到数据包实际的目的地址, 用它作为 Receive 的 destination
+    /// * 远端是回环地址时优先使用回环地址
+    fn host_addr_for(&self, remote: SocketAddr) -> Option<SocketAddr> {
+        let mut same_family = self
+            .host_addrs
+            .iter()
+            .filter(|addr| addr.is_ipv4() == remote.is_ipv4());
+        same_family
+            .clone()
+            .find(|addr| addr.ip().is_loopback() == remote.ip().is_loopback())
+            .or_else(|| same_family.next())
+            .copied()
+    }
+
+    /// 数据包发往的本地候选者地址
+    /// * 公网地址的 host 候选者收到的数据实际到达的是被映射的私有地址
+    /// * 同时发布了私有地址时, 来自私有地址的数据认为是发往私有地址的候选者
+    fn nat_destination(&self, local: SocketAddr, remote: SocketAddr) -> SocketAddr {
+        let Some((_, public)) = self.nat.iter().find(|(private, _)| *private == local.ip()) else {
+            return local;
+        };
+        if self.nat_only || !net::is_private(remote.ip()) {
+            SocketAddr::new(*public, local.port())
+        } else {
+            local
+        }
+    }
+
+    /// 通过 STUN/TURN 服务器收集 server reflexive 和 relay 候选者
+    /// * 事务的响应直接从 socket 上读取, 只能在 recv 循环之外调用
+    /// * 收到 answer 后才收集时对端可能已经开始连通性检查, 期间收到的其他数据在收集完后交给 Rtc
+    async fn gather_candidates(&mut self, servers: &[IceServer]) {
+        // 目前 STUN/TURN 只通过 UDP 收集
+        if !self.ice_transport.udp() {
+            return;
+        }
+        // 共享端口上 STUN/TURN 的响应会与其他会话的数据混在一起, 并且同一个 5 元组不能分配多个 TURN 中继
+        if self.sockets.is_muxed() && !servers.is_empty() {
+            warn!("STUN/TURN servers are ignored on the shared UDP port, use --public-ip instead");
+            return;
+        }
+
+        let mut skipped = vec![];
+        for server in servers {
+            if self.ice_servers.contains(server) {
+                continue;
+            }
+            self.ice_servers.push(server.clone());
+
+            info!("Gather candidates from {}", server.url);
+            if let Err(e) = self.gather_candidates_from(server, &mut skipped).await {
+                warn!("Failed to gather candidates from {}: {:?}", server.url, e);
+            }
+        }
+
+        for (source, data) in skipped {
+            if let Err(e) = self.handle_udp(source, &data) {
+                debug!("handle {} bytes from {} error {:?}", data.len(), source, e);
+            }
+        }
+    }
+
+    async fn gather_candidates_from(
+        &mut self,
+        server: &IceServer,
+        skipped: &mut Vec<(SocketAddr, Vec<u8>)>,
+    ) -> Result<(), WebrtcError> {
+        // 选择一个本地有对应地址族 host 地址的服务器地址
+        let (addr, base) = server
+            .resolve()
+            .await
+            .map_err(|e| WebrtcError::NetworkError(e.into()))?
+            .into_iter()
+            .find_map(|addr| self.host_addr_for(addr).map(|base| (addr, base)))
+            .ok_or_else(|| {
+                WebrtcError::NetworkError(format!("no usable address for {}", server.url).into())
+            })?;
+
+        match server.kind {
+            IceServerKind::Stun => {
+                let mapped = ice_server::binding(&self.sockets, addr, skipped)
+                    .await
+                    .map_err(|e| WebrtcError::NetworkError(e.into()))?;
+                self.add_server_reflexive_candidate(mapped, base).await?;
+            }
+            IceServerKind::Turn => {
+                let (Some(username), Some(credential)) = (&server.username, &server.credential)
+                else {
+                    return Err(WebrtcError::ServerError(
+                        "TURN server without username / credential".into(),
+                    ));
+                };
+
+                let allocation =
+                    TurnAllocation::allocate(&self.sockets, addr, username, credential, skipped)
+                        .await
+                        .map_err(|e| WebrtcError::NetworkError(e.into()))?;
+                if let Some(mapped) = allocation.mapped {
+                    self.add_server_reflexive_candidate(mapped, base).await?;
+                }
+
+                let candidate = Candidate::relayed(allocation.relayed, Protocol::Udp)
+                    .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+                self.turn.push(allocation);
+                self.add_local_candidate(candidate).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn add_server_reflexive_candidate(
+        &mut self,
+        mapped: SocketAddr,
+        base: SocketAddr,
+    ) -> Result<(), WebrtcError> {
+        if self.relay_only {
+            debug!("skip server reflexive candidate {} (relay only)", mapped);
+            return Ok(());
+        }
+        // 没有经过 NAT 时, 映射地址和 host 候选者一样
+        if self.local_candidates.iter().any(|c| c.addr() == mapped) {
+            return Ok(());
+        }
+
+        let candidate = Candidate::server_reflexive(mapped, base, Protocol::Udp)
+            .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+        self.add_local_candidate(candidate).await
     }
 
     pub async fn send_whip_request(
@@ -128,6 +573,17 @@ impl Client {
             Some("video_0".to_string()),
             Some("video_0".to_string()),
         ));
+        // 音频轨道和视频轨道使用同一个 stream-id, 以便对端同步播放
+        self.audio_mid = Some(change.add_media(
+            MediaKind::Audio,
+            direction,
+            Some("video_0".to_string()),
+            Some("audio_0".to_string()),
+        ));
+        // 数据通道使用同一个连接的 SCTP, 在 offer 中增加一个 application m-line
+        if let Some(label) = &self.data_channel {
+            change.add_channel(label.clone());
+        }
 
         // 创建 SDP Offer
         // * 如果此方法返回 SDPOffer, 说明更改不会立即生效, 调用者需要与 remote peer 进行协商, 并在获得 answer 后使用 SdpPendingOffer 应答
@@ -138,7 +594,13 @@ impl Client {
         // 此时 str0m 内部会生成一个 SdpPendingOffer, 它表示当前有一个待处理的 Offer, 等远端回复
         let (offer, pending) = change.apply().ok_or(WebrtcError::SdpError)?;
 
-        let offer_str = offer.to_sdp_string();
+        let mut offer_str = tcp::with_tcp_type(&offer.to_sdp_string());
+        // str0m 的 SDP API 不能声明发送 simulcast, 直接在 offer 中加上 rid 和 simulcast 属性
+        let simulcast = matches!(direction, RtcDirection::SendOnly | RtcDirection::SendRecv)
+            && !self.simulcast_rids.is_empty();
+        if let (true, Some(mid)) = (simulcast, self.video_mid) {
+            offer_str = simulcast::with_simulcast(&offer_str, mid, &self.simulcast_rids);
+        }
         info!("offer: {}", offer_str);
         info!("token: {:?}", token);
         info!("url: {}", url);
@@ -181,12 +643,13 @@ impl Client {
                     .headers()
                     .get(reqwest::header::HeaderName::from_static("location"))
                 {
-                    next_url = reqwest::Url::from_str(
-                        location
-                            .to_str()
-                            .map_err(|e| WebrtcError::ServerError(e.into()))?,
-                    )
-                    .map_err(|e| WebrtcError::ServerError(e.into()))?;
+                    next_url = next_url
+                        .join(
+                            location
+                                .to_str()
+                                .map_err(|e| WebrtcError::ServerError(e.into()))?,
+                        )
+                        .map_err(|e| WebrtcError::ServerError(e.into()))?;
                     info!("Redirect! Next URL: {:?}", next_url);
                     continue;
                 }
@@ -206,6 +669,46 @@ impl Client {
         }
 
         info!("headers: {:?}", res.headers());
+
+        // 记录会话的资源 URL, Location 可能是相对路径, 需要基于请求的 URL 解析
+        let resource_url = match res.headers().get(reqwest::header::LOCATION) {
+            Some(location) => Some(
+                next_url
+                    .join(
+                        location
+                            .to_str()
+                            .map_err(|e| WebrtcError::ServerError(e.into()))?,
+                    )
+                    .map_err(|e| WebrtcError::ServerError(e.into()))?,
+            ),
+            None => {
+                warn!("no Location header in response, session can't be deleted");
+                None
+            }
+        };
+        let etag = res
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let link_ice_servers = ice_server::parse_link_headers(
+            res.headers()
+                .get_all(LINK)
+                .iter()
+                .filter_map(|v| v.to_str().ok()),
+        );
+        let layer_url = ice_server::parse_links(
+            res.headers()
+                .get_all(LINK)
+                .iter()
+                .filter_map(|v| v.to_str().ok()),
+        )
+        .into_iter()
+        .find(|(_, params)| {
+            params.get("rel").map(|r| r.as_str()) == Some(simulcast::LAYER_LINK_REL)
+        })
+        .and_then(|(url, _)| next_url.join(&url).ok());
+
         let answer = res
             .text()
             .await
@@ -250,20 +753,378 @@ impl Client {
             }
         }
 
+        if matches!(direction, RtcDirection::SendOnly | RtcDirection::SendRecv) {
+            self.negotiate_video_params()?;
+            self.negotiate_audio_params();
+        }
+        if simulcast {
+            self.declare_simulcast(&modified_answer);
+        }
+
+        if let Some(resource_url) = resource_url {
+            info!("session resource url: {}, etag: {:?}", resource_url, etag);
+            let (local_creds, _) = parse_sdp_frag(&offer_str);
+            self.session = Some(Session {
+                url: resource_url,
+                token: token.clone(),
+                etag,
+                trickle: true,
+                local_creds: local_creds.ok_or(WebrtcError::SdpError)?,
+                answer: modified_answer,
+                layer_url,
+            });
+        }
+
+        // 服务端通过 Link 头下发的 STUN/TURN 服务器, 收集到的候选者通过 PATCH trickle 给服务端
+        info!("ice servers from Link header: {:?}", link_ice_servers);
+        self.gather_candidates(&link_ice_servers).await;
+
+        Ok(())
+    }
+
+    /// 添加本地候选者
+    /// * 会话已经建立时通过 PATCH 把候选者 trickle 给服务端
+    pub async fn add_local_candidate(&mut self, candidate: Candidate) -> Result<(), WebrtcError> {
+        info!("add local candidate: {}", candidate.to_sdp_string());
+        self.rtc.add_local_candidate(candidate.clone());
+        self.local_candidates.push(candidate.clone());
+
+        let Some(session) = self.session.as_ref().filter(|s| s.trickle) else {
+            return Ok(());
+        };
+
+        // 没有 ETag 时用 `*` 匹配任意版本
+        let if_match = session.etag.clone().unwrap_or("*".to_string());
+        let frag = self.sdp_frag(&session.local_creds, &[candidate]);
+        let body = self.send_patch(frag, &if_match).await?;
+
+        let (_, remote_candidates) = parse_sdp_frag(&body);
+        for candidate in remote_candidates {
+            self.rtc.add_remote_candidate(candidate);
+        }
+
+        Ok(())
+    }
+
+    /// 开始 ICE restart (RFC 9725), 返回是否已经开始
+    /// * 生成新的本地 ice-ufrag / ice-pwd, 连同所有本地候选者通过 PATCH 发送给服务端
+    /// * PATCH 在后台任务中发送, 服务端在响应中返回新的远端 ice-ufrag / ice-pwd 和候选者, 由 recv 处理
+    fn start_ice_restart(&mut self) -> bool {
+        if self.ice_restarts >= MAX_ICE_RESTARTS {
+            return false;
+        }
+        let Some(session) = self.session.as_ref().filter(|s| s.trickle) else {
+            return false;
+        };
+
+        // str0m 中 ICE restart 需要通过 SDP 协商完成, 生成的 offer 不需要发送, 只用新的 ice 凭证
+        let mut change = self.rtc.sdp_api();
+        let local_creds = change.ice_restart(true);
+        let Some((_offer, pending)) = change.apply() else {
+            error!("failed to create ice restart offer");
+            return false;
+        };
+
+        self.ice_restarts += 1;
+        warn!("ice restart #{}", self.ice_restarts);
+
+        // ICE restart 时使用 `If-Match: *`, 服务端会返回新的 ETag
+        let frag = self.sdp_frag(&local_creds, &self.local_candidates);
+        let request = session.patch(frag, "*");
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = tx.send(PatchResponse::send(request).await);
+        });
+
+        self.ice_restart = Some(IceRestart {
+            pending: Some(pending),
+            local_creds,
+            response: Some(rx),
+            deadline: Instant::now() + ICE_RESTART_TIMEOUT,
+        });
+        true
+    }
+
+    /// 检查进行中的 ICE restart, 返回 false 表示 ICE restart 失败, 连接已经断开
+    /// * PATCH 的响应到达后接受服务端新的 ice-ufrag / ice-pwd 和候选者
+    /// * 超时还没有重新连上时再次 restart, 直到次数用完
+    fn poll_ice_restart(&mut self) -> bool {
+        let Some(mut restart) = self.ice_restart.take() else {
+            return true;
+        };
+
+        if let Some(response) = restart.response.as_mut() {
+            match response.try_recv() {
+                Ok(result) => {
+                    restart.response = None;
+                    let pending = restart.pending.take().expect("ice restart offer");
+                    let result = result
+                        .and_then(|response| match self.session.as_mut() {
+                            Some(session) => session.on_patch_response(response),
+                            None => Err(WebrtcError::ServerError("no session".into())),
+                        })
+                        .and_then(|body| {
+                            self.accept_ice_restart(pending, restart.local_creds.clone(), &body)
+                        });
+                    if let Err(e) = result {
+                        error!("ice restart failed: {:?}", e);
+                        restart.deadline = Instant::now();
+                    }
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Closed) => {
+                    error!("ice restart request aborted");
+                    restart.response = None;
+                    restart.deadline = Instant::now();
+                }
+            }
+        }
+
+        if Instant::now() < restart.deadline {
+            self.ice_restart = Some(restart);
+            return true;
+        }
+        warn!("ice not reconnected after restart #{}", self.ice_restarts);
+        self.start_ice_restart()
+    }
+
+    /// 接受 ICE restart 的响应
+    /// * 把之前 answer 里的远端 ice 凭证替换成新的, 旧的候选者随 ICE restart 失效
+    fn accept_ice_restart(
+        &mut self,
+        pending: SdpPendingOffer,
+        local_creds: IceCreds,
+        body: &str,
+    ) -> Result<(), WebrtcError> {
+        info!("ice restart answer:\n{}", body);
+        let Some(session) = self.session.as_mut() else {
+            return Err(WebrtcError::ServerError("no session".into()));
+        };
+
+        let (remote_creds, remote_candidates) = parse_sdp_frag(body);
+        let Some(remote_creds) = remote_creds else {
+            error!("ice restart answer without ice-ufrag / ice-pwd");
+            return Err(WebrtcError::SdpError);
+        };
+
+        let answer = replace_remote_creds(&session.answer, &remote_creds);
+        let sdp_answer = SdpAnswer::from_sdp_string(&answer).map_err(|e| {
+            error!("Failed to parse SDP answer: {:?}", e);
+            WebrtcError::SdpError
+        })?;
+        self.rtc
+            .sdp_api()
+            .accept_answer(pending, sdp_answer)
+            .map_err(|e| WebrtcError::WebrtcError(e.into()))?;
+
+        for candidate in remote_candidates {
+            self.rtc.add_remote_candidate(candidate);
+        }
+
+        session.local_creds = local_creds;
+        session.answer = answer;
+
         Ok(())
     }
 
+    /// 构造 trickle ICE 使用的 SDP 片段
+    fn sdp_frag(&self, creds: &IceCreds, candidates: &[Candidate]) -> String {
+        let mut frag = format!(
+            "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\n",
+            creds.ufrag, creds.pass
+        );
+        // 所有 media 都 BUNDLE 在一起, 候选者只需要挂在第一个 m-line 下
+        if let Some(mid) = self.video_mid {
+            frag.push_str(&format!(
+                "m=video 9 UDP/TLS/RTP/SAVPF 0\r\na=mid:{}\r\n",
+                mid
+            ));
+        }
+        for candidate in candidates {
+            frag.push_str(&format!("a={}\r\n", candidate.to_sdp_string()));
+        }
+
+        tcp::with_tcp_type(&frag)
+    }
+
+    /// 对会话资源发送 PATCH, 返回响应的 SDP 片段 (可能为空)
+    async fn send_patch(&mut self, frag: String, if_match: &str) -> Result<String, WebrtcError> {
+        let Some(session) = self.session.as_mut() else {
+            return Err(WebrtcError::ServerError("no session".into()));
+        };
+
+        let response = PatchResponse::send(session.patch(frag, if_match)).await?;
+        session.on_patch_response(response)
+    }
+
+    /// 结束 WHIP/WHEP 会话
+    /// * 对会话的资源 URL 发送 DELETE, 让服务端立即释放会话, 而不是等到 ICE 超时
+    /// * 释放 TURN 分配, 中继端口不用等到分配过期才被回收
+    /// * 没有会话 (作为服务端, 或者已经结束) 时不发送 DELETE
+    pub async fn close(&mut self) -> Result<(), WebrtcError> {
+        for turn in self.turn.drain(..) {
+            if let Err(e) = self.sockets.send_to(&turn.release(), turn.server).await {
+                debug!("sending TURN release to {} error {:?}", turn.server, e);
+            }
+        }
+
+        let Some(session) = self.session.take() else {
+            return Ok(());
+        };
+
+        info!("DELETE session: {}", session.url);
+        let mut request = reqwest::Client::new()
+            .delete(session.url)
+            .header(USER_AGENT, "bitwhip");
+        if let Some(token) = &session.token {
+            request = request.bearer_auth(token);
+        }
+
+        let res = request
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+        info!("DELETE status: {}", res.status());
+        if !res.status().is_success() {
+            return Err(WebrtcError::ServerError(
+                format!("DELETE failed with status: {}", res.status()).into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// answer 被接受后查找一次发送视频使用的 H264 参数
+    /// * 对端接受的 profile 必须能解码编码器输出的码流
+    /// * packetization-mode 必须为 1, 否则超过 MTU 的 NALU 无法分片发送
+    fn negotiate_video_params(&mut self) -> Result<(), WebrtcError> {
+        let Some(mid) = self.video_mid else {
+            return Ok(());
+        };
+        // 对端拒绝了视频 m-line
+        let Some(writer) = self.rtc.writer(mid) else {
+            return Ok(());
+        };
+        let accepted: Vec<PayloadParams> = writer
+            .payload_params()
+            .filter(|p| p.spec().codec == Codec::H264)
+            .cloned()
+            .collect();
+
+        let params = accepted.iter().find(|p| {
+            let format = &p.spec().format;
+            format.packetization_mode == Some(1)
+                && match (self.h264_profile_level_id, format.profile_level_id) {
+                    (Some(local), Some(remote)) => h264_profile_compatible(local, remote),
+                    _ => true,
+                }
+        });
+        let Some(params) = params else {
+            return Err(WebrtcError::NoCompatibleCodec(format!(
+                "no H264 payload compatible with profile-level-id {:?} (packetization-mode=1), remote accepted: {:?}",
+                self.h264_profile_level_id.map(|id| format!("{:06x}", id)),
+                accepted
+                    .iter()
+                    .map(|p| p.spec().format.clone())
+                    .collect::<Vec<_>>()
+            )));
+        };
+
+        if let (Some(local), Some(remote)) = (
+            self.h264_profile_level_id,
+            params.spec().format.profile_level_id,
+        ) {
+            if local & 0xff > remote & 0xff {
+                warn!(
+                    "encoder level {:06x} is higher than the remote accepted {:06x}",
+                    local, remote
+                );
+            }
+        }
+        info!("negotiated video params: {:?}", params);
+        self.video_params = Some(params.clone());
+
+        Ok(())
+    }
+
+    /// answer 被接受后查找一次发送音频使用的 Opus 参数
+    /// * 对端拒绝了音频 m-line 或者没有接受 Opus 时不发送音频
+    fn negotiate_audio_params(&mut self) {
+        let Some(writer) = self.audio_mid.and_then(|mid| self.rtc.writer(mid)) else {
+            return;
+        };
+        self.audio_params = writer
+            .payload_params()
+            .find(|p| p.spec().codec == Codec::Opus)
+            .cloned();
+        match &self.audio_params {
+            Some(params) => info!("negotiated audio params: {:?}", params),
+            None => warn!("remote did not accept Opus, audio is not sent"),
+        }
+    }
+
+    /// 对端接受了 simulcast 时为每一层声明发送的 RTP 流
+    /// * SDP API 不知道 offer 中加上的 rid, 需要通过 direct API 声明, 每一层使用随机的 SSRC 和 RTX SSRC
+    fn declare_simulcast(&mut self, answer: &str) {
+        let Some(mid) = self.video_mid else {
+            return;
+        };
+        if !simulcast::answer_accepts_simulcast(answer, mid) {
+            warn!("remote did not accept simulcast, only the first layer is sent");
+            return;
+        }
+
+        let mut api = self.rtc.direct_api();
+        for rid in &self.simulcast_rids {
+            api.declare_stream_tx(
+                Ssrc::from(rand::random::<u32>()),
+                Some(Ssrc::from(rand::random::<u32>())),
+                mid,
+                Some(*rid),
+            );
+        }
+        info!("sending simulcast layers: {:?}", self.simulcast_rids);
+        self.send_rids = self.simulcast_rids.clone();
+    }
+
     pub fn accept_whip_request(&mut self, offer: String) -> Result<String, WebrtcError> {
         let offer = SdpOffer::from_sdp_string(&offer).map_err(|_| WebrtcError::SdpError)?;
         if let Ok(answer) = self.rtc.sdp_api().accept_offer(offer) {
-            return Ok(answer.to_sdp_string());
+            let answer = answer.to_sdp_string();
+            // 共享端口时, 对端的连通性检查通过 answer 中的 ice-ufrag 找到这个会话
+            if let (Some(creds), _) = parse_sdp_frag(&answer) {
+                self.sockets.route_ufrag(&creds.ufrag);
+            }
+            return Ok(tcp::with_tcp_type(&answer));
         }
 
         return Err(WebrtcError::SdpError);
     }
 
+    /// 作为服务端时接收对端 trickle 过来的候选者, 在 recv 中添加到 Rtc
+    pub fn remote_candidates(&mut self) -> UnboundedSender<Candidate> {
+        let (tx, rx) = unbounded_channel();
+        self.remote_candidates = Some(rx);
+        tx
+    }
+
+    /// wake 变化时 recv 立即返回 Continue, 调用者在两次 recv 之间发送其他来源的数据
+    /// * recv 可能正在发送 poll_output 取出的数据包, 不能放在 select 中与其他通道竞争后被取消
+    pub fn wake_on(&mut self, wake: watch::Receiver<()>) {
+        self.wake = Some(wake);
+    }
+
     pub async fn recv<'a>(&mut self) -> Result<WebrtcEvent, WebrtcError> {
         trace!("recv poll_output()");
+        if !self.poll_ice_restart() {
+            return Ok(WebrtcEvent::Disconnected);
+        }
+        if let Some(rx) = self.remote_candidates.as_mut() {
+            while let Ok(candidate) = rx.try_recv() {
+                info!("add remote candidate: {}", candidate.to_sdp_string());
+                self.rtc.add_remote_candidate(candidate);
+            }
+        }
         let timeout = match self
             .rtc
             .poll_output()
@@ -272,43 +1133,112 @@ impl Client {
             Output::Event(event) => match event {
                 Event::Connected => {
                     info!("connected");
-                    return Ok(WebrtcEvent::Continue);
+                    return Ok(WebrtcEvent::Connected);
                 }
                 Event::IceConnectionStateChange(state) => {
                     info!("ice connection state change: {:?}", state);
                     match state {
-                        IceConnectionState::Disconnected => return Ok(WebrtcEvent::Disconnected),
+                        IceConnectionState::Connected | IceConnectionState::Completed => {
+                            self.ice_restarts = 0;
+                            self.ice_restart = None;
+                            return Ok(WebrtcEvent::Continue);
+                        }
+                        IceConnectionState::Disconnected => {
+                            // 会话支持 PATCH 时先尝试 ICE restart, 而不是直接断开
+                            // 已经在 restart 时继续等待, 超时后由 poll_ice_restart 再次 restart
+                            if self.ice_restart.is_some() || self.start_ice_restart() {
+                                return Ok(WebrtcEvent::Continue);
+                            }
+                            return Ok(WebrtcEvent::Disconnected);
+                        }
                         _ => return Ok(WebrtcEvent::Continue),
                     }
                 }
                 Event::MediaIngressStats(stats) => {
-                    info!("egress stats: {:?}", stats);
-                    return Ok(WebrtcEvent::Continue);
+                    debug!("ingress stats: {:?}", stats);
+                    return Ok(WebrtcEvent::Stats(StatsEvent::Ingress(stats)));
                 }
                 Event::MediaEgressStats(stats) => {
-                    info!("egress stats: {:?}", stats);
-                    return Ok(WebrtcEvent::Continue);
+                    debug!("egress stats: {:?}", stats);
+                    return Ok(WebrtcEvent::Stats(StatsEvent::Egress(stats)));
                 }
                 Event::PeerStats(stats) => {
-                    info!("stats: {:?}", stats);
-                    return Ok(WebrtcEvent::Continue);
+                    debug!("peer stats: {:?}", stats);
+                    return Ok(WebrtcEvent::Stats(StatsEvent::Peer(stats)));
                 }
                 Event::MediaData(media) => {
                     return Ok(WebrtcEvent::Media(media));
                 }
+                Event::KeyframeRequest(request) => {
+                    debug!("keyframe request: {:?}", request);
+                    return Ok(WebrtcEvent::KeyframeRequest(request));
+                }
+                Event::EgressBitrateEstimate(estimate) => {
+                    debug!("bandwidth estimate: {:?}", estimate);
+                    return Ok(WebrtcEvent::BandwidthEstimate(estimate));
+                }
+                Event::ChannelOpen(id, label) => {
+                    info!("data channel open: {:?} {}", id, label);
+                    if self.channel.is_none() {
+                        self.channel = Some(id);
+                    }
+                    return Ok(WebrtcEvent::Continue);
+                }
+                Event::ChannelData(data) => {
+                    trace!(
+                        "data channel message: {:?} {} bytes",
+                        data.id,
+                        data.data.len()
+                    );
+                    return Ok(WebrtcEvent::ChannelData(data));
+                }
+                Event::ChannelClose(id) => {
+                    info!("data channel closed: {:?}", id);
+                    if self.channel == Some(id) {
+                        self.channel = None;
+                    }
+                    return Ok(WebrtcEvent::Continue);
+                }
                 Event::MediaAdded(media) => {
                     info!("Media Added: {:?}", media);
                     info!("Codec Config: {:?}", self.rtc.codec_config());
+                    // 作为 WHIP 服务端时 m-line 来自对端的 offer, 在这里记录视频的 mid
+                    if media.kind == MediaKind::Video && self.video_mid.is_none() {
+                        self.video_mid = Some(media.mid);
+                    }
+                    if media.kind == MediaKind::Audio && self.audio_mid.is_none() {
+                        self.audio_mid = Some(media.mid);
+                    }
                     return Ok(WebrtcEvent::Continue);
                 }
                 _ => {
                     return Ok(WebrtcEvent::Continue);
                 }
             },
-            Output::Timeout(timeout) => timeout,
+            Output::Timeout(timeout) => {
+                self.poll_turn().await;
+                timeout
+            }
+            Output::Transmit(send) if send.proto == Protocol::Tcp => {
+                if let Some(tcp) = &mut self.tcp {
+                    tcp.send(send.source, send.destination, &send.contents);
+                }
+                return Ok(WebrtcEvent::Continue);
+            }
             Output::Transmit(send) => {
-                // Apply random packet loss to outbound traffic
-                if let Err(e) = self.socket.send_to(&send.contents, send.destination).await {
+                // relay 候选者的数据需要封装成 Send indication 发给 TURN 服务器
+                let result =
+                    if let Some(turn) = self.turn.iter_mut().find(|t| t.relayed == send.source) {
+                        let (permission, indication) =
+                            turn.send_indication(send.destination, &send.contents);
+                        if let Some(permission) = permission {
+                            let _ = self.sockets.send_to(&permission, turn.server).await;
+                        }
+                        self.sockets.send_to(&indication, turn.server).await
+                    } else {
+                        self.sockets.send_to(&send.contents, send.destination).await
+                    };
+                if let Err(e) = result {
                     debug!(
                         "sending to {} => {}, len {} error {:?}",
                         send.source,
@@ -321,7 +1251,11 @@ impl Client {
             }
         };
 
-        let duration = timeout - Instant::now();
+        let mut duration = timeout - Instant::now();
+        if self.ice_restart.is_some() {
+            // 等待 ICE restart 的 PATCH 响应时不能一直阻塞在 socket 上
+            duration = duration.min(ICE_RESTART_POLL_INTERVAL);
+        }
         if duration.is_zero() {
             // Drive time forwards in rtc straight away.
             return match self.rtc.handle_input(Input::Timeout(Instant::now())) {
@@ -333,33 +1267,66 @@ impl Client {
             };
         }
 
-        let input = match tokio::time::timeout(duration, self.socket.recv_from(&mut self.buf)).await
-        {
-            Ok(Ok((n, source))) => {
-                // UDP data received.
-                info!(
-                    "received from {} => {}, len {}",
+        // 同时等待 UDP socket, TCP 连接上的数据和调用者的唤醒
+        // * 没有 TCP 连接或者唤醒通道时对应的分支一直等待
+        let (sockets, buf, tcp, wake) =
+            (&self.sockets, &mut self.buf, &mut self.tcp, &mut self.wake);
+        let tcp_packet = async {
+            match tcp {
+                Some(tcp) => tcp.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let woken = async {
+            match wake {
+                Some(wake) => wake.changed().await.is_ok(),
+                None => std::future::pending().await,
+            }
+        };
+        let received = tokio::time::timeout(duration, async {
+            tokio::select! {
+                res = sockets.recv_from(buf) => Received::Udp(res),
+                packet = tcp_packet => Received::Tcp(packet),
+                changed = woken => Received::Wake(changed),
+            }
+        })
+        .await;
+
+        let tcp_data: Vec<u8>;
+        let input = match received {
+            Ok(Received::Tcp((source, destination, data))) => {
+                trace!(
+                    "tcp received from {} => {}, len {}",
                     source,
-                    SocketAddr::new(
-                        self.local_socket_addr.ip(),
-                        self.socket.local_addr().unwrap().port(),
-                    ),
-                    n
+                    destination,
+                    data.len()
                 );
+                tcp_data = data;
+                let destination = self.nat_destination(destination, source);
                 Input::Receive(
                     Instant::now(),
                     Receive {
-                        proto: Protocol::Udp,
+                        proto: Protocol::Tcp,
                         source,
-                        destination: SocketAddr::new(
-                            self.local_socket_addr.ip(),
-                            self.socket.local_addr().unwrap().port(),
-                        ),
-                        contents: (&self.buf[..n]).try_into().expect("should webrtc"),
+                        destination,
+                        contents: tcp_data.as_slice().try_into().expect("should webrtc"),
                     },
                 )
             }
-            Ok(Err(e)) => match e.kind() {
+            Ok(Received::Wake(changed)) => {
+                if !changed {
+                    // 最后唤醒一次, 让调用者发现数据的来源已经关闭
+                    self.wake = None;
+                }
+                return Ok(WebrtcEvent::Continue);
+            }
+            Ok(Received::Udp(Ok((n, source)))) => {
+                // UDP data received.
+                let buf = self.buf;
+                self.handle_udp(source, &buf[..n])?;
+                return Ok(WebrtcEvent::Continue);
+            }
+            Ok(Received::Udp(Err(e))) => match e.kind() {
                 ErrorKind::ConnectionReset => return Ok(WebrtcEvent::Continue),
                 _ => {
                     error!("[TransportWebrtc] network error {:?}", e);
@@ -380,20 +1347,93 @@ impl Client {
         return Ok(WebrtcEvent::Continue);
     }
 
-    pub fn send_video(&mut self, frame_data: Bytes, pts: Duration) -> Result<(), WebrtcError> {
+    /// 把 UDP socket 上收到的数据交给 Rtc
+    /// * TURN 服务器转发的数据需要从 Data indication 中解出来, 其他的是 TURN 请求的响应
+    fn handle_udp(&mut self, source: SocketAddr, data: &[u8]) -> Result<(), WebrtcError> {
+        let relayed_data: Vec<u8>;
+        let (source, destination, contents) =
+            if let Some(turn) = self.turn.iter_mut().find(|t| t.server == source) {
+                let Some((peer, data)) = turn.handle(data) else {
+                    return Ok(());
+                };
+                trace!(
+                    "relayed from {} => {}, len {}",
+                    peer,
+                    turn.relayed,
+                    data.len()
+                );
+                relayed_data = data;
+                (peer, turn.relayed, relayed_data.as_slice())
+            } else if self.relay_only {
+                // 只使用 relay 候选者时不接受直接发到本地端口的数据
+                debug!("relay only, drop {} bytes from {}", data.len(), source);
+                return Ok(());
+            } else {
+                let Some(destination) = self.host_addr_for(source) else {
+                    debug!(
+                        "no host candidate for {}, drop {} bytes",
+                        source,
+                        data.len()
+                    );
+                    return Ok(());
+                };
+                let destination = self.nat_destination(destination, source);
+                info!(
+                    "received from {} => {}, len {}",
+                    source,
+                    destination,
+                    data.len()
+                );
+                (source, destination, data)
+            };
+
+        self.rtc
+            .handle_input(Input::Receive(
+                Instant::now(),
+                Receive {
+                    proto: Protocol::Udp,
+                    source,
+                    destination,
+                    contents: contents.try_into().expect("should webrtc"),
+                },
+            ))
+            .map_err(|e| WebrtcError::WebrtcError(e.into()))
+    }
+
+    /// 发送到期的 TURN 刷新请求 (分配和权限)
+    async fn poll_turn(&mut self) {
+        let now = Instant::now();
+        for turn in &mut self.turn {
+            for request in turn.poll_timeout(now) {
+                if let Err(e) = self.sockets.send_to(&request, turn.server).await {
+                    debug!("sending TURN refresh to {} error {:?}", turn.server, e);
+                }
+            }
+        }
+    }
+
+    /// 发送一帧视频, layer 为 simulcast 层的序号 (0 为最高层)
+    /// * 对端没有接受 simulcast 时只发送第一层
+    pub fn send_video(
+        &mut self,
+        layer: usize,
+        frame_data: Bytes,
+        pts: Duration,
+    ) -> Result<(), WebrtcError> {
+        let rid = match self.send_rids.get(layer) {
+            Some(rid) => Some(*rid),
+            None if layer == 0 && self.send_rids.is_empty() => None,
+            None => return Ok(()),
+        };
         if let Some(mid) = self.video_mid {
-            // TODO = maybe look this up once?
-            let params = &self
-                .rtc
-                .codec_config()
-                .find(|p| {
-                    debug!("payload: {:?}", p);
-                    p.spec().codec == Codec::H264
-                        && p.spec().format.profile_level_id.unwrap_or(0) == 4382751
-                })
-                .cloned()
-                .unwrap();
-            if let Some(writer) = self.rtc.writer(mid) {
+            let Some(params) = self.video_params.as_ref() else {
+                warn!("trying to send video before negotiation");
+                return Ok(());
+            };
+            if let Some(mut writer) = self.rtc.writer(mid) {
+                if let Some(rid) = rid {
+                    writer = writer.rid(rid);
+                }
                 let freq = params.spec().clock_rate;
                 let media_time: MediaTime = pts.into();
                 writer
@@ -410,4 +1450,476 @@ impl Client {
         }
         Ok(())
     }
+
+    /// 原样转发另一个会话收到的媒体数据 (restream), 不重新编码
+    /// * 按照编解码器找到这个会话协商的 payload type, 对端没有协商这个编解码器时返回 false
+    pub fn forward(&mut self, media: &MediaData) -> Result<bool, WebrtcError> {
+        let mid = match media.params.spec().codec {
+            Codec::Opus => self.audio_mid,
+            _ => self.video_mid,
+        };
+        let Some(writer) = mid.and_then(|mid| self.rtc.writer(mid)) else {
+            return Ok(false);
+        };
+        let Some(pt) = writer.match_params(media.params) else {
+            return Ok(false);
+        };
+
+        writer
+            .write(pt, media.network_time, media.time, media.data.clone())
+            .map_err(|e| WebrtcError::SendError(e.to_string()))?;
+        Ok(true)
+    }
+
+    /// 在数据通道上发送一条消息
+    /// * 数据通道还没有打开或者发送缓冲区已满时返回错误, 消息被丢弃
+    pub fn send_data(&mut self, message: &ChannelMessage) -> Result<(), WebrtcError> {
+        let Some(id) = self.channel else {
+            return Err(WebrtcError::SendError("data channel not open".to_string()));
+        };
+        let Some(mut channel) = self.rtc.channel(id) else {
+            return Err(WebrtcError::SendError(format!(
+                "no data channel for id {:?}",
+                id
+            )));
+        };
+
+        match channel.write(message.binary, &message.data) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(WebrtcError::SendError(
+                "data channel buffer full".to_string(),
+            )),
+            Err(e) => Err(WebrtcError::SendError(e.to_string())),
+        }
+    }
+
+    /// 对端请求关键帧的 simulcast 层的序号, 没有使用 simulcast 时为 None
+    pub fn send_layer(&self, rid: Option<Rid>) -> Option<usize> {
+        rid.and_then(|rid| self.send_rids.iter().position(|r| *r == rid))
+    }
+
+    pub fn video_mid(&self) -> Option<Mid> {
+        self.video_mid
+    }
+
+    /// 请求对端发送关键帧 (RTCP PLI 或 FIR), rid 为接收的 simulcast 层
+    pub fn request_keyframe(
+        &mut self,
+        rid: Option<Rid>,
+        kind: KeyframeRequestKind,
+    ) -> Result<(), WebrtcError> {
+        let Some(mid) = self.video_mid else {
+            return Err(WebrtcError::SendError("no video media".to_string()));
+        };
+        let Some(mut writer) = self.rtc.writer(mid) else {
+            return Err(WebrtcError::SendError(format!("no media for mid {}", mid)));
+        };
+
+        debug!("request keyframe: {:?} {:?}", rid, kind);
+        writer
+            .request_keyframe(rid, kind)
+            .map_err(|e| WebrtcError::SendError(e.to_string()))
+    }
+
+    /// 通过 WHEP layer 扩展请求 SFU 转发指定的 simulcast 层
+    /// * 服务端没有在 Link 头中提供 layer 扩展时返回 false
+    pub async fn select_layer(&mut self, rid: Rid) -> Result<bool, WebrtcError> {
+        let (Some(session), Some(mid)) = (&self.session, self.video_mid) else {
+            return Ok(false);
+        };
+        let Some(layer_url) = &session.layer_url else {
+            return Ok(false);
+        };
+
+        let body = serde_json::json!({
+            "mediaId": mid.to_string(),
+            "encodingId": rid.to_string(),
+        });
+        info!("select layer {}: {}", layer_url, body);
+        let mut request = reqwest::Client::new()
+            .post(layer_url.clone())
+            .header(USER_AGENT, "bitwhip")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(token) = &session.token {
+            request = request.bearer_auth(token);
+        }
+
+        let res = request
+            .send()
+            .await
+            .map_err(|e| WebrtcError::ServerError(e.into()))?;
+        if !res.status().is_success() {
+            return Err(WebrtcError::ServerError(
+                format!("layer selection failed with status: {}", res.status()).into(),
+            ));
+        }
+
+        Ok(true)
+    }
+
+    /// 告诉带宽估计当前实际编码的码率和希望达到的码率
+    /// * current 低于 desired 时会发送探测包, 估计才能向上增长
+    pub fn set_bitrate(&mut self, current: u64, desired: u64) {
+        let mut bwe = self.rtc.bwe();
+        bwe.set_current_bitrate(Bitrate::bps(current));
+        bwe.set_desired_bitrate(Bitrate::bps(desired));
+    }
+
+    /// 发送一个 Opus 数据包, pts 为音频采样数换算出来的时间
+    pub fn send_audio(&mut self, packet_data: Bytes, pts: Duration) -> Result<(), WebrtcError> {
+        let Some(mid) = self.audio_mid else {
+            warn!("trying to send audio without mid");
+            return Ok(());
+        };
+
+        // 对端拒绝了音频 m-line 或者没有接受 Opus 时直接丢弃
+        let Some(params) = self.audio_params.as_ref() else {
+            return Ok(());
+        };
+        if let Some(writer) = self.rtc.writer(mid) {
+            let media_time: MediaTime = pts.into();
+            writer
+                .write(
+                    params.pt(),
+                    Instant::now(),
+                    media_time.rebase(params.spec().clock_rate),
+                    packet_data,
+                )
+                .map_err(|e| WebrtcError::SendError(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+/// 编码器输出的 H264 码流 (local) 能否被对端接受的 profile-level-id (remote) 解码
+/// * profile-level-id 为 3 个字节: profile_idc, profile-iop (constraint_set 标志), level_idc
+/// * 对端要求的 constraint_set 标志编码器必须都满足, 不比较 level
+fn h264_profile_compatible(local: u32, remote: u32) -> bool {
+    let [_, local_idc, local_iop, _] = local.to_be_bytes();
+    let [_, remote_idc, remote_iop, _] = remote.to_be_bytes();
+
+    // constrained baseline (constraint_set1) 的码流同时符合 main 和 high profile
+    let constrained_baseline = local_idc == 0x42 && local_iop & 0x40 != 0;
+    (local_idc == remote_idc && remote_iop & !local_iop == 0)
+        || (constrained_baseline && matches!(remote_idc, 0x4d | 0x64))
+}
+
+/// 把 SDP 中的 ice-ufrag / ice-pwd 替换成 ICE restart 后新的凭证, 并去掉旧的候选者
+fn replace_remote_creds(sdp: &str, creds: &IceCreds) -> String {
+    sdp.lines()
+        .filter(|line| !line.starts_with("a=candidate:"))
+        .map(|line| {
+            if line.starts_with("a=ice-ufrag:") {
+                format!("a=ice-ufrag:{}", creds.ufrag)
+            } else if line.starts_with("a=ice-pwd:") {
+                format!("a=ice-pwd:{}", creds.pass)
+            } else {
+                line.to_string()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// 从 SDP (或 SDP 片段) 中解析 ice-ufrag / ice-pwd 和候选者
+pub fn parse_sdp_frag(sdp: &str) -> (Option<IceCreds>, Vec<Candidate>) {
+    let mut ufrag = None;
+    let mut pass = None;
+    let mut candidates = vec![];
+    for line in sdp.lines().map(|line| line.trim()) {
+        if let Some(value) = line.strip_prefix("a=ice-ufrag:") {
+            ufrag.get_or_insert(value.to_string());
+        } else if let Some(value) = line.strip_prefix("a=ice-pwd:") {
+            pass.get_or_insert(value.to_string());
+        } else if let Some(candidate) = line.strip_prefix("a=") {
+            if !candidate.starts_with("candidate:") {
+                continue;
+            }
+            match Candidate::from_sdp_string(candidate) {
+                Ok(candidate) => candidates.push(candidate),
+                Err(e) => warn!("Failed to parse candidate {}: {:?}", candidate, e),
+            }
+        }
+    }
+
+    let creds = match (ufrag, pass) {
+        (Some(ufrag), Some(pass)) => Some(IceCreds { ufrag, pass }),
+        _ => None,
+    };
+
+    (creds, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ice_server::tests::{MockTurnServer, TURN_PASSWORD, TURN_USERNAME};
+    use axum::{
+        Router,
+        http::{HeaderMap, StatusCode},
+        routing::{patch, post},
+    };
+
+    fn session(url: &str) -> Session {
+        Session {
+            url: reqwest::Url::parse(url).unwrap(),
+            token: None,
+            etag: Some("\"v1\"".to_string()),
+            trickle: true,
+            local_creds: IceCreds {
+                ufrag: "abcd".to_string(),
+                pass: "0123456789abcdef012345".to_string(),
+            },
+            answer: String::new(),
+            layer_url: None,
+        }
+    }
+
+    fn response(status: u16, etag: Option<&str>, body: &str) -> PatchResponse {
+        PatchResponse {
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+            etag: etag.map(|etag| etag.to_string()),
+            body: body.to_string(),
+        }
+    }
+
+    #[test]
+    fn parse_sdp_frag_reads_creds_and_candidates() {
+        let frag = "a=ice-ufrag:EsAw\r\n\
+                    a=ice-pwd:P2uYro0UCOQ4zxjKXaWCBui1\r\n\
+                    m=video 9 UDP/TLS/RTP/SAVPF 0\r\n\
+                    a=mid:0\r\n\
+                    a=candidate:1 1 udp 2130706431 192.0.2.1 50000 typ host\r\n\
+                    a=candidate:2 1 udp 1694498815 198.51.100.7 50001 typ srflx raddr 192.0.2.1 rport 50000\r\n\
+                    a=candidate:broken\r\n\
+                    a=end-of-candidates\r\n";
+        let (creds, candidates) = parse_sdp_frag(frag);
+
+        let creds = creds.unwrap();
+        assert_eq!(creds.ufrag, "EsAw");
+        assert_eq!(creds.pass, "P2uYro0UCOQ4zxjKXaWCBui1");
+        let addrs: Vec<SocketAddr> = candidates.iter().map(|c| c.addr()).collect();
+        assert_eq!(
+            addrs,
+            vec![
+                "192.0.2.1:50000".parse().unwrap(),
+                "198.51.100.7:50001".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_sdp_frag_without_pwd_has_no_creds() {
+        let (creds, candidates) = parse_sdp_frag("a=ice-ufrag:EsAw\r\n");
+        assert!(creds.is_none());
+        assert!(candidates.is_empty());
+    }
+
+    #[test]
+    fn replace_remote_creds_drops_old_candidates() {
+        let answer = "v=0\na=ice-ufrag:old\na=ice-pwd:oldpwd\na=candidate:1 1 udp 1 192.0.2.1 1 typ host\na=mid:0";
+        let creds = IceCreds {
+            ufrag: "new".to_string(),
+            pass: "newpwd".to_string(),
+        };
+        assert_eq!(
+            replace_remote_creds(answer, &creds),
+            "v=0\na=ice-ufrag:new\na=ice-pwd:newpwd\na=mid:0"
+        );
+    }
+
+    #[test]
+    fn h264_profiles() {
+        // constrained baseline 可以被 constrained baseline / main / high 解码
+        assert!(h264_profile_compatible(0x42e02a, 0x42e01f));
+        assert!(h264_profile_compatible(0x42e02a, 0x42001f));
+        assert!(h264_profile_compatible(0x42e02a, 0x4d001f));
+        assert!(h264_profile_compatible(0x42e02a, 0x64001f));
+        // 没有 constraint_set1 的 baseline 不满足 constrained baseline
+        assert!(!h264_profile_compatible(0x42001f, 0x42e01f));
+        assert!(!h264_profile_compatible(0x640c1f, 0x42e01f));
+    }
+
+    #[test]
+    fn patch_response_updates_etag() {
+        let mut session = session("http://127.0.0.1/session");
+
+        // 没有 ETag 的成功响应保留之前的 ETag
+        assert_eq!(
+            session.on_patch_response(response(204, None, "")).unwrap(),
+            ""
+        );
+        assert_eq!(session.etag.as_deref(), Some("\"v1\""));
+
+        let body = session
+            .on_patch_response(response(200, Some("\"v2\""), "a=ice-ufrag:x\r\n"))
+            .unwrap();
+        assert_eq!(body, "a=ice-ufrag:x\r\n");
+        assert_eq!(session.etag.as_deref(), Some("\"v2\""));
+    }
+
+    #[test]
+    fn patch_response_precondition_failed() {
+        let mut session = session("http://127.0.0.1/session");
+        assert!(
+            session
+                .on_patch_response(response(412, Some("\"v9\""), ""))
+                .is_err()
+        );
+        // 412 不更新 ETag, 也不关闭 trickle
+        assert_eq!(session.etag.as_deref(), Some("\"v1\""));
+        assert!(session.trickle);
+    }
+
+    #[test]
+    fn patch_not_supported_disables_trickle() {
+        for status in [405, 415, 501] {
+            let mut session = session("http://127.0.0.1/session");
+            assert!(
+                session
+                    .on_patch_response(response(status, None, ""))
+                    .is_err()
+            );
+            assert!(!session.trickle);
+        }
+    }
+
+    /// 模拟 WHIP 服务端: If-Match 为 `*` 或者当前 ETag 时返回新的 ETag, 否则返回 412
+    async fn patch_server() -> String {
+        let app = Router::new().route(
+            "/session",
+            patch(|headers: HeaderMap, body: String| async move {
+                let if_match = headers
+                    .get("if-match")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                if if_match != "*" && if_match != "\"v1\"" {
+                    return (
+                        StatusCode::PRECONDITION_FAILED,
+                        [("etag", "\"v2\"")],
+                        String::new(),
+                    );
+                }
+                assert!(body.starts_with("a=ice-ufrag:abcd\r\n"));
+                (
+                    StatusCode::OK,
+                    [("etag", "\"v2\"")],
+                    "a=ice-ufrag:srv\r\na=ice-pwd:serverpassword0123456\r\n".to_string(),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/session", addr)
+    }
+
+    #[tokio::test]
+    async fn patch_with_if_match() {
+        let mut session = session(&patch_server().await);
+        let frag = "a=ice-ufrag:abcd\r\na=ice-pwd:0123456789abcdef012345\r\n".to_string();
+
+        let if_match = session.etag.clone().unwrap();
+        let response = PatchResponse::send(session.patch(frag.clone(), &if_match))
+            .await
+            .unwrap();
+        let body = session.on_patch_response(response).unwrap();
+        assert_eq!(session.etag.as_deref(), Some("\"v2\""));
+        let (creds, _) = parse_sdp_frag(&body);
+        assert_eq!(creds.unwrap().ufrag, "srv");
+
+        // 过期的 ETag 返回 412
+        let response = PatchResponse::send(session.patch(frag.clone(), "\"v0\""))
+            .await
+            .unwrap();
+        assert_eq!(response.status, reqwest::StatusCode::PRECONDITION_FAILED);
+        assert!(session.on_patch_response(response).is_err());
+
+        // ICE restart 使用 `If-Match: *`
+        let response = PatchResponse::send(session.patch(frag, "*")).await.unwrap();
+        assert!(session.on_patch_response(response).is_ok());
+    }
+
+    async fn wait_connected(client: &mut Client) {
+        loop {
+            if let WebrtcEvent::Connected = client.recv().await.unwrap() {
+                return;
+            }
+        }
+    }
+
+    /// 只使用 relay 候选者的 WHEP 客户端通过回环地址上的 TURN 服务器连接到只有 host 候选者的服务端
+    #[tokio::test]
+    async fn relay_only_session_connects() {
+        let loopback: InterfaceFilter = "127.0.0.0/8".parse().unwrap();
+        let turn = MockTurnServer::start().await;
+
+        // 服务端: 收到 offer 后返回 answer, 然后等待连接
+        let (offer_tx, mut offer_rx) = unbounded_channel::<(String, oneshot::Sender<String>)>();
+        let server_config = ClientConfig {
+            include_loopback: true,
+            allow_interfaces: vec![loopback.clone()],
+            ..Default::default()
+        };
+        let server = tokio::spawn(async move {
+            let mut server = Client::new(&server_config).await.unwrap();
+            let (offer, answer_tx) = offer_rx.recv().await.unwrap();
+            answer_tx
+                .send(server.accept_whip_request(offer).unwrap())
+                .unwrap();
+            wait_connected(&mut server).await;
+        });
+        let app = Router::new().route(
+            "/whep",
+            post(move |offer: String| async move {
+                let (answer_tx, answer_rx) = oneshot::channel();
+                offer_tx.send((offer, answer_tx)).unwrap();
+                (
+                    StatusCode::CREATED,
+                    [("location", "/whep/session")],
+                    answer_rx.await.unwrap(),
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/whep", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let config = ClientConfig {
+            ice_servers: vec![
+                IceServer::parse(
+                    &format!("turn:{}", turn.addr),
+                    Some(TURN_USERNAME.to_string()),
+                    Some(TURN_PASSWORD.to_string()),
+                )
+                .unwrap(),
+            ],
+            include_loopback: true,
+            allow_interfaces: vec![loopback],
+            ice_transport_policy: IceTransportPolicy::Relay,
+            ..Default::default()
+        };
+        let mut client = Client::new(&config).await.unwrap();
+        // 只有 relay 候选者, 本地地址不出现在 offer 里
+        assert!(!client.local_candidates.is_empty());
+        assert!(
+            client
+                .local_candidates
+                .iter()
+                .all(|c| c.addr() == turn.relayed)
+        );
+
+        client
+            .send_whip_request(&url, &None, RtcDirection::RecvOnly)
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), wait_connected(&mut client))
+            .await
+            .expect("relay session connected");
+        tokio::time::timeout(Duration::from_secs(10), server)
+            .await
+            .expect("server connected")
+            .unwrap();
+    }
 }
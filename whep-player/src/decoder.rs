@@ -0,0 +1,134 @@
+use crate::encoder::OPUS_SAMPLE_RATE;
+use anyhow::{Result, anyhow};
+use ffmpeg::{
+    ChannelLayout, Packet,
+    codec::{Context as CodecContext, Id},
+    decoder::{Audio, Video},
+    format::{Sample, sample::Type as SampleType},
+    frame, software,
+    software::resampling::Context as Resampler,
+};
+use ffmpeg_next as ffmpeg;
+use str0m::format::{Codec, PayloadParams};
+use str0m::media::Pt;
+use tracing::info;
+
+/// 视频解码器
+/// * 根据协商得到的 payload type 选择 ffmpeg 解码器, 优先使用外部库, 不存在时使用 ffmpeg 内置的解码器
+pub struct VideoDecoder {
+    decoder: Video,
+    pt: Pt,
+}
+
+impl VideoDecoder {
+    pub fn new(params: &PayloadParams) -> Result<Self> {
+        let codec = params.spec().codec;
+        let names: &[&str] = match codec {
+            Codec::H264 => &["h264"],
+            Codec::H265 => &["hevc"],
+            Codec::Vp8 => &["libvpx", "vp8"],
+            Codec::Vp9 => &["libvpx-vp9", "vp9"],
+            Codec::Av1 => &["libdav1d", "av1"],
+            _ => &[],
+        };
+        let decoder = names
+            .iter()
+            .find_map(|name| ffmpeg::decoder::find_by_name(name))
+            .ok_or_else(|| anyhow!("Missing decoder for {:?}", codec))?;
+        info!(
+            "using decoder {} for payload type {:?}",
+            decoder.name(),
+            params.pt()
+        );
+
+        Ok(VideoDecoder {
+            decoder: CodecContext::new_with_codec(decoder).decoder().video()?,
+            pt: params.pt(),
+        })
+    }
+
+    pub fn pt(&self) -> Pt {
+        self.pt
+    }
+
+    /// 解码一帧的数据, 返回解码出的所有画面
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<frame::Video>> {
+        self.decoder.send_packet(&Packet::borrow(data))?;
+
+        let mut frames = vec![];
+        let mut frame = frame::Video::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            frames.push(frame);
+            frame = frame::Video::empty();
+        }
+
+        Ok(frames)
+    }
+}
+
+/// Opus 音频解码器
+/// * 输出 48kHz 双声道交错的 f32 采样, 可以直接交给 SDL 播放
+pub struct AudioDecoder {
+    decoder: Audio,
+    resampler: Option<Resampler>,
+}
+
+impl AudioDecoder {
+    pub fn new() -> Result<Self> {
+        let codec =
+            ffmpeg::decoder::find(Id::OPUS).ok_or_else(|| anyhow!("Missing decoder opus"))?;
+
+        let mut context = CodecContext::new_with_codec(codec);
+        // RTP 的 Opus 没有 extradata, SDP 中固定为 opus/48000/2
+        unsafe {
+            let context = &mut *context.as_mut_ptr();
+            context.sample_rate = OPUS_SAMPLE_RATE as i32;
+            context.ch_layout = ChannelLayout::STEREO.0;
+        }
+
+        Ok(AudioDecoder {
+            decoder: context.decoder().audio()?,
+            resampler: None,
+        })
+    }
+
+    pub fn decode(&mut self, data: &[u8]) -> Result<Vec<f32>> {
+        self.decoder.send_packet(&Packet::borrow(data))?;
+
+        let mut samples = vec![];
+        let mut frame = frame::Audio::empty();
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let input = (frame.format(), frame.channel_layout(), frame.rate());
+            let resampler = match &mut self.resampler {
+                Some(resampler)
+                    if (
+                        resampler.input().format,
+                        resampler.input().channel_layout,
+                        resampler.input().rate,
+                    ) == input =>
+                {
+                    resampler
+                }
+                resampler => resampler.insert(software::resampler(
+                    input,
+                    (
+                        Sample::F32(SampleType::Packed),
+                        ChannelLayout::STEREO,
+                        OPUS_SAMPLE_RATE,
+                    ),
+                )?),
+            };
+
+            let mut resampled = frame::Audio::empty();
+            resampler.run(&frame, &mut resampled)?;
+            let len = resampled.samples() * 2 * 4;
+            samples.extend(
+                resampled.data(0)[..len]
+                    .chunks_exact(4)
+                    .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]])),
+            );
+        }
+
+        Ok(samples)
+    }
+}
@@ -1,56 +1,279 @@
 use anyhow::{Context, Result, anyhow, bail};
-use ffmpeg::ffi::AVCodecContext;
-use ffmpeg::{Frame, Packet, codec::Context as CodecContext, encoder::Video};
+use ffmpeg::ffi::{AVCodecContext, AVPictureType, av_buffer_ref, av_hwframe_transfer_data};
+use ffmpeg::{
+    ChannelLayout, Packet, Rational,
+    codec::Context as CodecContext,
+    encoder::{Audio, Video},
+    format::{Pixel, Sample, sample::Type as SampleType},
+    frame, software,
+    software::{resampling::Context as Resampler, scaling},
+};
 use ffmpeg_next as ffmpeg;
 use log::info;
 use std::{
     collections::HashMap,
     ffi::{CString, c_void},
+    time::{Duration, Instant},
 };
+use whep_player::VideoEncoder;
+
+/// 发给视频编码线程的命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderControl {
+    KeyFrame(Option<usize>), // 对端请求某一个 simulcast 层 (None 为所有层) 的关键帧, 或者新的会话开始
+    Bitrate(u64),            // 根据带宽估计调整的目标码率 (bit/s)
+    ResolutionDivisor(u32),  // 采集的宽高除以这个数, 1 表示原始分辨率
+    FramerateDivisor(u32),   // 编码的帧率除以这个数, 1 表示原始帧率
+}
+
+/// 两次强制 IDR 之间的最小间隔, 避免大量关键帧请求导致码率暴涨
+const MIN_FORCED_IDR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// H264 constrained baseline 的 profile_idc 和 constraint_set0/1/2 标志, 低 8 位为 level_idc
+pub const H264_CONSTRAINED_BASELINE: u32 = 0x42e000;
+
+/// H264 各个 level 的限制 (ITU-T H.264 表 A-1): level_idc, 每秒最大宏块数, 每帧最大宏块数, baseline 最大码率 (kbit/s)
+const H264_LEVELS: &[(u32, u64, u64, u64)] = &[
+    (10, 1485, 99, 64),
+    (11, 3000, 396, 192),
+    (12, 6000, 396, 384),
+    (13, 11880, 396, 768),
+    (20, 11880, 396, 2000),
+    (21, 19800, 792, 4000),
+    (22, 20250, 1620, 4000),
+    (30, 40500, 1620, 10000),
+    (31, 108000, 3600, 14000),
+    (32, 216000, 5120, 20000),
+    (40, 245760, 8192, 20000),
+    (41, 245760, 8192, 50000),
+    (42, 522240, 8704, 50000),
+    (50, 589824, 22080, 135000),
+    (51, 983040, 36864, 240000),
+    (52, 2073600, 36864, 240000),
+];
+
+/// 能够容纳指定宽高, 帧率和码率的最低 level_idc, 超过所有 level 时为最高的 5.2
+pub fn h264_level(dimensions: (u32, u32), frame_rate: i32, bit_rate: u64) -> u32 {
+    let (width, height) = dimensions;
+    let frame_size = (width as u64).div_ceil(16) * (height as u64).div_ceil(16);
+    let mb_rate = frame_size * frame_rate.max(1) as u64;
+    H264_LEVELS
+        .iter()
+        .find(|&&(_, max_mbps, max_fs, max_br)| {
+            frame_size <= max_fs && mb_rate <= max_mbps && bit_rate <= max_br * 1000
+        })
+        .map_or(52, |&(level, ..)| level)
+}
+
+/// H264 编码器的实现
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Nvenc,    // h264_nvenc, 可以直接编码 D3D11 纹理
+    X264,     // libx264
+    OpenH264, // libopenh264
+}
+
+impl Backend {
+    /// 命令行选择的编码器, auto 时按顺序尝试, 使用第一个能打开的
+    pub fn candidates(encoder: VideoEncoder) -> Vec<Self> {
+        match encoder {
+            VideoEncoder::Auto => vec![Self::Nvenc, Self::X264, Self::OpenH264],
+            VideoEncoder::Nvenc => vec![Self::Nvenc],
+            VideoEncoder::X264 => vec![Self::X264],
+            VideoEncoder::Openh264 => vec![Self::OpenH264],
+        }
+    }
+
+    fn codec_name(self) -> &'static str {
+        match self {
+            Self::Nvenc => "h264_nvenc",
+            Self::X264 => "libx264",
+            Self::OpenH264 => "libopenh264",
+        }
+    }
+
+    /// 编码器接受的内存中的像素格式, 第一个为首选
+    fn formats(self) -> &'static [Pixel] {
+        match self {
+            Self::Nvenc => &[Pixel::NV12, Pixel::YUV420P, Pixel::BGRA],
+            Self::X264 => &[Pixel::YUV420P, Pixel::NV12],
+            Self::OpenH264 => &[Pixel::YUV420P],
+        }
+    }
+
+    /// format 的画面交给编码器之前需要转换成的像素格式
+    pub fn format_for(self, format: Pixel) -> Pixel {
+        let formats = self.formats();
+        if formats.contains(&format) {
+            format
+        } else {
+            formats[0]
+        }
+    }
+
+    /// 低延迟编码的选项, profile 为 constrained baseline, level 为 SDP 中声明的 level_idc
+    /// * level 按照 level_idc 的写法设置 (比如 42 表示 4.2), nvenc / x264 的 level 选项
+    ///   和 openh264 使用的 AVCodecContext.level 都接受这种写法
+    fn options(self, level: u32) -> HashMap<String, String> {
+        let options: &[(&str, &str)] = match self {
+            Self::Nvenc => &[
+                ("preset", "p6"),
+                ("tune", "ull"),
+                ("profile", "baseline"),
+                // pict_type 为 I 的帧编码为 IDR, 而不只是 I 帧
+                ("forced-idr", "1"),
+            ],
+            Self::X264 => &[
+                ("preset", "veryfast"),
+                // 没有 lookahead 和帧缓冲, 每输入一帧立即输出
+                ("tune", "zerolatency"),
+                ("profile", "baseline"),
+                ("forced-idr", "1"),
+            ],
+            // openh264 没有 B 帧和 lookahead, 本身就是逐帧输出
+            Self::OpenH264 => &[("profile", "constrained_baseline"), ("rc_mode", "bitrate")],
+        };
+
+        let mut options: HashMap<String, String> = options
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        options.insert("level".to_string(), level.to_string());
+        options
+    }
+}
 
 pub struct Encoder {
     encoder: Video,
+    backend: Backend,
+    format: Pixel, // 编码器的输入格式, 其他格式的画面先转换
     dimensions: (u32, u32),
+    scaler: Scaler,
+    frame_index: i64,          // 输入编码器的帧数, 作为时间基为 1/帧率 的 pts
+    idr_pending: bool,         // 收到了关键帧请求, 还没有编码出 IDR
+    last_idr: Option<Instant>, // 上一个关键帧 (包括 GOP 自然产生的) 的时间
 }
 
 impl Encoder {
-    pub fn new<F>(
-        encoder: &str,
-        encoder_options: Option<HashMap<String, String>>,
-        setting_func: F,
-    ) -> Result<Self>
-    where
-        F: FnOnce(&mut ffmpeg::encoder::video::Video) -> Result<()>,
-    {
-        let codec = ffmpeg::encoder::find_by_name(encoder)
-            .ok_or_else(|| anyhow!("Missing encoder {}", encoder))?;
+    /// 按照 frame 的尺寸和格式创建编码器
+    /// * D3D11 纹理直接交给 nvenc 编码, 其他情况下编码时先转换为编码器支持的像素格式
+    /// * level 为推流时声明的 level_idc, 由 h264_level 按照最高的码率选择, 重新创建编码器时不变
+    pub fn new(
+        backend: Backend,
+        frame: &frame::Video,
+        frame_rate: i32,
+        bit_rate: u64,
+        keyframe_interval: u32,
+        level: u32,
+    ) -> Result<Self> {
+        let codec_name = backend.codec_name();
+        let codec = ffmpeg::encoder::find_by_name(codec_name)
+            .ok_or_else(|| anyhow!("Missing encoder {}", codec_name))?;
 
         let codec_context = CodecContext::new_with_codec(codec);
 
         let mut encoder = codec_context.encoder().video()?;
 
-        setting_func(&mut encoder)?;
-
-        let dimensions = (encoder.width(), encoder.height());
-
-        if let Some(encoder_options) = encoder_options {
-            for (key, value) in encoder_options.iter() {
-                info!("Setting option {key} {value}");
-                unsafe { Self::set_option(encoder.as_mut_ptr(), &key, &value)? };
+        let hw_frames = unsafe { (*frame.as_ptr()).hw_frames_ctx };
+        let format = if backend == Backend::Nvenc && !hw_frames.is_null() {
+            Pixel::D3D11
+        } else {
+            backend.format_for(frame.format())
+        };
+        let frame_rate = Rational::new(frame_rate, 1);
+        encoder.set_bit_rate(bit_rate as usize);
+        encoder.set_width(frame.width());
+        encoder.set_height(frame.height());
+        encoder.set_time_base(frame_rate.invert());
+        encoder.set_frame_rate(Some(frame_rate));
+        // 关键帧请求会强制 IDR, 周期性的关键帧只用于没有反馈的接收端恢复, 间隔可以较长
+        encoder.set_gop((frame_rate.numerator() as u32).saturating_mul(keyframe_interval));
+        encoder.set_max_b_frames(0);
+        encoder.set_format(format);
+        if format == Pixel::D3D11 {
+            unsafe {
+                (*encoder.as_mut_ptr()).hw_frames_ctx = av_buffer_ref(hw_frames);
             }
         }
 
+        for (key, value) in backend.options(level).iter() {
+            info!("Setting option {key} {value}");
+            unsafe { Self::set_option(encoder.as_mut_ptr(), &key, &value)? };
+        }
+
         Ok(Encoder {
             encoder: encoder.open()?,
-            dimensions,
+            backend,
+            format,
+            dimensions: (frame.width(), frame.height()),
+            scaler: Scaler::new(),
+            frame_index: 0,
+            idr_pending: false,
+            last_idr: None,
         })
     }
 
-    pub fn encode(&mut self, frame: &Frame) -> Result<Option<Packet>> {
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// 让之后的一帧编码为 IDR
+    /// * 距离上一个关键帧不足最小间隔时推迟到间隔满足后
+    pub fn force_idr(&mut self) {
+        self.idr_pending = true;
+    }
+
+    /// 运行时修改目标码率, 返回 false 表示需要重新创建编码器才能生效
+    /// * 编码器 (nvenc / x264) 在下一次 send_frame 时发现码率变化并重新配置, 不需要重新打开
+    /// * FFmpeg 的 libopenh264 只在打开时读取码率
+    pub fn set_bit_rate(&mut self, bit_rate: u64) -> bool {
+        if self.backend == Backend::OpenH264 {
+            return false;
+        }
+        unsafe {
+            (*self.encoder.as_mut_ptr()).bit_rate = bit_rate as i64;
+        }
+
+        true
+    }
+
+    pub fn encode(&mut self, frame: &mut frame::Video) -> Result<Option<Packet>> {
+        // 软件编码器不能直接使用采集的格式 (比如 D3D11 纹理, BGRA) 时先转换
+        let mut converted;
+        let frame = if frame.format() != self.format {
+            let (width, height) = self.dimensions;
+            converted = self.scaler.run(frame, self.format, width, height)?;
+            &mut converted
+        } else {
+            frame
+        };
+        // 采集的时间戳不一定连续, 编码器的码率控制使用按帧数递增的 pts
+        frame.set_pts(Some(self.frame_index));
+        self.frame_index += 1;
+
+        let force_idr = self.idr_pending
+            && self
+                .last_idr
+                .is_none_or(|last| last.elapsed() >= MIN_FORCED_IDR_INTERVAL);
+        // pict_type 为 I 时编码器输出关键帧 (nvenc 需要 forced-idr 才会输出 IDR)
+        unsafe {
+            (*frame.as_mut_ptr()).pict_type = if force_idr {
+                AVPictureType::AV_PICTURE_TYPE_I
+            } else {
+                AVPictureType::AV_PICTURE_TYPE_NONE
+            };
+        }
+        if force_idr {
+            info!("forcing IDR");
+            self.idr_pending = false;
+        }
         self.encoder.send_frame(frame)?;
 
         let mut packet = Packet::empty();
         if self.encoder.receive_packet(&mut packet).is_ok() {
+            if packet.is_key() {
+                self.last_idr = Some(Instant::now());
+            }
             return Ok(Some(packet));
         }
 
@@ -78,3 +301,213 @@ impl Encoder {
         return self.dimensions;
     }
 }
+
+/// 缩放画面和转换像素格式
+/// * 用于编码 simulcast 的低分辨率层, 以及把画面转换为软件编码器支持的格式
+/// * 显存中的画面 (D3D11 纹理) 先下载到内存, 再用 swscale 处理
+pub struct Scaler {
+    scaler: Option<scaling::Context>,
+}
+
+impl Scaler {
+    pub fn new() -> Self {
+        Self { scaler: None }
+    }
+
+    /// 宽高除以 divisor (向下取偶数), 并转换为 format
+    pub fn scale(
+        &mut self,
+        frame: &frame::Video,
+        divisor: u32,
+        format: Pixel,
+    ) -> Result<frame::Video> {
+        let width = (frame.width() / divisor).max(2) & !1;
+        let height = (frame.height() / divisor).max(2) & !1;
+        self.run(frame, format, width, height)
+    }
+
+    /// 转换为 format 和指定的宽高
+    pub fn run(
+        &mut self,
+        frame: &frame::Video,
+        format: Pixel,
+        width: u32,
+        height: u32,
+    ) -> Result<frame::Video> {
+        let mut downloaded = frame::Video::empty();
+        let source = if unsafe { (*frame.as_ptr()).hw_frames_ctx.is_null() } {
+            frame
+        } else {
+            let ret =
+                unsafe { av_hwframe_transfer_data(downloaded.as_mut_ptr(), frame.as_ptr(), 0) };
+            if ret < 0 {
+                bail!("av_hwframe_transfer_data failed: {ret}");
+            }
+            &downloaded
+        };
+
+        let reuse = self.scaler.as_ref().is_some_and(|scaler| {
+            let input = scaler.input();
+            let output = scaler.output();
+            input.format == source.format()
+                && (input.width, input.height) == (source.width(), source.height())
+                && (output.format, output.width, output.height) == (format, width, height)
+        });
+        if !reuse {
+            self.scaler = Some(scaling::Context::get(
+                source.format(),
+                source.width(),
+                source.height(),
+                format,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            )?);
+        }
+
+        let mut scaled = frame::Video::empty();
+        self.scaler.as_mut().unwrap().run(source, &mut scaled)?;
+        scaled.set_pts(frame.pts());
+
+        Ok(scaled)
+    }
+}
+
+/// Opus 固定使用 48kHz 采样率 (RFC 7587)
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
+const OPUS_CHANNELS: usize = 2;
+
+/// libopus 音频编码器
+/// * 输入的音频帧先重采样为 48kHz 双声道 s16, 再按编码器的帧长 (默认 20ms) 切分后编码
+/// * 输出数据包的 pts 以采样数为单位 (时间基 1/48000)
+pub struct AudioEncoder {
+    encoder: Audio,
+    resampler: Option<Resampler>,
+    samples: Vec<i16>, // 等待编码的交错采样
+    frame_size: usize,
+    pts: i64,
+}
+
+impl AudioEncoder {
+    pub fn new(bit_rate: usize) -> Result<Self> {
+        let codec = ffmpeg::encoder::find_by_name("libopus")
+            .ok_or_else(|| anyhow!("Missing encoder libopus"))?;
+
+        let mut encoder = CodecContext::new_with_codec(codec).encoder().audio()?;
+        encoder.set_rate(OPUS_SAMPLE_RATE as i32);
+        encoder.set_format(Sample::I16(SampleType::Packed));
+        encoder.set_channel_layout(ChannelLayout::STEREO);
+        encoder.set_bit_rate(bit_rate);
+        encoder.set_time_base(Rational::new(1, OPUS_SAMPLE_RATE as i32));
+        // 关闭 SILK 以获得最低的算法延迟
+        unsafe { Encoder::set_option(encoder.as_mut_ptr(), "application", "lowdelay")? };
+
+        let encoder = encoder.open()?;
+        let frame_size = encoder.frame_size() as usize;
+
+        Ok(AudioEncoder {
+            encoder,
+            resampler: None,
+            samples: vec![],
+            frame_size,
+            pts: 0,
+        })
+    }
+
+    pub fn encode(&mut self, frame: &frame::Audio) -> Result<Vec<Packet>> {
+        // 输入格式变化时重新创建重采样器
+        let input = (frame.format(), frame.channel_layout(), frame.rate());
+        let resampler = match &mut self.resampler {
+            Some(resampler)
+                if (
+                    resampler.input().format,
+                    resampler.input().channel_layout,
+                    resampler.input().rate,
+                ) == input =>
+            {
+                resampler
+            }
+            resampler => resampler.insert(software::resampler(
+                input,
+                (
+                    Sample::I16(SampleType::Packed),
+                    ChannelLayout::STEREO,
+                    OPUS_SAMPLE_RATE,
+                ),
+            )?),
+        };
+
+        let mut resampled = frame::Audio::empty();
+        resampler.run(frame, &mut resampled)?;
+        let len = resampled.samples() * OPUS_CHANNELS * 2;
+        self.samples.extend(
+            resampled.data(0)[..len]
+                .chunks_exact(2)
+                .map(|b| i16::from_ne_bytes([b[0], b[1]])),
+        );
+
+        let mut packets = vec![];
+        while self.samples.len() >= self.frame_size * OPUS_CHANNELS {
+            let mut input = frame::Audio::new(
+                Sample::I16(SampleType::Packed),
+                self.frame_size,
+                ChannelLayout::STEREO,
+            );
+            input.set_rate(OPUS_SAMPLE_RATE);
+            input.set_pts(Some(self.pts));
+            for (dst, src) in input
+                .data_mut(0)
+                .chunks_exact_mut(2)
+                .zip(self.samples.drain(..self.frame_size * OPUS_CHANNELS))
+            {
+                dst.copy_from_slice(&src.to_ne_bytes());
+            }
+            self.pts += self.frame_size as i64;
+
+            self.encoder.send_frame(&input)?;
+            let mut packet = Packet::empty();
+            while self.encoder.receive_packet(&mut packet).is_ok() {
+                packets.push(packet);
+                packet = Packet::empty();
+            }
+        }
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn h264_level_fits_frame() {
+        // 1280x720 为 3600 个宏块, 30 fps 时 108000 宏块/秒, 正好是 3.1 的上限
+        assert_eq!(h264_level((1280, 720), 30, 2_000_000), 31);
+        assert_eq!(h264_level((1280, 720), 60, 2_000_000), 32);
+        // 1080p 高度按 16 对齐为 1088, 8160 个宏块
+        assert_eq!(h264_level((1920, 1080), 30, 4_000_000), 40);
+        assert_eq!(h264_level((1920, 1080), 60, 4_000_000), 42);
+        assert_eq!(h264_level((640, 360), 30, 1_000_000), 30);
+        assert_eq!(h264_level((320, 240), 15, 300_000), 12);
+        // 码率超过 level 的限制时提高 level
+        assert_eq!(h264_level((1920, 1080), 30, 30_000_000), 41);
+        assert_eq!(h264_level((7680, 4320), 60, 100_000_000), 52);
+    }
+
+    #[test]
+    fn options_set_level_for_every_backend() {
+        // 2160p60 超过了 4.2, 所有编码器都按照 h264_level 的结果设置, 而不是固定的 4.2
+        let level = h264_level((3840, 2160), 60, 20_000_000);
+        assert_eq!(level, 52);
+        for backend in [Backend::Nvenc, Backend::X264, Backend::OpenH264] {
+            let options = backend.options(level);
+            assert_eq!(options["level"], "52", "{:?}", backend);
+        }
+
+        let level = h264_level((1280, 720), 30, 2_000_000);
+        assert_eq!(Backend::X264.options(level)["level"], "31");
+        assert_eq!(H264_CONSTRAINED_BASELINE | level, 0x42e01f);
+    }
+}
@@ -0,0 +1,1075 @@
+use crate::net::Sockets;
+use anyhow::{Result, anyhow, bail};
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+// STUN 消息头中的 magic cookie (RFC 5389)
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+// STUN method
+const BINDING: u16 = 0x0001;
+const ALLOCATE: u16 = 0x0003;
+const REFRESH: u16 = 0x0004;
+const SEND: u16 = 0x0006;
+const DATA: u16 = 0x0007;
+const CREATE_PERMISSION: u16 = 0x0008;
+
+// STUN class
+const CLASS_REQUEST: u16 = 0x0000;
+const CLASS_INDICATION: u16 = 0x0010;
+const CLASS_SUCCESS: u16 = 0x0100;
+const CLASS_ERROR: u16 = 0x0110;
+
+// STUN attribute
+const ATTR_USERNAME: u16 = 0x0006;
+const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+const ATTR_ERROR_CODE: u16 = 0x0009;
+const ATTR_LIFETIME: u16 = 0x000D;
+const ATTR_XOR_PEER_ADDRESS: u16 = 0x0012;
+const ATTR_DATA: u16 = 0x0013;
+const ATTR_REALM: u16 = 0x0014;
+const ATTR_NONCE: u16 = 0x0015;
+const ATTR_XOR_RELAYED_ADDRESS: u16 = 0x0016;
+const ATTR_REQUESTED_TRANSPORT: u16 = 0x0019;
+const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+// REQUESTED-TRANSPORT 中 UDP 的协议号
+const TRANSPORT_UDP: u8 = 17;
+
+// TURN 权限的有效期是 5 分钟, 提前 1 分钟刷新
+const PERMISSION_REFRESH: Duration = Duration::from_secs(4 * 60);
+
+// 单个 STUN 事务的重传次数和超时
+const TRANSACTION_RETRIES: u32 = 3;
+const TRANSACTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IceServerKind {
+    Stun,
+    Turn,
+}
+
+/// STUN/TURN 服务器
+/// * 来自 WHIP/WHEP 响应的 `Link: <turn:...>; rel="ice-server"` 头, 或者命令行的 `--stun` / `--turn`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IceServer {
+    pub url: String,
+    pub kind: IceServerKind,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl IceServer {
+    /// 解析 `stun:host[:port]` / `turn:host[:port][?transport=udp]` 格式的 URL (RFC 7064 / RFC 7065)
+    pub fn parse(url: &str, username: Option<String>, credential: Option<String>) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid ice server url {}", url))?;
+        let kind = match scheme {
+            "stun" => IceServerKind::Stun,
+            "turn" => IceServerKind::Turn,
+            // 目前只支持 UDP, stuns/turns 需要 TLS
+            _ => bail!("Unsupported ice server scheme {}", url),
+        };
+
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            if let Some(("transport", transport)) = param.split_once('=')
+                && transport != "udp"
+            {
+                bail!("Unsupported ice server transport {}", url);
+            }
+        }
+
+        // IPv6 地址需要用 [] 包起来: turn:[::1]:3478
+        let (host, port) = if let Some(v6) = address.strip_prefix('[') {
+            let (host, port) = v6
+                .split_once(']')
+                .ok_or_else(|| anyhow!("Invalid ice server url {}", url))?;
+            (host, port.strip_prefix(':'))
+        } else {
+            match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            }
+        };
+        let port = match port {
+            Some(port) => port.parse()?,
+            None => 3478,
+        };
+        if host.is_empty() {
+            bail!("Invalid ice server url {}", url);
+        }
+
+        Ok(Self {
+            url: url.to_string(),
+            kind,
+            host: host.to_string(),
+            port,
+            username,
+            credential,
+        })
+    }
+
+    /// WHIP/WHEP 服务端在响应中下发这个服务器使用的 Link 头 (RFC 9725)
+    pub fn to_link(&self) -> String {
+        let mut link = format!("<{}>; rel=\"ice-server\"", self.url);
+        if let (Some(username), Some(credential)) = (&self.username, &self.credential) {
+            link.push_str(&format!(
+                "; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                username.replace('"', "\\\""),
+                credential.replace('"', "\\\"")
+            ));
+        }
+        link
+    }
+
+    /// 解析 DNS, 返回所有地址 (IPv4 / IPv6)
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port))
+            .await?
+            .collect();
+        if addrs.is_empty() {
+            bail!("Failed to resolve ice server {}", self.url);
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// 解析 WHIP/WHEP 响应中的 Link 头, 返回每个链接的 URL 和参数 (参数名为小写)
+/// * 一个 Link 头里可能有多个用逗号分隔的链接
+pub fn parse_links<'a>(
+    values: impl Iterator<Item = &'a str>,
+) -> Vec<(String, HashMap<String, String>)> {
+    let mut result = vec![];
+    for value in values {
+        // URL 中不会出现 `,<`, 以它作为链接之间的分隔
+        let mut links: Vec<String> = vec![];
+        for part in value.split(',') {
+            match links.last_mut() {
+                Some(last) if !part.trim_start().starts_with('<') => {
+                    last.push(',');
+                    last.push_str(part);
+                }
+                _ => links.push(part.to_string()),
+            }
+        }
+
+        for link in links {
+            let mut params = link.split(';').map(|p| p.trim());
+            let Some(url) = params
+                .next()
+                .and_then(|url| url.strip_prefix('<'))
+                .and_then(|url| url.strip_suffix('>'))
+            else {
+                continue;
+            };
+
+            let params: HashMap<String, String> = params
+                .filter_map(|p| p.split_once('='))
+                .map(|(k, v)| (k.trim().to_lowercase(), unquote(v.trim())))
+                .collect();
+            result.push((url.to_string(), params));
+        }
+    }
+
+    result
+}
+
+/// 从 Link 头中取出 STUN/TURN 服务器 (draft-ietf-wish-whip 4.4)
+/// * `Link: <turn:turn.example.net?transport=udp>; rel="ice-server"; username="user"; credential="pass"`
+pub fn parse_link_headers<'a>(values: impl Iterator<Item = &'a str>) -> Vec<IceServer> {
+    let mut servers = vec![];
+    for (url, params) in parse_links(values) {
+        if params.get("rel").map(|r| r.as_str()) != Some("ice-server") {
+            continue;
+        }
+
+        match IceServer::parse(
+            &url,
+            params.get("username").cloned(),
+            params.get("credential").cloned(),
+        ) {
+            Ok(server) => servers.push(server),
+            Err(e) => warn!("Skip ice server {}: {:?}", url, e),
+        }
+    }
+
+    servers
+}
+
+/// 去掉参数值的引号, 还原 quoted-string 中转义的字符 (RFC 9110 5.6.4)
+fn unquote(value: &str) -> String {
+    let Some(quoted) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    else {
+        return value.to_string();
+    };
+
+    let mut result = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// STUN 消息 (RFC 5389)
+struct StunMessage {
+    typ: u16,
+    txid: [u8; 12],
+    attrs: Vec<(u16, Vec<u8>)>,
+}
+
+impl StunMessage {
+    fn new(method: u16, class: u16) -> Self {
+        Self {
+            typ: method | class,
+            txid: rand::random(),
+            attrs: vec![],
+        }
+    }
+
+    fn method(&self) -> u16 {
+        self.typ & !(CLASS_SUCCESS | CLASS_INDICATION)
+    }
+
+    fn class(&self) -> u16 {
+        self.typ & (CLASS_SUCCESS | CLASS_INDICATION)
+    }
+
+    fn attr(&self, typ: u16) -> Option<&[u8]> {
+        self.attrs
+            .iter()
+            .find(|(t, _)| *t == typ)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn with(mut self, typ: u16, value: impl Into<Vec<u8>>) -> Self {
+        self.attrs.push((typ, value.into()));
+        self
+    }
+
+    fn with_address(self, typ: u16, addr: SocketAddr) -> Self {
+        let value = xor_address(addr, &self.txid);
+        self.with(typ, value)
+    }
+
+    fn address(&self, typ: u16) -> Option<SocketAddr> {
+        self.attr(typ)
+            .and_then(|v| parse_xor_address(v, &self.txid))
+    }
+
+    fn error_code(&self) -> Option<u16> {
+        self.attr(ATTR_ERROR_CODE)
+            .filter(|v| v.len() >= 4)
+            .map(|v| (v[2] & 0x07) as u16 * 100 + v[3] as u16)
+    }
+
+    fn string(&self, typ: u16) -> Option<String> {
+        self.attr(typ)
+            .map(|v| String::from_utf8_lossy(v).to_string())
+    }
+
+    /// 编码, 传入 key 时在最后追加 MESSAGE-INTEGRITY
+    fn encode(&self, key: Option<&[u8]>) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(128);
+        buf.extend_from_slice(&self.typ.to_be_bytes());
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf.extend_from_slice(&self.txid);
+        for (typ, value) in &self.attrs {
+            buf.extend_from_slice(&typ.to_be_bytes());
+            buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buf.extend_from_slice(value);
+            // 属性按 4 字节对齐
+            buf.resize(buf.len() + (4 - value.len() % 4) % 4, 0);
+        }
+
+        if let Some(key) = key {
+            // MESSAGE-INTEGRITY 计算时, 头部的长度需要包含 MESSAGE-INTEGRITY 本身 (24 字节)
+            let len = (buf.len() - 20 + 24) as u16;
+            buf[2..4].copy_from_slice(&len.to_be_bytes());
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key");
+            mac.update(&buf);
+            let digest = mac.finalize().into_bytes();
+            buf.extend_from_slice(&ATTR_MESSAGE_INTEGRITY.to_be_bytes());
+            buf.extend_from_slice(&20u16.to_be_bytes());
+            buf.extend_from_slice(&digest);
+        }
+
+        let len = (buf.len() - 20) as u16;
+        buf[2..4].copy_from_slice(&len.to_be_bytes());
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() < 20 || buf[0] & 0xC0 != 0 {
+            return None;
+        }
+        if u32::from_be_bytes(buf[4..8].try_into().ok()?) != MAGIC_COOKIE {
+            return None;
+        }
+
+        let typ = u16::from_be_bytes([buf[0], buf[1]]);
+        let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        let txid = buf[8..20].try_into().ok()?;
+        let body = buf.get(20..20 + len)?;
+
+        let mut attrs = vec![];
+        let mut offset = 0;
+        while offset + 4 <= body.len() {
+            let typ = u16::from_be_bytes([body[offset], body[offset + 1]]);
+            let len = u16::from_be_bytes([body[offset + 2], body[offset + 3]]) as usize;
+            let value = body.get(offset + 4..offset + 4 + len)?;
+            attrs.push((typ, value.to_vec()));
+            offset += 4 + len + (4 - len % 4) % 4;
+        }
+
+        Some(Self { typ, txid, attrs })
+    }
+}
+
+fn xor_address(addr: SocketAddr, txid: &[u8; 12]) -> Vec<u8> {
+    let mut value = vec![0];
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            value.push(0x01);
+            value.extend_from_slice(&port.to_be_bytes());
+            let ip = u32::from(ip) ^ MAGIC_COOKIE;
+            value.extend_from_slice(&ip.to_be_bytes());
+        }
+        IpAddr::V6(ip) => {
+            value.push(0x02);
+            value.extend_from_slice(&port.to_be_bytes());
+            let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+            mask.extend_from_slice(txid);
+            value.extend(ip.octets().iter().zip(mask).map(|(a, b)| a ^ b));
+        }
+    }
+
+    value
+}
+
+fn parse_xor_address(value: &[u8], txid: &[u8; 12]) -> Option<SocketAddr> {
+    if value.len() < 8 {
+        return None;
+    }
+
+    let port = u16::from_be_bytes([value[2], value[3]]) ^ (MAGIC_COOKIE >> 16) as u16;
+    let ip = match value[1] {
+        0x01 => {
+            let ip = u32::from_be_bytes(value[4..8].try_into().ok()?) ^ MAGIC_COOKIE;
+            IpAddr::from(ip.to_be_bytes())
+        }
+        0x02 if value.len() >= 20 => {
+            let mut mask = MAGIC_COOKIE.to_be_bytes().to_vec();
+            mask.extend_from_slice(txid);
+            let mut octets = [0u8; 16];
+            for (i, octet) in octets.iter_mut().enumerate() {
+                *octet = value[4 + i] ^ mask[i];
+            }
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// 在 socket 上完成一次 STUN 事务, 超时后重传
+/// * 调用时 socket 上不能有其他的接收者, 只在开始 ICE 之前 (或者 recv 循环之外) 使用
+/// * 事务过程中收到的其他来源的数据包 (比如对端的连通性检查) 放在 skipped 中, 由调用者交给 ICE 处理
+async fn transaction(
+    socket: &Sockets,
+    server: SocketAddr,
+    request: &StunMessage,
+    key: Option<&[u8]>,
+    skipped: &mut Vec<(SocketAddr, Vec<u8>)>,
+) -> Result<StunMessage> {
+    let data = request.encode(key);
+    let mut buf = [0u8; 1500];
+    for _ in 0..TRANSACTION_RETRIES {
+        socket.send_to(&data, server).await?;
+
+        let deadline = Instant::now() + TRANSACTION_TIMEOUT;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            let Ok(res) = tokio::time::timeout(timeout, socket.recv_from(&mut buf)).await else {
+                break;
+            };
+            let (n, source) = res?;
+            if source != server {
+                debug!("skip packet from {} during stun transaction", source);
+                skipped.push((source, buf[..n].to_vec()));
+                continue;
+            }
+            match StunMessage::decode(&buf[..n]) {
+                Some(response) if response.txid == request.txid => return Ok(response),
+                _ => continue,
+            }
+        }
+    }
+
+    bail!("STUN transaction to {} timed out", server)
+}
+
+/// STUN Binding, 获取 server reflexive 地址
+pub async fn binding(
+    socket: &Sockets,
+    server: SocketAddr,
+    skipped: &mut Vec<(SocketAddr, Vec<u8>)>,
+) -> Result<SocketAddr> {
+    let response = transaction(
+        socket,
+        server,
+        &StunMessage::new(BINDING, CLASS_REQUEST),
+        None,
+        skipped,
+    )
+    .await?;
+    if response.class() != CLASS_SUCCESS {
+        bail!("STUN binding failed: {:?}", response.error_code());
+    }
+
+    response
+        .address(ATTR_XOR_MAPPED_ADDRESS)
+        .ok_or_else(|| anyhow!("STUN binding response without XOR-MAPPED-ADDRESS"))
+}
+
+/// ICE 连通性检查 (STUN Binding 请求) 发往的本地 ice-ufrag
+/// * USERNAME 的格式为 `接收方 ufrag:发送方 ufrag` (RFC 8445 7.2.2)
+pub fn binding_request_ufrag(buf: &[u8]) -> Option<String> {
+    let request = StunMessage::decode(buf)?;
+    if request.method() != BINDING || request.class() != CLASS_REQUEST {
+        return None;
+    }
+
+    let username = request.string(ATTR_USERNAME)?;
+    let (local, _) = username.split_once(':')?;
+    Some(local.to_string())
+}
+
+/// TURN 分配 (RFC 8656)
+/// * 通过 Send indication 把数据发往对端, 对端的数据通过 Data indication 收到
+/// * 需要定期刷新分配和权限, 由 `poll_timeout` 生成刷新请求
+pub struct TurnAllocation {
+    pub server: SocketAddr,
+    pub relayed: SocketAddr,
+    pub mapped: Option<SocketAddr>,
+    username: String,
+    realm: String,
+    nonce: String,
+    key: Vec<u8>,
+    refresh_at: Instant,
+    lifetime: Duration,
+    permissions: HashMap<IpAddr, Instant>, // 对端 IP -> 下次需要刷新权限的时间
+}
+
+impl TurnAllocation {
+    pub async fn allocate(
+        socket: &Sockets,
+        server: SocketAddr,
+        username: &str,
+        password: &str,
+        skipped: &mut Vec<(SocketAddr, Vec<u8>)>,
+    ) -> Result<Self> {
+        let transport = [TRANSPORT_UDP, 0, 0, 0];
+
+        // 第一次请求不带认证信息, 服务端返回 401 以及 realm / nonce
+        let request =
+            StunMessage::new(ALLOCATE, CLASS_REQUEST).with(ATTR_REQUESTED_TRANSPORT, transport);
+        let response = transaction(socket, server, &request, None, skipped).await?;
+        if response.class() != CLASS_ERROR || response.error_code() != Some(401) {
+            bail!(
+                "TURN allocate unexpected response: {:?}",
+                response.error_code()
+            );
+        }
+
+        let realm = response
+            .string(ATTR_REALM)
+            .ok_or_else(|| anyhow!("TURN 401 without REALM"))?;
+        let mut nonce = response
+            .string(ATTR_NONCE)
+            .ok_or_else(|| anyhow!("TURN 401 without NONCE"))?;
+        // long-term credential: key = MD5(username:realm:password)
+        let key = Md5::digest(format!("{}:{}:{}", username, realm, password)).to_vec();
+
+        // 带认证信息重新请求, nonce 过期 (438) 时用新的 nonce 再试一次
+        let mut response;
+        let mut retries = 0;
+        loop {
+            let request = StunMessage::new(ALLOCATE, CLASS_REQUEST)
+                .with(ATTR_REQUESTED_TRANSPORT, transport)
+                .with(ATTR_USERNAME, username)
+                .with(ATTR_REALM, realm.as_str())
+                .with(ATTR_NONCE, nonce.as_str());
+            response = transaction(socket, server, &request, Some(&key), skipped).await?;
+            if response.class() == CLASS_ERROR && response.error_code() == Some(438) && retries == 0
+            {
+                nonce = response.string(ATTR_NONCE).unwrap_or(nonce);
+                retries += 1;
+                continue;
+            }
+            break;
+        }
+        if response.class() != CLASS_SUCCESS {
+            bail!("TURN allocate failed: {:?}", response.error_code());
+        }
+
+        let relayed = response
+            .address(ATTR_XOR_RELAYED_ADDRESS)
+            .ok_or_else(|| anyhow!("TURN allocate response without XOR-RELAYED-ADDRESS"))?;
+        let lifetime = lifetime(&response).unwrap_or(Duration::from_secs(600));
+        info!(
+            "TURN allocated {} on {}, lifetime {:?}",
+            relayed, server, lifetime
+        );
+
+        Ok(Self {
+            server,
+            relayed,
+            mapped: response.address(ATTR_XOR_MAPPED_ADDRESS),
+            username: username.to_string(),
+            realm,
+            nonce,
+            key,
+            refresh_at: Instant::now() + lifetime / 2,
+            lifetime,
+            permissions: HashMap::new(),
+        })
+    }
+
+    fn authenticated(&self, method: u16) -> StunMessage {
+        StunMessage::new(method, CLASS_REQUEST)
+            .with(ATTR_USERNAME, self.username.as_str())
+            .with(ATTR_REALM, self.realm.as_str())
+            .with(ATTR_NONCE, self.nonce.as_str())
+    }
+
+    /// 把发往对端的数据封装成 Send indication
+    /// * 对端还没有权限时, 先返回一个 CreatePermission 请求
+    pub fn send_indication(&mut self, peer: SocketAddr, data: &[u8]) -> (Option<Vec<u8>>, Vec<u8>) {
+        let permission = if self.permissions.contains_key(&peer.ip()) {
+            None
+        } else {
+            Some(self.create_permission(peer.ip()))
+        };
+
+        let indication = StunMessage::new(SEND, CLASS_INDICATION)
+            .with_address(ATTR_XOR_PEER_ADDRESS, peer)
+            .with(ATTR_DATA, data);
+
+        (permission, indication.encode(None))
+    }
+
+    fn create_permission(&mut self, peer: IpAddr) -> Vec<u8> {
+        debug!("TURN create permission for {}", peer);
+        self.permissions
+            .insert(peer, Instant::now() + PERMISSION_REFRESH);
+        self.authenticated(CREATE_PERMISSION)
+            .with_address(ATTR_XOR_PEER_ADDRESS, SocketAddr::new(peer, 0))
+            .encode(Some(&self.key))
+    }
+
+    /// 处理从 TURN 服务器收到的消息
+    /// * Data indication 返回对端地址和数据
+    /// * 其他的响应只需要更新状态
+    pub fn handle(&mut self, buf: &[u8]) -> Option<(SocketAddr, Vec<u8>)> {
+        let message = StunMessage::decode(buf)?;
+        match (message.method(), message.class()) {
+            (DATA, CLASS_INDICATION) => {
+                let peer = message.address(ATTR_XOR_PEER_ADDRESS)?;
+                let data = message.attr(ATTR_DATA)?.to_vec();
+                Some((peer, data))
+            }
+            (REFRESH, CLASS_SUCCESS) => {
+                if let Some(lifetime) = lifetime(&message) {
+                    self.lifetime = lifetime;
+                    self.refresh_at = Instant::now() + lifetime / 2;
+                }
+                None
+            }
+            (method, CLASS_ERROR) => {
+                warn!("TURN {:#06x} error: {:?}", method, message.error_code());
+                // nonce 过期, 换新的 nonce 后马上重新刷新分配和权限
+                if message.error_code() == Some(438) {
+                    if let Some(nonce) = message.string(ATTR_NONCE) {
+                        self.nonce = nonce;
+                    }
+                    self.refresh_at = Instant::now();
+                    for refresh_at in self.permissions.values_mut() {
+                        *refresh_at = Instant::now();
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// 返回到期需要发送的 Refresh / CreatePermission 请求
+    pub fn poll_timeout(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut requests = vec![];
+        if now >= self.refresh_at {
+            debug!("TURN refresh allocation {}", self.relayed);
+            // 请求失败时, 在剩余的有效期内再重试
+            self.refresh_at = now + self.lifetime / 4;
+            let lifetime = (self.lifetime.as_secs() as u32).to_be_bytes();
+            requests.push(
+                self.authenticated(REFRESH)
+                    .with(ATTR_LIFETIME, lifetime)
+                    .encode(Some(&self.key)),
+            );
+        }
+
+        let expired: Vec<IpAddr> = self
+            .permissions
+            .iter()
+            .filter(|(_, refresh_at)| now >= **refresh_at)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in expired {
+            requests.push(self.create_permission(peer));
+        }
+
+        requests
+    }
+
+    /// 释放分配的 Refresh 请求 (LIFETIME 为 0), 结束会话时发送, 不用等到分配过期
+    pub fn release(&self) -> Vec<u8> {
+        debug!("TURN release allocation {}", self.relayed);
+        self.authenticated(REFRESH)
+            .with(ATTR_LIFETIME, 0u32.to_be_bytes())
+            .encode(Some(&self.key))
+    }
+}
+
+fn lifetime(message: &StunMessage) -> Option<Duration> {
+    message
+        .attr(ATTR_LIFETIME)
+        .and_then(|v| v.try_into().ok())
+        .map(|v: [u8; 4]| Duration::from_secs(u32::from_be_bytes(v) as u64))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+    use whep_player::SocketMode;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// 校验 MESSAGE-INTEGRITY, 头部的长度按截止到 MESSAGE-INTEGRITY 计算 (后面可能还有 FINGERPRINT)
+    fn verify_integrity(buf: &[u8], key: &[u8]) -> bool {
+        let mut offset = 20;
+        while offset + 4 <= buf.len() {
+            let typ = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+            let len = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+            if typ == ATTR_MESSAGE_INTEGRITY {
+                let Some(digest) = buf.get(offset + 4..offset + 24) else {
+                    return false;
+                };
+                let mut message = buf[..offset].to_vec();
+                message[2..4].copy_from_slice(&((offset + 24 - 20) as u16).to_be_bytes());
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).unwrap();
+                mac.update(&message);
+                return mac.verify_slice(digest).is_ok();
+            }
+            offset += 4 + len + (4 - len % 4) % 4;
+        }
+        false
+    }
+
+    // RFC 5769 2.1: 带 USERNAME / MESSAGE-INTEGRITY / FINGERPRINT 的 Binding 请求
+    const SAMPLE_REQUEST: &str = "
+        000100582112a442b7e7a701bc34d686fa87dfae
+        802200105354554e207465737420636c69656e74
+        002400046e0001ff
+        80290008932ff9b151263b36
+        000600096576746a3a68367659202020
+        000800149aeaa70cbfd8cb56781ef2b5b2d3f249c1b571a2
+        80280004e57a3bcf";
+
+    // RFC 5769 2.2: IPv4 地址的 Binding 响应
+    const SAMPLE_IPV4_RESPONSE: &str = "
+        0101003c2112a442b7e7a701bc34d686fa87dfae
+        8022000b7465737420766563746f7220
+        002000080001a147e112a643
+        000800142b91f599fd9e90c38c7489f92af9ba53f06be7d7
+        80280004c07d4c96";
+
+    // RFC 5769 2.3: IPv6 地址的 Binding 响应
+    const SAMPLE_IPV6_RESPONSE: &str = "
+        010100482112a442b7e7a701bc34d686fa87dfae
+        8022000b7465737420766563746f7220
+        002000140002a1470113a9faa5d3f179bc25f4b5bed2b9d9
+        00080014a382954e4be67bf11784c97c8292c275bfe3ed41
+        80280004c8fb0b4c";
+
+    // RFC 5769 2.4: long-term credential 的请求
+    const SAMPLE_LONG_TERM_REQUEST: &str = "
+        000100602112a44278ad3433c6ad72c029da412e
+        00060012e3839ee38388e383aae38383e382afe382b90000
+        0015001c662f2f3439396b39353464364f4c33346f4c39465354767936347341
+        0014000b6578616d706c652e6f726700
+        00080014f67024656dd64a3e02b8e0712e85c9a28ca89666";
+
+    const SHORT_TERM_PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    #[test]
+    fn decode_sample_request() {
+        let buf = hex(SAMPLE_REQUEST);
+        let request = StunMessage::decode(&buf).unwrap();
+        assert_eq!(request.method(), BINDING);
+        assert_eq!(request.class(), CLASS_REQUEST);
+        assert_eq!(request.string(0x8022).unwrap(), "STUN test client");
+        // USERNAME 用空格填充到 4 字节对齐, 长度只有 9
+        assert_eq!(request.string(ATTR_USERNAME).unwrap(), "evtj:h6vY");
+        assert_eq!(binding_request_ufrag(&buf).unwrap(), "evtj");
+        assert!(verify_integrity(&buf, SHORT_TERM_PASSWORD));
+        assert!(!verify_integrity(&buf, b"wrong password"));
+    }
+
+    #[test]
+    fn decode_sample_responses() {
+        let buf = hex(SAMPLE_IPV4_RESPONSE);
+        let response = StunMessage::decode(&buf).unwrap();
+        assert_eq!(response.method(), BINDING);
+        assert_eq!(response.class(), CLASS_SUCCESS);
+        assert_eq!(
+            response.address(ATTR_XOR_MAPPED_ADDRESS).unwrap(),
+            "192.0.2.1:32853".parse().unwrap()
+        );
+        assert!(verify_integrity(&buf, SHORT_TERM_PASSWORD));
+        assert_eq!(binding_request_ufrag(&buf), None);
+
+        let buf = hex(SAMPLE_IPV6_RESPONSE);
+        let response = StunMessage::decode(&buf).unwrap();
+        assert_eq!(
+            response.address(ATTR_XOR_MAPPED_ADDRESS).unwrap(),
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                .parse()
+                .unwrap()
+        );
+        assert!(verify_integrity(&buf, SHORT_TERM_PASSWORD));
+    }
+
+    #[test]
+    fn encode_sample_long_term_request() {
+        let buf = hex(SAMPLE_LONG_TERM_REQUEST);
+        let request = StunMessage::decode(&buf).unwrap();
+        let username = request.string(ATTR_USERNAME).unwrap();
+        let realm = request.string(ATTR_REALM).unwrap();
+        let nonce = request.string(ATTR_NONCE).unwrap();
+        assert_eq!(username, "マトリックス");
+        assert_eq!(realm, "example.org");
+        assert_eq!(nonce, "f//499k954d6OL34oL9FSTvy64sA");
+
+        // 密码 SASLprep 之后为 TheMatrIX
+        let key = Md5::digest(format!("{}:{}:TheMatrIX", username, realm)).to_vec();
+        assert!(verify_integrity(&buf, &key));
+
+        let encoded = StunMessage {
+            typ: BINDING | CLASS_REQUEST,
+            txid: request.txid,
+            attrs: vec![],
+        }
+        .with(ATTR_USERNAME, username.as_str())
+        .with(ATTR_NONCE, nonce.as_str())
+        .with(ATTR_REALM, realm.as_str())
+        .encode(Some(&key));
+        assert_eq!(encoded, buf);
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        let peer4: SocketAddr = "198.51.100.7:3478".parse().unwrap();
+        let peer6: SocketAddr = "[2001:db8::1]:50000".parse().unwrap();
+        let message = StunMessage::new(SEND, CLASS_INDICATION)
+            .with_address(ATTR_XOR_PEER_ADDRESS, peer4)
+            .with_address(ATTR_XOR_RELAYED_ADDRESS, peer6)
+            .with(ATTR_DATA, &b"hello"[..]);
+        let buf = message.encode(Some(b"key"));
+        assert_eq!(buf.len() % 4, 0);
+        assert!(verify_integrity(&buf, b"key"));
+
+        let decoded = StunMessage::decode(&buf).unwrap();
+        assert_eq!(decoded.txid, message.txid);
+        assert_eq!(decoded.method(), SEND);
+        assert_eq!(decoded.class(), CLASS_INDICATION);
+        assert_eq!(decoded.address(ATTR_XOR_PEER_ADDRESS), Some(peer4));
+        assert_eq!(decoded.address(ATTR_XOR_RELAYED_ADDRESS), Some(peer6));
+        assert_eq!(decoded.attr(ATTR_DATA), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn decode_rejects_non_stun() {
+        // RTP 包的第一个字节是 0x80
+        assert!(StunMessage::decode(&[0x80; 40]).is_none());
+        // 长度超过数据
+        let mut buf = hex(SAMPLE_IPV4_RESPONSE);
+        buf.truncate(40);
+        assert!(StunMessage::decode(&buf).is_none());
+    }
+
+    #[test]
+    fn parse_ice_server_urls() {
+        let server = IceServer::parse(
+            "turn:turn.example.com?transport=udp",
+            Some("user".to_string()),
+            Some("pass".to_string()),
+        )
+        .unwrap();
+        assert_eq!(server.kind, IceServerKind::Turn);
+        assert_eq!(server.host, "turn.example.com");
+        assert_eq!(server.port, 3478);
+
+        let server = IceServer::parse("stun:[::1]:19302", None, None).unwrap();
+        assert_eq!(server.kind, IceServerKind::Stun);
+        assert_eq!(server.host, "::1");
+        assert_eq!(server.port, 19302);
+
+        assert!(IceServer::parse("turns:turn.example.com", None, None).is_err());
+        assert!(IceServer::parse("turn:turn.example.com?transport=tcp", None, None).is_err());
+        assert!(IceServer::parse("stun::3478", None, None).is_err());
+    }
+
+    #[test]
+    fn link_header_round_trip() {
+        let turn = IceServer::parse(
+            "turn:turn.example.com:3478?transport=udp",
+            Some("user".to_string()),
+            Some("p\"ss".to_string()),
+        )
+        .unwrap();
+        let stun = IceServer::parse("stun:stun.example.com", None, None).unwrap();
+        let header = format!(
+            "{}, {}, <https://example.com/layer>; rel=\"urn:ietf:params:whep:ext:core:layer\"",
+            turn.to_link(),
+            stun.to_link()
+        );
+
+        let servers = parse_link_headers([header.as_str()].into_iter());
+        assert_eq!(servers, vec![turn.clone(), stun]);
+        assert_eq!(servers[0].credential.as_deref(), Some("p\"ss"));
+    }
+
+    pub(crate) const TURN_USERNAME: &str = "user";
+    pub(crate) const TURN_PASSWORD: &str = "pass";
+    const TURN_REALM: &str = "example.org";
+
+    /// 回环地址上的 TURN 服务器, 只实现客户端用到的部分
+    /// * 不带认证信息的 Allocate 返回 401, 第一次带认证信息的请求返回 438 (nonce 过期)
+    /// * 有权限的对端发到中继地址的数据通过 Data indication 转发给客户端
+    pub(crate) struct MockTurnServer {
+        pub addr: SocketAddr,
+        pub relayed: SocketAddr,
+    }
+
+    impl MockTurnServer {
+        pub async fn start() -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let server = Self {
+                addr: socket.local_addr().unwrap(),
+                relayed: relay.local_addr().unwrap(),
+            };
+            tokio::spawn(Self::run(socket, relay));
+            server
+        }
+
+        async fn run(socket: UdpSocket, relay: UdpSocket) {
+            let key = Md5::digest(format!(
+                "{}:{}:{}",
+                TURN_USERNAME, TURN_REALM, TURN_PASSWORD
+            ))
+            .to_vec();
+            let relayed = relay.local_addr().unwrap();
+            let mut nonce = "nonce-1".to_string();
+            let mut client = None;
+            let mut permissions = vec![];
+            let mut buf = [0u8; 1500];
+            let mut relay_buf = [0u8; 1500];
+            loop {
+                tokio::select! {
+                    res = socket.recv_from(&mut buf) => {
+                        let (n, source) = res.unwrap();
+                        let Some(request) = StunMessage::decode(&buf[..n]) else {
+                            continue;
+                        };
+                        if (request.method(), request.class()) == (SEND, CLASS_INDICATION) {
+                            if let (Some(peer), Some(data)) =
+                                (request.address(ATTR_XOR_PEER_ADDRESS), request.attr(ATTR_DATA))
+                                && permissions.contains(&peer.ip())
+                            {
+                                relay.send_to(data, peer).await.unwrap();
+                            }
+                            continue;
+                        }
+                        if request.class() != CLASS_REQUEST {
+                            continue;
+                        }
+
+                        let error = |code: u16| {
+                            StunMessage {
+                                typ: request.method() | CLASS_ERROR,
+                                txid: request.txid,
+                                attrs: vec![],
+                            }
+                            .with(ATTR_ERROR_CODE, [0, 0, (code / 100) as u8, (code % 100) as u8])
+                        };
+                        let response = if request.attr(ATTR_USERNAME).is_none()
+                            || !verify_integrity(&buf[..n], &key)
+                        {
+                            error(401)
+                                .with(ATTR_REALM, TURN_REALM)
+                                .with(ATTR_NONCE, nonce.as_str())
+                                .encode(None)
+                        } else if request.string(ATTR_NONCE).as_deref() != Some("nonce-2") {
+                            nonce = "nonce-2".to_string();
+                            error(438).with(ATTR_NONCE, nonce.as_str()).encode(None)
+                        } else {
+                            let success = StunMessage {
+                                typ: request.method() | CLASS_SUCCESS,
+                                txid: request.txid,
+                                attrs: vec![],
+                            };
+                            let lifetime = 600u32.to_be_bytes();
+                            let success = match request.method() {
+                                ALLOCATE => {
+                                    client = Some(source);
+                                    success
+                                        .with_address(ATTR_XOR_RELAYED_ADDRESS, relayed)
+                                        .with_address(ATTR_XOR_MAPPED_ADDRESS, source)
+                                        .with(ATTR_LIFETIME, lifetime)
+                                }
+                                CREATE_PERMISSION => {
+                                    if let Some(peer) = request.address(ATTR_XOR_PEER_ADDRESS) {
+                                        permissions.push(peer.ip());
+                                    }
+                                    success
+                                }
+                                REFRESH => success.with(ATTR_LIFETIME, lifetime),
+                                _ => continue,
+                            };
+                            success.encode(Some(&key))
+                        };
+                        socket.send_to(&response, source).await.unwrap();
+                    }
+                    res = relay.recv_from(&mut relay_buf) => {
+                        let (n, peer) = res.unwrap();
+                        let Some(client) = client.filter(|_| permissions.contains(&peer.ip())) else {
+                            continue;
+                        };
+                        let indication = StunMessage::new(DATA, CLASS_INDICATION)
+                            .with_address(ATTR_XOR_PEER_ADDRESS, peer)
+                            .with(ATTR_DATA, &relay_buf[..n]);
+                        socket.send_to(&indication.encode(None), client).await.unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn turn_allocation_relays_data() {
+        let server = MockTurnServer::start().await;
+        let sockets = Sockets::bind(SocketMode::PerFamily).unwrap();
+        let port = sockets.port(server.addr.ip()).unwrap();
+
+        // 分配之前对端已经发来的数据 (比如连通性检查) 不会被丢弃
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        peer.send_to(b"check", ("127.0.0.1", port)).await.unwrap();
+
+        let mut skipped = vec![];
+        let mut turn = TurnAllocation::allocate(
+            &sockets,
+            server.addr,
+            TURN_USERNAME,
+            TURN_PASSWORD,
+            &mut skipped,
+        )
+        .await
+        .unwrap();
+        assert_eq!(skipped, vec![(peer_addr, b"check".to_vec())]);
+        assert_eq!(turn.relayed, server.relayed);
+        assert_eq!(
+            turn.mapped.map(|addr| addr.port()),
+            sockets.port(server.addr.ip())
+        );
+
+        // 发往对端: 第一次发送时先创建权限
+        let (permission, indication) = turn.send_indication(peer_addr, b"hello");
+        sockets
+            .send_to(&permission.unwrap(), server.addr)
+            .await
+            .unwrap();
+        sockets.send_to(&indication, server.addr).await.unwrap();
+
+        let mut buf = [0u8; 1500];
+        let (n, source) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hello");
+        assert_eq!(source, server.relayed);
+
+        let (permission, _) = turn.send_indication(peer_addr, b"again");
+        assert!(permission.is_none());
+
+        // 对端发到中继地址的数据通过 Data indication 收到, CreatePermission 的响应被忽略
+        peer.send_to(b"world", server.relayed).await.unwrap();
+        let received = loop {
+            let (n, source) = sockets.recv_from(&mut buf).await.unwrap();
+            assert_eq!(source, server.addr);
+            if let Some(received) = turn.handle(&buf[..n]) {
+                break received;
+            }
+        };
+        assert_eq!(received, (peer_addr, b"world".to_vec()));
+
+        // 到期时生成刷新分配和权限的请求, 服务端都能验证通过
+        let requests = turn.poll_timeout(Instant::now() + Duration::from_secs(600));
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            sockets.send_to(&request, server.addr).await.unwrap();
+            let (n, _) = sockets.recv_from(&mut buf).await.unwrap();
+            let response = StunMessage::decode(&buf[..n]).unwrap();
+            assert_eq!(response.class(), CLASS_SUCCESS);
+            turn.handle(&buf[..n]);
+        }
+        assert!(turn.poll_timeout(Instant::now()).is_empty());
+
+        // 释放分配的请求 LIFETIME 为 0, 服务端能验证通过
+        let release = turn.release();
+        let request = StunMessage::decode(&release).unwrap();
+        assert_eq!(request.method(), REFRESH);
+        assert_eq!(lifetime(&request), Some(Duration::ZERO));
+        sockets.send_to(&release, server.addr).await.unwrap();
+        let (n, _) = sockets.recv_from(&mut buf).await.unwrap();
+        assert_eq!(
+            StunMessage::decode(&buf[..n]).unwrap().class(),
+            CLASS_SUCCESS
+        );
+    }
+}
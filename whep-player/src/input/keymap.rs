@@ -0,0 +1,100 @@
+/// USB HID usage id (键盘页) 转换为 Linux evdev 的键码 (linux/input-event-codes.h)
+/// * X server 使用 evdev 驱动时 X 的键码为 evdev 键码加 8
+pub fn hid_to_evdev(usage: u32) -> Option<u16> {
+    let code = match usage {
+        // a - z
+        4 => 30,
+        5 => 48,
+        6 => 46,
+        7 => 32,
+        8 => 18,
+        9 => 33,
+        10 => 34,
+        11 => 35,
+        12 => 23,
+        13 => 36,
+        14 => 37,
+        15 => 38,
+        16 => 50,
+        17 => 49,
+        18 => 24,
+        19 => 25,
+        20 => 16,
+        21 => 19,
+        22 => 31,
+        23 => 20,
+        24 => 22,
+        25 => 47,
+        26 => 17,
+        27 => 45,
+        28 => 21,
+        29 => 44,
+        // 1 - 9, 0
+        30..=39 => usage as u16 - 28,
+        40 => 28, // Enter
+        41 => 1,  // Escape
+        42 => 14, // Backspace
+        43 => 15, // Tab
+        44 => 57, // Space
+        45 => 12, // -
+        46 => 13, // =
+        47 => 26, // [
+        48 => 27, // ]
+        49 => 43, // \
+        50 => 43, // 非 US 键盘的 #, 与 \ 在同一个位置
+        51 => 39, // ;
+        52 => 40, // '
+        53 => 41, // `
+        54 => 51, // ,
+        55 => 52, // .
+        56 => 53, // /
+        57 => 58, // CapsLock
+        // F1 - F10
+        58..=67 => usage as u16 + 1,
+        68 => 87,  // F11
+        69 => 88,  // F12
+        70 => 99,  // PrintScreen
+        71 => 70,  // ScrollLock
+        72 => 119, // Pause
+        73 => 110, // Insert
+        74 => 102, // Home
+        75 => 104, // PageUp
+        76 => 111, // Delete
+        77 => 107, // End
+        78 => 109, // PageDown
+        79 => 106, // Right
+        80 => 105, // Left
+        81 => 108, // Down
+        82 => 103, // Up
+        83 => 69,  // NumLock
+        84 => 98,  // 小键盘 /
+        85 => 55,  // 小键盘 *
+        86 => 74,  // 小键盘 -
+        87 => 78,  // 小键盘 +
+        88 => 96,  // 小键盘 Enter
+        89 => 79,  // 小键盘 1
+        90 => 80,
+        91 => 81,
+        92 => 75,
+        93 => 76,
+        94 => 77,
+        95 => 71,
+        96 => 72,
+        97 => 73,   // 小键盘 9
+        98 => 82,   // 小键盘 0
+        99 => 83,   // 小键盘 .
+        100 => 86,  // 非 US 键盘的 \
+        101 => 127, // Menu
+        224 => 29,  // 左 Ctrl
+        225 => 42,  // 左 Shift
+        226 => 56,  // 左 Alt
+        227 => 125, // 左 Win / Command
+        228 => 97,  // 右 Ctrl
+        229 => 54,  // 右 Shift
+        230 => 100, // 右 Alt
+        231 => 126, // 右 Win / Command
+        _ => return None,
+    };
+
+    Some(code)
+}
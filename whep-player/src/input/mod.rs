@@ -0,0 +1,345 @@
+use crate::client::ChannelMessage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use str0m::channel::ChannelData;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tracing::{debug, error, info};
+use whep_player::InputBackend;
+
+mod keymap;
+#[cfg(target_os = "linux")]
+mod xtest;
+
+/// 没有指定 label 时转发输入事件使用的数据通道
+pub const INPUT_CHANNEL_LABEL: &str = "input";
+
+/// 播放端通过数据通道发送给推流端的输入事件, 每个事件是一条 JSON 文本消息
+/// * `{"type":"mouse_move","x":640,"y":360,"width":1280,"height":720}`
+/// * 坐标是播放端画面中的像素坐标, width / height 为画面的尺寸, 推流端按照采集的分辨率缩放
+/// * 按键使用 USB HID usage id (与 SDL scancode 相同), 与平台和键盘布局无关
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InputEvent {
+    MouseMove {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    MouseButton {
+        button: MouseButton,
+        pressed: bool,
+    },
+    /// dy 为正时向上滚动 (远离用户), dx 为正时向右滚动
+    MouseWheel {
+        dx: i32,
+        dy: i32,
+    },
+    Key {
+        scancode: u32,
+        pressed: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    X1,
+    X2,
+}
+
+/// 注入到系统中的输入动作
+/// * 坐标已经按照推流端的屏幕尺寸缩放, 按键已经转换为 Linux evdev 键码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputAction {
+    MouseMove { x: u32, y: u32 },
+    MouseButton { button: MouseButton, pressed: bool },
+    MouseWheel { dx: i32, dy: i32 },
+    Key { code: u16, pressed: bool },
+}
+
+impl InputEvent {
+    pub fn to_message(&self) -> ChannelMessage {
+        ChannelMessage {
+            binary: false,
+            data: serde_json::to_vec(self).expect("serialize input event"),
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(serde_json::from_slice(data)?)
+    }
+
+    /// 按照推流端屏幕的尺寸转换为注入的动作
+    /// * 画面或者屏幕的尺寸为 0, 以及没有对应键码的按键返回 None
+    pub fn to_action(&self, screen: (u32, u32)) -> Option<InputAction> {
+        let action = match *self {
+            InputEvent::MouseMove {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (screen_width, screen_height) = screen;
+                if width == 0 || height == 0 || screen_width == 0 || screen_height == 0 {
+                    return None;
+                }
+                let scale = |value: u32, from: u32, to: u32| {
+                    (value as u64 * to as u64 / from as u64).min(to as u64 - 1) as u32
+                };
+                InputAction::MouseMove {
+                    x: scale(x, width, screen_width),
+                    y: scale(y, height, screen_height),
+                }
+            }
+            InputEvent::MouseButton { button, pressed } => {
+                InputAction::MouseButton { button, pressed }
+            }
+            InputEvent::MouseWheel { dx, dy } => InputAction::MouseWheel { dx, dy },
+            InputEvent::Key { scancode, pressed } => InputAction::Key {
+                code: keymap::hid_to_evdev(scancode)?,
+                pressed,
+            },
+        };
+
+        Some(action)
+    }
+}
+
+/// 在推流端的系统中注入输入动作
+pub trait InputInjector {
+    /// 屏幕的尺寸, 鼠标坐标按照它缩放
+    fn screen_size(&self) -> (u32, u32);
+
+    fn inject(&mut self, action: InputAction) -> Result<()>;
+}
+
+/// 只记录收到的输入动作, 不注入到系统中
+/// * 用于测试和排查问题, 没有可用的注入方式时也可以确认事件是否到达
+/// * 没有真正的屏幕, 坐标按照推流画面的大小 (`--video-size`) 缩放
+pub struct RecordingInjector {
+    screen: (u32, u32),
+    actions: Vec<InputAction>,
+}
+
+impl RecordingInjector {
+    pub fn new(screen: (u32, u32)) -> Self {
+        Self {
+            screen,
+            actions: vec![],
+        }
+    }
+}
+
+impl InputInjector for RecordingInjector {
+    fn screen_size(&self) -> (u32, u32) {
+        self.screen
+    }
+
+    fn inject(&mut self, action: InputAction) -> Result<()> {
+        info!("input action #{}: {:?}", self.actions.len(), action);
+        self.actions.push(action);
+        Ok(())
+    }
+}
+
+pub fn create_injector(
+    backend: InputBackend,
+    video_size: (u32, u32),
+) -> Result<Box<dyn InputInjector>> {
+    match backend {
+        #[cfg(target_os = "linux")]
+        InputBackend::Xtest => Ok(Box::new(xtest::XTestInjector::new()?)),
+        #[cfg(not(target_os = "linux"))]
+        InputBackend::Xtest => anyhow::bail!("XTest input injection is only available on Linux"),
+        InputBackend::Record => Ok(Box::new(RecordingInjector::new(video_size))),
+    }
+}
+
+/// 处理数据通道上收到的一条消息, 把其中的输入事件注入到系统中
+/// * 不是输入事件的消息和不能转换的事件只记录日志
+pub fn handle_message(injector: &mut dyn InputInjector, data: &[u8]) {
+    let event = match InputEvent::from_bytes(data) {
+        Ok(event) => event,
+        Err(err) => {
+            debug!("not an input event: {:?}", err);
+            return;
+        }
+    };
+    let Some(action) = event.to_action(injector.screen_size()) else {
+        debug!("ignore input event: {:?}", event);
+        return;
+    };
+    if let Err(err) = injector.inject(action) {
+        error!("inject input error: {:?}", err);
+    }
+}
+
+/// 把数据通道上收到的输入事件注入到系统中, 直到数据通道的消息通道关闭
+/// * 注入可能阻塞 (比如等待 X server), 在单独的线程中运行
+pub fn inject_loop(
+    backend: InputBackend,
+    video_size: (u32, u32),
+    mut rx: UnboundedReceiver<ChannelData>,
+) {
+    let mut injector = match create_injector(backend, video_size) {
+        Ok(injector) => injector,
+        Err(err) => {
+            error!("create input injector error: {:?}", err);
+            return;
+        }
+    };
+
+    while let Some(message) = rx.blocking_recv() {
+        handle_message(injector.as_mut(), &message.data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_event_json_round_trip() {
+        let events = [
+            InputEvent::MouseMove {
+                x: 640,
+                y: 360,
+                width: 1280,
+                height: 720,
+            },
+            InputEvent::MouseButton {
+                button: MouseButton::Left,
+                pressed: true,
+            },
+            InputEvent::MouseButton {
+                button: MouseButton::X2,
+                pressed: false,
+            },
+            InputEvent::MouseWheel { dx: -1, dy: 3 },
+            InputEvent::Key {
+                scancode: 4,
+                pressed: true,
+            },
+        ];
+        for event in &events {
+            let message = event.to_message();
+            assert!(!message.binary);
+            assert_eq!(&InputEvent::from_bytes(&message.data).unwrap(), event);
+        }
+
+        // 与文档中的消息格式一致
+        assert_eq!(
+            String::from_utf8(events[0].to_message().data).unwrap(),
+            r#"{"type":"mouse_move","x":640,"y":360,"width":1280,"height":720}"#
+        );
+        assert_eq!(
+            InputEvent::from_bytes(br#"{"type":"mouse_button","button":"x1","pressed":true}"#)
+                .unwrap(),
+            InputEvent::MouseButton {
+                button: MouseButton::X1,
+                pressed: true,
+            }
+        );
+        assert!(InputEvent::from_bytes(b"hello").is_err());
+        assert!(InputEvent::from_bytes(br#"{"type":"touch","x":1,"y":2}"#).is_err());
+    }
+
+    #[test]
+    fn handle_message_scales_and_maps_keys() {
+        let mut injector = RecordingInjector::new((1920, 1080));
+        let events = [
+            InputEvent::MouseMove {
+                x: 320,
+                y: 180,
+                width: 640,
+                height: 360,
+            },
+            // 右下角的边界限制在屏幕内
+            InputEvent::MouseMove {
+                x: 1280,
+                y: 720,
+                width: 1280,
+                height: 720,
+            },
+            // 画面尺寸为 0 的事件被忽略
+            InputEvent::MouseMove {
+                x: 1,
+                y: 1,
+                width: 0,
+                height: 0,
+            },
+            InputEvent::MouseButton {
+                button: MouseButton::Right,
+                pressed: true,
+            },
+            InputEvent::MouseWheel { dx: 0, dy: -2 },
+            // a, 1, 0, F1, 左 Ctrl
+            InputEvent::Key {
+                scancode: 4,
+                pressed: true,
+            },
+            InputEvent::Key {
+                scancode: 30,
+                pressed: true,
+            },
+            InputEvent::Key {
+                scancode: 39,
+                pressed: true,
+            },
+            InputEvent::Key {
+                scancode: 58,
+                pressed: true,
+            },
+            InputEvent::Key {
+                scancode: 224,
+                pressed: false,
+            },
+            // 没有对应键码的 usage id 被忽略
+            InputEvent::Key {
+                scancode: 0,
+                pressed: true,
+            },
+        ];
+        for event in &events {
+            handle_message(&mut injector, &event.to_message().data);
+        }
+        handle_message(&mut injector, b"not an input event");
+
+        assert_eq!(
+            injector.actions,
+            vec![
+                InputAction::MouseMove { x: 960, y: 540 },
+                InputAction::MouseMove { x: 1919, y: 1079 },
+                InputAction::MouseButton {
+                    button: MouseButton::Right,
+                    pressed: true,
+                },
+                InputAction::MouseWheel { dx: 0, dy: -2 },
+                InputAction::Key {
+                    code: 30,
+                    pressed: true,
+                },
+                InputAction::Key {
+                    code: 2,
+                    pressed: true,
+                },
+                InputAction::Key {
+                    code: 11,
+                    pressed: true,
+                },
+                InputAction::Key {
+                    code: 59,
+                    pressed: true,
+                },
+                InputAction::Key {
+                    code: 29,
+                    pressed: false,
+                },
+            ]
+        );
+    }
+}
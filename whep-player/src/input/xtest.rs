@@ -0,0 +1,163 @@
+use super::{InputAction, InputInjector, MouseButton};
+use anyhow::{Result, anyhow};
+use std::ptr;
+use tracing::info;
+use x11::{xlib, xtest};
+
+/// 通过 XTest 扩展注入输入事件
+/// * 连接 DISPLAY 环境变量指定的 X server, 也可以是 Xvfb
+pub struct XTestInjector {
+    display: *mut xlib::Display,
+    width: u32, // 屏幕的尺寸
+    height: u32,
+}
+
+impl XTestInjector {
+    pub fn new() -> Result<Self> {
+        let display = unsafe { xlib::XOpenDisplay(ptr::null()) };
+        if display.is_null() {
+            return Err(anyhow!("failed to open X display"));
+        }
+
+        let (width, height) = unsafe {
+            let screen = xlib::XDefaultScreen(display);
+            (
+                xlib::XDisplayWidth(display, screen) as u32,
+                xlib::XDisplayHeight(display, screen) as u32,
+            )
+        };
+        info!("XTest input injection on a {}x{} screen", width, height);
+
+        Ok(Self {
+            display,
+            width,
+            height,
+        })
+    }
+
+    fn click(&mut self, button: u32, times: u32) {
+        for _ in 0..times {
+            unsafe {
+                xtest::XTestFakeButtonEvent(self.display, button, xlib::True, 0);
+                xtest::XTestFakeButtonEvent(self.display, button, xlib::False, 0);
+            }
+        }
+    }
+}
+
+impl InputInjector for XTestInjector {
+    fn screen_size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn inject(&mut self, action: InputAction) -> Result<()> {
+        match action {
+            InputAction::MouseMove { x, y } => unsafe {
+                xtest::XTestFakeMotionEvent(self.display, -1, x as i32, y as i32, 0);
+            },
+            InputAction::MouseButton { button, pressed } => {
+                let button = match button {
+                    MouseButton::Left => 1,
+                    MouseButton::Middle => 2,
+                    MouseButton::Right => 3,
+                    MouseButton::X1 => 8,
+                    MouseButton::X2 => 9,
+                };
+                let pressed = if pressed { xlib::True } else { xlib::False };
+                unsafe {
+                    xtest::XTestFakeButtonEvent(self.display, button, pressed, 0);
+                }
+            }
+            // X 中滚轮是按钮 4 / 5 (上 / 下) 和 6 / 7 (左 / 右), 滚动一格为一次点击
+            InputAction::MouseWheel { dx, dy } => {
+                self.click(if dy > 0 { 4 } else { 5 }, dy.unsigned_abs());
+                self.click(if dx > 0 { 7 } else { 6 }, dx.unsigned_abs());
+            }
+            InputAction::Key { code, pressed } => {
+                let pressed = if pressed { xlib::True } else { xlib::False };
+                unsafe {
+                    xtest::XTestFakeKeyEvent(self.display, code as u32 + 8, pressed, 0);
+                }
+            }
+        }
+
+        unsafe {
+            xlib::XFlush(self.display);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for XTestInjector {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{InputEvent, handle_message};
+
+    fn pointer(display: *mut xlib::Display) -> (i32, i32) {
+        let (mut root, mut child) = (0, 0);
+        let (mut x, mut y, mut win_x, mut win_y, mut mask) = (0, 0, 0, 0, 0);
+        unsafe {
+            xlib::XSync(display, xlib::False);
+            xlib::XQueryPointer(
+                display,
+                xlib::XDefaultRootWindow(display),
+                &mut root,
+                &mut child,
+                &mut x,
+                &mut y,
+                &mut win_x,
+                &mut win_y,
+                &mut mask,
+            );
+        }
+        (x, y)
+    }
+
+    fn key_down(display: *mut xlib::Display, keycode: usize) -> bool {
+        let mut keys = [0; 32];
+        unsafe {
+            xlib::XSync(display, xlib::False);
+            xlib::XQueryKeymap(display, keys.as_mut_ptr());
+        }
+        keys[keycode / 8] as u8 & (1 << (keycode % 8)) != 0
+    }
+
+    /// 需要 DISPLAY 指向可以使用 XTest 的 X server, 比如:
+    /// `xvfb-run -s "-screen 0 1280x720x24" cargo test -- --ignored xtest`
+    #[test]
+    #[ignore]
+    fn xtest_injects_pointer_and_keys() {
+        let mut injector = XTestInjector::new().unwrap();
+        let (width, height) = injector.screen_size();
+
+        let event = InputEvent::MouseMove {
+            x: 100,
+            y: 50,
+            width: 200,
+            height: 100,
+        };
+        handle_message(&mut injector, &event.to_message().data);
+        assert_eq!(
+            pointer(injector.display),
+            (width as i32 / 2, height as i32 / 2)
+        );
+
+        // 左 Shift: HID 225 -> evdev 42 -> X 键码 50
+        let key = |pressed| InputEvent::Key {
+            scancode: 225,
+            pressed,
+        };
+        handle_message(&mut injector, &key(true).to_message().data);
+        assert!(key_down(injector.display, 50));
+        handle_message(&mut injector, &key(false).to_message().data);
+        assert!(!key_down(injector.display, 50));
+    }
+}
@@ -1,4 +1,5 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::{net::SocketAddr, path::PathBuf};
 
 #[derive(Parser)]
 #[command(name = "bitwhip")]
@@ -10,6 +11,18 @@ pub struct Cli {
     /// Increase log verbosity, multiple occurrences (-vvv) further increase
     #[clap(short, global = true, action = clap::ArgAction::Count)]
     pub verbose: u8,
+
+    /// Periodically write connection and media statistics to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Format of the statistics file
+    #[arg(long, global = true, value_enum, default_value_t = StatsFormat::Json)]
+    pub stats_format: StatsFormat,
+
+    /// Seconds between two records in the statistics file
+    #[arg(long, global = true, default_value_t = 2)]
+    pub stats_interval: u64,
 }
 
 #[derive(Debug, Subcommand)]
@@ -22,10 +35,104 @@ pub enum Commands {
 
         /// The WHIP bearer token
         token: Option<String>,
+
+        /// Capture audio from an FFmpeg input device, e.g. dshow:audio=Stereo Mix, pulse:default
+        #[arg(long, value_name = "FORMAT:DEVICE")]
+        audio: Option<String>,
+
+        /// Publish a generated sine tone of this frequency instead of capturing --audio
+        #[arg(long, value_name = "HZ", conflicts_with = "audio", num_args = 0..=1, default_missing_value = "440")]
+        test_tone: Option<u32>,
+
+        /// The Opus bitrate in bits per second
+        #[arg(long, default_value_t = 128000)]
+        audio_bitrate: usize,
+
+        /// Publish this many simulcast layers (full, 1/2 and 1/4 resolution as RIDs f, h and q)
+        #[arg(long, value_name = "LAYERS", default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=3))]
+        simulcast: u8,
+
+        /// Open a data channel with this label, lines read from stdin are sent on it and received messages are logged
+        #[arg(long, value_name = "LABEL")]
+        data_channel: Option<String>,
+
+        /// Inject mouse and keyboard events received from viewers over the data channel with this backend
+        #[arg(long, value_enum, value_name = "BACKEND")]
+        remote_input: Option<InputBackend>,
+
+        /// Where to capture video from
+        #[arg(long, value_enum, default_value_t = VideoSource::default())]
+        source: VideoSource,
+
+        /// Stream a media file or FFmpeg input URL (rtsp://, rtmp://, srt://) instead of --source, - reads stdin (e.g. y4m)
+        #[arg(long, value_name = "PATH|URL", conflicts_with = "source")]
+        input: Option<String>,
+
+        /// Frames per second captured or generated by --source
+        #[arg(long, value_name = "FPS", default_value_t = 60, value_parser = clap::value_parser!(i32).range(1..=240))]
+        framerate: i32,
+
+        /// Size of the testsrc and smptebars test patterns
+        #[arg(long, value_name = "WxH", default_value = "1280x720", value_parser = parse_video_size)]
+        video_size: (u32, u32),
+
+        /// H.264 encoder, auto uses the first one that opens
+        #[arg(long, value_enum, default_value_t = VideoEncoder::Auto)]
+        encoder: VideoEncoder,
+
+        /// Seconds between periodic keyframes, viewers joining or losing packets request one sooner
+        #[arg(long, value_name = "SECONDS", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=600))]
+        keyframe_interval: u32,
+
+        #[command(flatten)]
+        bitrate: BitrateArgs,
+
+        #[command(flatten)]
+        ice: IceArgs,
+
+        #[command(flatten)]
+        reconnect: ReconnectArgs,
     },
 
     /// Start a WHIP server that accepts incoming requests
-    PlayWHIP {},
+    PlayWHIP {
+        /// Video codecs to accept, in order of preference
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "h264")]
+        video_codec: Vec<VideoCodec>,
+
+        /// Simulcast layer (RID) to play, switched by packet loss when not set
+        #[arg(long, value_name = "RID")]
+        simulcast_layer: Option<String>,
+
+        /// Forward mouse and keyboard events to the publisher over its data channel
+        #[arg(long)]
+        send_input: bool,
+
+        #[command(flatten)]
+        server: ServerArgs,
+
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        ice: IceArgs,
+    },
+
+    /// Restream WHIP publishers at /whip/{stream} to WHEP viewers at /whep/{stream}, without transcoding
+    Restream {
+        /// Video codecs to accept, in order of preference
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "h264")]
+        video_codec: Vec<VideoCodec>,
+
+        #[command(flatten)]
+        server: ServerArgs,
+
+        #[command(flatten)]
+        auth: AuthArgs,
+
+        #[command(flatten)]
+        ice: IceArgs,
+    },
 
     /// Play from a WHEP destination
     #[command(arg_required_else_help = true)]
@@ -35,7 +142,311 @@ pub enum Commands {
 
         /// The WHEP bearer token
         token: Option<String>,
+
+        /// Video codecs to offer, in order of preference
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "h264")]
+        video_codec: Vec<VideoCodec>,
+
+        /// Simulcast layer (RID) to request from the server, switched by packet loss when not set
+        #[arg(long, value_name = "RID")]
+        simulcast_layer: Option<String>,
+
+        /// Open a data channel with this label, lines read from stdin are sent on it and received messages are logged
+        #[arg(long, value_name = "LABEL")]
+        data_channel: Option<String>,
+
+        /// Forward mouse and keyboard events to the publisher over the data channel
+        #[arg(long)]
+        send_input: bool,
+
+        #[command(flatten)]
+        ice: IceArgs,
+
+        #[command(flatten)]
+        reconnect: ReconnectArgs,
     },
 }
 
+#[derive(Debug, Args)]
+pub struct IceArgs {
+    /// STUN server used to gather server reflexive candidates, e.g. stun:stun.l.google.com:19302
+    #[arg(long, value_name = "URL")]
+    pub stun: Vec<String>,
+
+    /// TURN server used to gather relay candidates, e.g. turn:turn.example.com:3478?transport=udp
+    #[arg(long, value_name = "URL", requires_all = ["turn_username", "turn_credential"])]
+    pub turn: Vec<String>,
+
+    /// The TURN username
+    #[arg(long)]
+    pub turn_username: Option<String>,
+
+    /// The TURN credential
+    #[arg(long)]
+    pub turn_credential: Option<String>,
+
+    /// Also create host candidates for loopback addresses (127.0.0.1, ::1)
+    #[arg(long)]
+    pub include_loopback: bool,
+
+    /// Only create host candidates for matching interfaces, by name (eth0, wl*) or CIDR (192.168.0.0/16)
+    #[arg(long, value_name = "NAME|CIDR")]
+    pub allow_iface: Vec<String>,
+
+    /// Never create host candidates for matching interfaces, by name (docker0, veth*) or CIDR
+    #[arg(long, value_name = "NAME|CIDR")]
+    pub deny_iface: Vec<String>,
+
+    /// Also advertise this public IP for host candidates (NAT 1:1), as PUBLIC or PUBLIC/PRIVATE
+    #[arg(long, value_name = "IP[/PRIVATE]")]
+    pub public_ip: Vec<String>,
+
+    /// Advertise only the --public-ip addresses, not the private addresses they map
+    #[arg(long, requires = "public_ip")]
+    pub public_ip_only: bool,
+
+    /// Use one dual-stack socket, or one socket per address family
+    #[arg(long, value_enum, default_value_t = SocketMode::PerFamily)]
+    pub socket_mode: SocketMode,
+
+    /// Which transports to create ICE candidates for
+    #[arg(long, value_enum, default_value_t = IceTransport::Udp)]
+    pub ice_transport: IceTransport,
+
+    /// Which candidates to use, `relay` only uses TURN relay candidates (requires --turn)
+    #[arg(long, value_enum, default_value_t = IceTransportPolicy::All)]
+    pub ice_transport_policy: IceTransportPolicy,
+}
+
+#[derive(Debug, Args)]
+pub struct BitrateArgs {
+    /// Video bitrate in bits per second before the first bandwidth estimate
+    #[arg(long, value_name = "BPS", default_value_t = 2_500_000)]
+    pub start_bitrate: u64,
+
+    /// Never encode video below this bitrate, however low the bandwidth estimate
+    #[arg(long, value_name = "BPS", default_value_t = 300_000)]
+    pub min_bitrate: u64,
+
+    /// Never encode video above this bitrate, however high the bandwidth estimate
+    #[arg(long, value_name = "BPS", default_value_t = 5_000_000)]
+    pub max_bitrate: u64,
+
+    /// What to reduce when the bandwidth estimate stays below --min-bitrate
+    #[arg(long, value_enum, default_value_t = StepDown::None)]
+    pub step_down: StepDown,
+}
+
+#[derive(Debug, Args)]
+pub struct ServerArgs {
+    /// Address the WHIP server listens on
+    #[arg(long, value_name = "ADDR", default_value = "0.0.0.0:1337")]
+    pub listen: SocketAddr,
+
+    /// Serve HTTPS with this PEM certificate chain
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key of --tls-cert
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Origin allowed to publish from a web page, may be repeated [default: any]
+    #[arg(long, value_name = "ORIGIN")]
+    pub allow_origin: Vec<String>,
+
+    /// Share this UDP port between all sessions instead of binding one port per session
+    #[arg(long, value_name = "PORT")]
+    pub udp_port: Option<u16>,
+}
+
+#[derive(Debug, Args)]
+pub struct AuthArgs {
+    /// Accept this bearer token from publishers, may be repeated
+    #[arg(long = "auth-token", value_name = "TOKEN")]
+    pub auth_tokens: Vec<String>,
+
+    /// Accept JWTs signed with this HS256 secret
+    #[arg(long, value_name = "SECRET", conflicts_with = "jwt_public_key")]
+    pub jwt_secret: Option<String>,
+
+    /// Accept JWTs signed with the RS256 public key in this PEM file
+    #[arg(long, value_name = "PATH")]
+    pub jwt_public_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReconnectArgs {
+    /// Give up after this many consecutive reconnect attempts (0 disables reconnecting) [default: unlimited]
+    #[arg(long, value_name = "N")]
+    pub max_reconnect_attempts: Option<u32>,
+
+    /// Delay before the first reconnect attempt, doubled on every further attempt
+    #[arg(long, value_name = "MS", default_value_t = 500)]
+    pub reconnect_min_delay: u64,
+
+    /// Upper bound of the reconnect delay
+    #[arg(long, value_name = "MS", default_value_t = 30000)]
+    pub reconnect_max_delay: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IceTransport {
+    /// UDP candidates only
+    #[default]
+    Udp,
+    /// ICE-TCP candidates only (passive and active), for networks that block UDP
+    Tcp,
+    /// Both UDP and ICE-TCP candidates, ICE prefers UDP when both work
+    All,
+}
+
+impl IceTransport {
+    pub fn udp(self) -> bool {
+        matches!(self, Self::Udp | Self::All)
+    }
+
+    pub fn tcp(self) -> bool {
+        matches!(self, Self::Tcp | Self::All)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IceTransportPolicy {
+    /// Host, server reflexive and relay candidates
+    #[default]
+    All,
+    /// TURN relay candidates only, the peer never sees a local or public address
+    Relay,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SocketMode {
+    /// One IPv6 socket that also handles IPv4 (IPv4-mapped addresses)
+    DualStack,
+    /// One socket for IPv4 and one for IPv6
+    #[default]
+    PerFamily,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StepDown {
+    /// Only lower the bitrate
+    #[default]
+    None,
+    /// Halve the captured resolution, up to twice
+    Resolution,
+    /// Halve the encoded framerate, up to twice
+    Framerate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// One JSON object per line
+    #[default]
+    Json,
+    /// One summary row per line, with a header
+    Csv,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputBackend {
+    /// XTest extension of the X server in DISPLAY (Linux, also works with Xvfb)
+    Xtest,
+    /// Only log the received events, pointer coordinates are scaled to --video-size
+    Record,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum VideoSource {
+    /// Desktop Duplication API of the primary display (Windows)
+    #[cfg_attr(target_os = "windows", default)]
+    Dxdup,
+    /// The X server in DISPLAY (Linux)
+    #[cfg_attr(not(target_os = "windows"), default)]
+    X11grab,
+    /// Generated testsrc2 pattern with a clock and frame counter, no display needed
+    Testsrc,
+    /// Generated SMPTE color bars with a clock and frame counter, no display needed
+    Smptebars,
+}
+
+/// `1280x720` 形式的画面大小, 宽高必须是偶数 (YUV 4:2:0)
+fn parse_video_size(s: &str) -> Result<(u32, u32), String> {
+    let (width, height) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected WxH, got {}", s))?;
+    let width: u32 = width.parse().map_err(|e| format!("width: {}", e))?;
+    let height: u32 = height.parse().map_err(|e| format!("height: {}", e))?;
+    if width == 0 || height == 0 || !width.is_multiple_of(2) || !height.is_multiple_of(2) {
+        return Err(format!(
+            "width and height must be even and non-zero, got {}",
+            s
+        ));
+    }
+
+    Ok((width, height))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum VideoEncoder {
+    /// Try nvenc, x264 and openh264 in this order
+    #[default]
+    Auto,
+    /// NVIDIA NVENC, encodes captured D3D11 frames without copying them to memory
+    Nvenc,
+    /// libx264 software encoder, tuned for zero latency
+    X264,
+    /// Cisco libopenh264 software encoder
+    Openh264,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VideoCodec {
+    H264,
+    /// HEVC
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
 pub mod util;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_size() {
+        assert_eq!(parse_video_size("1280x720"), Ok((1280, 720)));
+        assert_eq!(parse_video_size("2x2"), Ok((2, 2)));
+        for invalid in [
+            "1280", "1280x", "x720", "1280*720", "-2x2", "1281x720", "1280x719", "0x720", "1280x0",
+        ] {
+            assert!(parse_video_size(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn stream_video_size_arg() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(
+                ["whep-player", "stream", "http://localhost/whip"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+        let Commands::Stream { video_size, .. } = parse(&[]).unwrap().commands else {
+            panic!("not a stream command");
+        };
+        assert_eq!(video_size, (1280, 720));
+        let Commands::Stream { video_size, .. } =
+            parse(&["--video-size", "640x360"]).unwrap().commands
+        else {
+            panic!("not a stream command");
+        };
+        assert_eq!(video_size, (640, 360));
+        assert!(parse(&["--video-size", "641x360"]).is_err());
+    }
+}
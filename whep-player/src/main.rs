@@ -1,21 +1,54 @@
 use crate::player::render_video;
-use anyhow::{Error, Result};
-use axum::{Router, response::Response, routing::post};
+use anyhow::{Error, Result, anyhow, bail};
+use auth::Auth;
+use axum_server::tls_rustls::RustlsConfig;
+use bitrate::BitrateConfig;
 use clap::Parser;
-use encoder::Encoder;
-use ffmpeg_next::{
-    Packet, Rational,
-    ffi::{AVBufferRef, av_buffer_ref},
-    format::Pixel,
+use client::{ChannelMessage, ClientConfig};
+use encoder::{
+    AudioEncoder, Backend, Encoder, EncoderControl, H264_CONSTRAINED_BASELINE, Scaler, h264_level,
+};
+use ffmpeg_next::{Packet, format::Pixel, frame};
+use net::UdpMux;
+use reconnect::ReconnectConfig;
+use server::SessionManager;
+use source::{
+    AudioSource, Source,
+    file::{FileInput, H264Packets},
+};
+use stats::Stats;
+use std::{
+    sync::{Arc, mpsc},
+    time::{Duration, Instant},
+};
+use str0m::channel::ChannelData;
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    sync::{mpsc::UnboundedSender, watch},
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
+use whep_player::{
+    BitrateArgs, Cli, Commands, IceArgs, InputBackend, ReconnectArgs, ServerArgs, VideoCodec,
+    VideoEncoder, VideoSource,
 };
-use source::Source;
-use std::{collections::HashMap, sync::mpsc, time::Instant};
-use whep_player::{Cli, Commands};
 
+mod auth;
+mod bitrate;
 mod client;
+mod decoder;
 mod encoder;
+mod ice_server;
+mod input;
+mod net;
 mod player;
+mod reconnect;
+mod restream;
+mod server;
+mod simulcast;
 mod source;
+mod stats;
+mod tcp;
 mod whip;
 
 // no_mangle: 防止 Rust 编译器对符号名进行名称修饰 (name mangling)
@@ -27,35 +60,82 @@ pub static NvOptimusEnablement: i32 = 1;
 #[allow(non_upper_case_globals)]
 pub static AmdPowerXpressRequestHighPerformance: i32 = 1;
 
-struct EncodedPacket(Packet, Instant);
-
-fn create_encoder(width: u32, height: u32, hw_frames: *mut AVBufferRef) -> Result<Encoder> {
-    let encoder = Encoder::new(
-        "h264_nvenc",
-        Some(HashMap::from([
-            ("preset".into(), "p6".into()),
-            ("tune".into(), "ull".into()),
-        ])),
-        |encoder| {
-            let frame_rate = Rational::new(60, 1);
-            encoder.set_bit_rate(5000 * 1000);
-            encoder.set_width(width);
-            encoder.set_height(height);
-            encoder.set_time_base(frame_rate.invert());
-            encoder.set_frame_rate(Some(frame_rate));
-            encoder.set_gop(120);
-            encoder.set_max_b_frames(0);
-            encoder.set_format(Pixel::D3D11);
-            unsafe {
-                let encoder = &mut *encoder.as_mut_ptr();
-                encoder.hw_frames_ctx = av_buffer_ref(hw_frames);
-            }
+struct EncodedPacket(Packet, Instant, usize); // 数据包, 编码开始的时间, simulcast 层的序号
 
-            Ok(())
-        },
-    )?;
+/// 推流的画面来源和编码器
+struct VideoConfig {
+    source: VideoSource,
+    input: Option<String>, // 媒体文件或者输入 URL, 有时代替 source
+    size: (u32, u32),      // 测试画面的大小
+    frame_rate: i32,       // 采集或者生成画面的帧率
+    encoder: VideoEncoder,
+    keyframe_interval: u32, // 周期性关键帧的间隔 (秒)
+}
 
-    Ok(encoder)
+/// 推流的音频来源
+enum AudioInput {
+    Device(String), // FFmpeg 输入设备, `<format>:<device>`
+    Sine(u32),      // 指定频率 (Hz) 的正弦波
+}
+
+/// 按照 frame 的尺寸和格式创建编码器, 依次尝试 backends 直到有一个可以打开
+fn create_encoder(
+    backends: &[Backend],
+    frame: &frame::Video,
+    frame_rate: i32,
+    bit_rate: u64,
+    keyframe_interval: u32,
+    level: u32,
+) -> Result<Encoder> {
+    for &backend in backends {
+        match Encoder::new(
+            backend,
+            frame,
+            frame_rate,
+            bit_rate,
+            keyframe_interval,
+            level,
+        ) {
+            Ok(encoder) => return Ok(encoder),
+            Err(err) => warn!("{:?} encoder unavailable: {:?}", backend, err),
+        }
+    }
+
+    bail!("No usable H264 encoder among {:?}", backends)
+}
+
+/// 命令行选择的画面来源
+fn create_source(video: &VideoConfig) -> Result<Box<dyn Source>> {
+    match video.source {
+        #[cfg(target_os = "windows")]
+        VideoSource::Dxdup => Ok(Box::new(source::dxdup::DisplayDuplicator::new(
+            video.frame_rate,
+        )?)),
+        #[cfg(target_os = "linux")]
+        VideoSource::X11grab => Ok(Box::new(source::x11grab::X11Grab::new(video.frame_rate)?)),
+        pattern @ (VideoSource::Testsrc | VideoSource::Smptebars) => Ok(Box::new(
+            source::testsrc::TestPattern::new(pattern, video.size, video.frame_rate)?,
+        )),
+        #[allow(unreachable_patterns)]
+        kind => bail!("Video source {:?} is not supported on this platform", kind),
+    }
+}
+
+/// 退出信号
+/// * Ctrl-C 时通知各个会话退出, 以便对会话资源发送 DELETE, 避免服务端残留会话直到 ICE 超时
+fn shutdown_channel() -> (Arc<watch::Sender<bool>>, watch::Receiver<bool>) {
+    let (tx, rx) = watch::channel(false);
+    let tx = Arc::new(tx);
+
+    let ctrl_c_tx = tx.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            info!("Ctrl-C received, shutting down");
+            ctrl_c_tx.send_replace(true);
+        }
+    });
+
+    (tx, rx)
 }
 
 #[tokio::main]
@@ -67,57 +147,573 @@ async fn main() -> Result<(), Error> {
     let args = Cli::parse();
     let _guard = whep_player::util::init_logger(args.verbose);
 
+    let stats = Stats::new();
+    if let Some(path) = args.stats_file {
+        tokio::spawn(stats::write_periodically(
+            path,
+            args.stats_format,
+            Duration::from_secs(args.stats_interval),
+            stats.subscribe(),
+        ));
+    }
+
     match args.commands {
-        Commands::Stream { url, token } => stream(url, token).await?,
-        Commands::PlayWHIP {} => play_whip().await,
-        Commands::PlayWHEP { url, token } => play_whep(url, token).await?,
+        Commands::Stream {
+            url,
+            token,
+            audio,
+            test_tone,
+            audio_bitrate,
+            simulcast,
+            data_channel,
+            remote_input,
+            source,
+            input,
+            framerate,
+            video_size,
+            encoder,
+            keyframe_interval,
+            bitrate,
+            ice,
+            reconnect,
+        } => {
+            let audio = match (audio, test_tone) {
+                (Some(device), _) => Some(AudioInput::Device(device)),
+                (None, Some(frequency)) => Some(AudioInput::Sine(frequency)),
+                (None, None) => None,
+            };
+            let audio = audio.map(|input| (input, audio_bitrate));
+            let video = VideoConfig {
+                source,
+                input,
+                size: video_size,
+                frame_rate: framerate,
+                encoder,
+                keyframe_interval,
+            };
+            let bitrate = bitrate_config(&bitrate)?;
+            // 接收输入事件时收到的消息交给注入线程, 否则与标准输入输出连接
+            let data = match (remote_input, &data_channel) {
+                (Some(backend), _) => Some(remote_input_channel(backend, video_size)),
+                (None, Some(_)) => Some(data_channel_stdio()),
+                (None, None) => None,
+            };
+            let data_channel =
+                data_channel.or(remote_input.map(|_| input::INPUT_CHANNEL_LABEL.to_string()));
+            // 推流的编码器只输出 H264, profile-level-id 在打开编码器后确定
+            let config = ClientConfig {
+                start_bitrate: Some(bitrate.start),
+                simulcast_rids: simulcast::RIDS[..simulcast as usize]
+                    .iter()
+                    .map(|rid| rid.to_string())
+                    .collect(),
+                data_channel,
+                ..client_config(&ice, vec![VideoCodec::H264])?
+            };
+            stream(
+                url,
+                token,
+                audio,
+                config,
+                video,
+                bitrate,
+                data,
+                stats,
+                reconnect_config(&reconnect),
+            )
+            .await?
+        }
+        Commands::PlayWHIP {
+            video_codec,
+            simulcast_layer,
+            send_input,
+            server,
+            auth,
+            ice,
+        } => {
+            let config = ClientConfig {
+                simulcast_layer,
+                udp_mux: udp_mux(&ice, &server)?,
+                ..client_config(&ice, video_codec)?
+            };
+            let auth = Auth::new(&auth)?;
+            play_whip(config, send_input, server, auth, stats).await?
+        }
+        Commands::Restream {
+            video_codec,
+            server,
+            auth,
+            ice,
+        } => {
+            let config = ClientConfig {
+                udp_mux: udp_mux(&ice, &server)?,
+                ..client_config(&ice, video_codec)?
+            };
+            let auth = Auth::new(&auth)?;
+            restream(config, server, auth, stats).await?
+        }
+        Commands::PlayWHEP {
+            url,
+            token,
+            video_codec,
+            simulcast_layer,
+            data_channel,
+            send_input,
+            ice,
+            reconnect,
+        } => {
+            let config = ClientConfig {
+                simulcast_layer,
+                data_channel: data_channel
+                    .or(send_input.then(|| input::INPUT_CHANNEL_LABEL.to_string())),
+                ..client_config(&ice, video_codec)?
+            };
+            play_whep(
+                url,
+                token,
+                config,
+                send_input,
+                stats,
+                reconnect_config(&reconnect),
+            )
+            .await?
+        }
     }
 
     Ok(())
 }
 
-async fn stream(url: String, token: Option<String>) -> Result<()> {
+fn reconnect_config(reconnect: &ReconnectArgs) -> ReconnectConfig {
+    ReconnectConfig {
+        max_attempts: reconnect.max_reconnect_attempts,
+        min_delay: Duration::from_millis(reconnect.reconnect_min_delay),
+        max_delay: Duration::from_millis(reconnect.reconnect_max_delay),
+    }
+}
+
+fn bitrate_config(bitrate: &BitrateArgs) -> Result<BitrateConfig> {
+    anyhow::ensure!(
+        bitrate.min_bitrate <= bitrate.max_bitrate,
+        "--min-bitrate must not be greater than --max-bitrate"
+    );
+
+    Ok(BitrateConfig {
+        min: bitrate.min_bitrate,
+        max: bitrate.max_bitrate,
+        start: bitrate
+            .start_bitrate
+            .clamp(bitrate.min_bitrate, bitrate.max_bitrate),
+        step_down: bitrate.step_down,
+    })
+}
+
+fn client_config(ice: &IceArgs, video_codecs: Vec<VideoCodec>) -> Result<ClientConfig> {
+    let mut ice_servers = vec![];
+    for url in &ice.stun {
+        ice_servers.push(ice_server::IceServer::parse(url, None, None)?);
+    }
+    for url in &ice.turn {
+        ice_servers.push(ice_server::IceServer::parse(
+            url,
+            ice.turn_username.clone(),
+            ice.turn_credential.clone(),
+        )?);
+    }
+
+    Ok(ClientConfig {
+        ice_servers,
+        socket_mode: ice.socket_mode,
+        include_loopback: ice.include_loopback,
+        allow_interfaces: ice
+            .allow_iface
+            .iter()
+            .map(|f| f.parse())
+            .collect::<Result<_>>()?,
+        deny_interfaces: ice
+            .deny_iface
+            .iter()
+            .map(|f| f.parse())
+            .collect::<Result<_>>()?,
+        public_ips: ice
+            .public_ip
+            .iter()
+            .map(|ip| ip.parse())
+            .collect::<Result<_>>()?,
+        public_ip_only: ice.public_ip_only,
+        ice_transport: ice.ice_transport,
+        ice_transport_policy: ice.ice_transport_policy,
+        video_codecs,
+        h264_profile_level_id: None,
+        start_bitrate: None,
+        simulcast_rids: vec![],
+        simulcast_layer: None,
+        data_channel: None,
+        udp_mux: None,
+    })
+}
+
+/// 服务端指定了 --udp-port 时, 所有会话共享这个端口
+fn udp_mux(ice: &IceArgs, server: &ServerArgs) -> Result<Option<UdpMux>> {
+    let Some(port) = server.udp_port else {
+        return Ok(None);
+    };
+    let mux = UdpMux::bind(ice.socket_mode, port)
+        .map_err(|e| anyhow!("bind UDP port {}: {}", port, e))?;
+    Ok(Some(mux))
+}
+
+/// 把数据通道上收到的消息写到日志中
+fn log_channel_messages() -> UnboundedSender<ChannelData> {
+    let (incoming_tx, mut incoming_rx) = tokio::sync::mpsc::unbounded_channel::<ChannelData>();
+    tokio::spawn(async move {
+        while let Some(message) = incoming_rx.recv().await {
+            if message.binary {
+                info!("data channel: {} bytes", message.data.len());
+            } else {
+                info!("data channel: {}", String::from_utf8_lossy(&message.data));
+            }
+        }
+    });
+
+    incoming_tx
+}
+
+/// 数据通道与标准输入输出之间的消息通道
+/// * 标准输入的每一行作为一条文本消息发送, 收到的消息写到日志中
+fn data_channel_stdio() -> whip::DataChannel {
+    let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let message = ChannelMessage {
+                binary: false,
+                data: line.into_bytes(),
+            };
+            if outgoing_tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    whip::DataChannel {
+        incoming: log_channel_messages(),
+        outgoing: outgoing_rx,
+    }
+}
+
+/// 推流端接收输入事件的消息通道, 收到的事件在单独的线程中注入到系统中
+fn remote_input_channel(backend: InputBackend, video_size: (u32, u32)) -> whip::DataChannel {
+    let (incoming_tx, incoming_rx) = tokio::sync::mpsc::unbounded_channel();
+    // 推流端不发送消息, 发送端直接释放
+    let (_, outgoing_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || input::inject_loop(backend, video_size, incoming_rx));
+
+    whip::DataChannel {
+        incoming: incoming_tx,
+        outgoing: outgoing_rx,
+    }
+}
+
+/// 播放端转发输入事件的消息通道
+/// * 返回会话使用的 DataChannel, 以及播放窗口发送输入事件的一端
+fn send_input_channel() -> (whip::DataChannel, UnboundedSender<ChannelMessage>) {
+    let (outgoing_tx, outgoing_rx) = tokio::sync::mpsc::unbounded_channel();
+    let data = whip::DataChannel {
+        incoming: log_channel_messages(),
+        outgoing: outgoing_rx,
+    };
+
+    (data, outgoing_tx)
+}
+
+/// simulcast 第 layer 层的码率
+/// * 总码率按照像素数分配给各层, 每低一层像素数为上一层的 1/4
+fn layer_bit_rate(total: u64, layer: usize, layers: usize) -> u64 {
+    let weight = |layer: usize| 1u64 << (2 * (layers - 1 - layer));
+    let total_weight: u64 = (0..layers).map(weight).sum();
+    total * weight(layer) / total_weight
+}
+
+/// 采集音频并编码为 Opus, 直到 publish 退出 (通道关闭)
+fn capture_audio(input: AudioInput, bit_rate: usize, tx: UnboundedSender<Packet>) -> Result<()> {
+    let mut source: Box<dyn AudioSource> = match input {
+        AudioInput::Device(device) => Box::new(source::audio::DeviceAudio::new(&device)?),
+        AudioInput::Sine(frequency) => Box::new(source::testsrc::SineAudio::new(frequency)?),
+    };
+    let mut encoder = AudioEncoder::new(bit_rate)?;
+    loop {
+        let frame = source.get_frame()?;
+        for packet in encoder.encode(&frame)? {
+            if tx.send(packet).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 直接发送时收到关键帧请求后, 等待输入中的下一个 IDR 的最长时间, 超过后改为转码
+const MAX_KEYFRAME_WAIT: Duration = Duration::from_secs(2);
+
+/// 把输入的 H264 数据包直接交给 publish 发送, 不解码和编码
+/// * 不能调整码率; 收到关键帧请求后等待输入中的下一个 IDR
+/// * 输入的关键帧间隔太长, 等待超过 MAX_KEYFRAME_WAIT 时返回输入, 之后改为转码
+/// * publish 已经退出时返回 None
+fn pass_through(
+    input: FileInput,
+    tx: &UnboundedSender<EncodedPacket>,
+    control_rx: &mpsc::Receiver<EncoderControl>,
+) -> Result<Option<FileInput>> {
+    info!("input is H264 constrained baseline, sending it without transcoding");
+    let mut packets = H264Packets::new(input)?;
+    let start = Instant::now();
+    let mut keyframe_requested: Option<Instant> = None;
+    loop {
+        for control in control_rx.try_iter() {
+            match control {
+                EncoderControl::KeyFrame(_) => {
+                    keyframe_requested.get_or_insert_with(Instant::now);
+                }
+                control => debug!("ignore {:?} when passing the input through", control),
+            }
+        }
+        if keyframe_requested.is_some_and(|at| at.elapsed() >= MAX_KEYFRAME_WAIT) {
+            warn!(
+                "no keyframe in the input {:?} after a keyframe request, transcoding instead",
+                MAX_KEYFRAME_WAIT
+            );
+            return packets.into_input().map(Some);
+        }
+
+        let packet = packets.next()?;
+        if packet.is_key() {
+            keyframe_requested = None;
+        }
+        if tx.send(EncodedPacket(packet, start, 0)).is_err() {
+            // publish 已经退出
+            return Ok(None);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn stream(
+    url: String,
+    token: Option<String>,
+    audio: Option<(AudioInput, usize)>,
+    config: ClientConfig,
+    video: VideoConfig,
+    bitrate: BitrateConfig,
+    data: Option<whip::DataChannel>,
+    stats: Stats,
+    reconnect: ReconnectConfig,
+) -> Result<()> {
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let (control_tx, control_rx) = mpsc::channel();
+    let (profile_tx, profile_rx) = tokio::sync::oneshot::channel();
+    let (_shutdown_tx, shutdown_rx) = shutdown_channel();
+
+    // 音频在单独的线程中采集和编码, 失败时只影响音频
+    let audio_rx = audio.map(|(input, bit_rate)| {
+        let (audio_tx, audio_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || {
+            if let Err(err) = capture_audio(input, bit_rate, audio_tx) {
+                error!("audio capture error: {:?}", err);
+            }
+        });
+        audio_rx
+    });
 
+    // 每个 simulcast 层一个编码器, 第 n 层的宽高为原始画面的 1/2^n
+    let layers = config.simulcast_rids.len().max(1);
     let join_handle = tokio::task::spawn_blocking(move || -> Result<()> {
-        let mut encoder: Option<Encoder> = None;
-        let mut source: Box<dyn Source + Send + Sync> =
-            Box::new(source::dxdup::DisplayDuplicator::new()?);
+        let mut encoders: Vec<Option<Encoder>> = (0..layers).map(|_| None).collect();
+        let mut scalers: Vec<Scaler> = (1..layers).map(|_| Scaler::new()).collect();
+        // 第一次成功打开编码器后, 之后重新创建时只使用同一个编码器
+        let mut backends = Backend::candidates(video.encoder);
+        let mut profile_tx = Some(profile_tx);
+        // 推流时声明的 level_idc, 所有编码器都按照它设置 level
+        let mut level: Option<u32> = None;
+        let mut source: Box<dyn Source> = match &video.input {
+            Some(url) => {
+                let mut input = FileInput::open(url)?;
+                // 输入已经是可以直接发送的 H264 时不解码和编码, 按照输入的 profile-level-id 推流
+                // * 之后可能改为转码, 编码器的 level 更高时按照较高的 level 声明
+                if layers == 1
+                    && input.can_pass_through()
+                    && let Some(profile_level_id) = input.profile_level_id()
+                    && let Some(profile_tx) = profile_tx.take()
+                {
+                    let frame_rate = input.frame_rate().unwrap_or(video.frame_rate);
+                    let level_idc = h264_level(input.dimensions(), frame_rate, bitrate.max)
+                        .max(profile_level_id & 0xff);
+                    level = Some(level_idc);
+                    let _ = profile_tx.send((profile_level_id & !0xff) | level_idc);
+                    match pass_through(input, &tx, &control_rx)? {
+                        Some(rest) => input = rest,
+                        None => return Ok(()),
+                    }
+                }
+                Box::new(input)
+            }
+            None => create_source(&video)?,
+        };
+        let frame_rate = source.frame_rate().unwrap_or(video.frame_rate);
+
+        let mut bit_rate = bitrate.start;
+        let mut frame_rate_divisor = 1;
+        let mut frame_count: u64 = 0;
 
         let ensure_encoder = |encoder: &mut Option<Encoder>,
-                              width: u32,
-                              height: u32,
-                              hw_frames: *mut AVBufferRef|
+                              backends: &mut Vec<Backend>,
+                              frame: &frame::Video,
+                              frame_rate: i32,
+                              bit_rate: u64,
+                              level: u32|
          -> Result<()> {
-            if let Some(enc) = encoder {
-                if enc.dimensions() != (width, height) {
-                    encoder.replace(create_encoder(width, height, hw_frames)?);
+            if encoder
+                .as_ref()
+                .is_none_or(|enc| enc.dimensions() != (frame.width(), frame.height()))
+            {
+                let created = create_encoder(
+                    backends,
+                    frame,
+                    frame_rate,
+                    bit_rate,
+                    video.keyframe_interval,
+                    level,
+                )?;
+                if backends.len() > 1 {
+                    info!("using {:?} H264 encoder", created.backend());
+                    *backends = vec![created.backend()];
                 }
-            } else {
-                encoder.replace(create_encoder(width, height, hw_frames)?);
+                encoder.replace(created);
             }
 
             Ok(())
         };
         let start = Instant::now();
         loop {
+            // 处理两帧之间收到的所有命令, 关键帧请求会被合并
+            for control in control_rx.try_iter() {
+                match control {
+                    EncoderControl::KeyFrame(layer) => {
+                        for (i, encoder) in encoders.iter_mut().enumerate() {
+                            if let (Some(encoder), true) =
+                                (encoder, layer.is_none_or(|layer| layer == i))
+                            {
+                                encoder.force_idr();
+                            }
+                        }
+                    }
+                    EncoderControl::Bitrate(value) => {
+                        bit_rate = value;
+                        for (i, slot) in encoders.iter_mut().enumerate() {
+                            // 不能在运行时修改码率的编码器按照新的码率重新创建
+                            if slot.as_mut().is_some_and(|encoder| {
+                                !encoder.set_bit_rate(layer_bit_rate(value, i, layers))
+                            }) {
+                                *slot = None;
+                            }
+                        }
+                    }
+                    // 分辨率变化后 ensure_encoder 会重新创建编码器
+                    EncoderControl::ResolutionDivisor(divisor) => {
+                        if !source.set_downscale(divisor)? {
+                            warn!("source does not support downscaling");
+                        }
+                    }
+                    EncoderControl::FramerateDivisor(divisor) => {
+                        if divisor != frame_rate_divisor {
+                            info!("encoding at {} fps", frame_rate / divisor as i32);
+                            frame_rate_divisor = divisor;
+                            encoders.iter_mut().for_each(|encoder| *encoder = None);
+                        }
+                    }
+                }
+            }
+
             // Pull frame from duplicator
-            let frame = source.get_frame()?;
-            let hw_frames = unsafe { (*frame.as_ptr()).hw_frames_ctx };
-            // Fetch encoder or create it
-            ensure_encoder(&mut encoder, frame.width(), frame.height(), hw_frames)?;
-            if let Some(encoder) = &mut encoder {
-                // Encode frame
-                if let Some(packet) = encoder.encode(&frame)? {
-                    tx.send(EncodedPacket(packet, start)).unwrap();
+            let mut frame = source.get_frame()?;
+            // 降低帧率时丢弃采集的帧
+            frame_count += 1;
+            if frame_count % frame_rate_divisor as u64 != 0 {
+                continue;
+            }
+            for (layer, encoder) in encoders.iter_mut().enumerate() {
+                let mut scaled;
+                let frame = if layer == 0 {
+                    &mut frame
+                } else {
+                    // 直接缩小为编码器支持的格式, 避免编码时再转换一次
+                    let format = backends[0].format_for(Pixel::NV12);
+                    scaled = scalers[layer - 1].scale(&frame, 1 << layer, format)?;
+                    &mut scaled
+                };
+                // 按照第一层的第一帧和最高的码率选择 level, 之后调整码率或者降低分辨率和帧率都不会超过
+                let level = *level.get_or_insert_with(|| {
+                    h264_level((frame.width(), frame.height()), frame_rate, bitrate.max)
+                });
+                // Fetch encoder or create it
+                ensure_encoder(
+                    encoder,
+                    &mut backends,
+                    frame,
+                    frame_rate / frame_rate_divisor as i32,
+                    layer_bit_rate(bit_rate, layer, layers),
+                    level,
+                )?;
+                // 第一层的编码器打开后才知道画面大小, 这时才能开始推流
+                if layer == 0
+                    && let Some(profile_tx) = profile_tx.take()
+                {
+                    let _ = profile_tx.send(H264_CONSTRAINED_BASELINE | level);
+                }
+                if let Some(encoder) = encoder {
+                    // Encode frame
+                    if let Some(packet) = encoder.encode(frame)? {
+                        if tx.send(EncodedPacket(packet, start, layer)).is_err() {
+                            // publish 已经退出
+                            return Ok(());
+                        }
+                    }
                 }
             }
         }
     });
 
+    let profile_level_id = match profile_rx.await {
+        Ok(profile_level_id) => profile_level_id,
+        Err(_) => {
+            // 编码线程在打开编码器之前就退出了
+            join_handle.await??;
+            bail!("Video encoding stopped before an encoder was opened");
+        }
+    };
+    info!("publishing H264 profile-level-id {:06x}", profile_level_id);
+    let config = ClientConfig {
+        h264_profile_level_id: Some(profile_level_id),
+        ..config
+    };
+
+    let publish = whip::publish(
+        &url,
+        token,
+        &config,
+        rx,
+        audio_rx,
+        control_tx,
+        bitrate,
+        data,
+        stats,
+        reconnect,
+        shutdown_rx,
+    );
+    tokio::pin!(publish);
     tokio::select! {
-        _ = whip::publish(&url, token, rx) => {},
+        _ = &mut publish => {},
         res = join_handle => {
+            // 编码线程退出后 packet 通道会关闭, 等 publish 结束会话后再返回
+            publish.await;
             res??
         }
     }
@@ -125,47 +721,127 @@ async fn stream(url: String, token: Option<String>) -> Result<()> {
     Ok(())
 }
 
-async fn whip_handler(
-    tx: mpsc::Sender<ffmpeg_next::frame::Video>,
-    offer: String,
-) -> Response<String> {
-    let answer = whip::subscribe_as_server(tx, offer);
-    Response::builder()
-        .status(201)
-        .header("Location", "/")
-        .body(answer)
-        .unwrap()
-}
-
-async fn play_whip() {
-    println!("Listening for WHIP Requests on 0.0.0.0:1337");
+async fn play_whip(
+    config: ClientConfig,
+    send_input: bool,
+    server: ServerArgs,
+    auth: Option<Auth>,
+    stats: Stats,
+) -> Result<()> {
     let (tx, rx): (
         mpsc::Sender<ffmpeg_next::frame::Video>,
         mpsc::Receiver<ffmpeg_next::frame::Video>,
     ) = mpsc::channel();
+    let (audio_tx, audio_rx) = mpsc::channel();
+    let (_shutdown_tx, shutdown_rx) = shutdown_channel();
+    // 输入事件发给正在显示的推流端
+    let (data, input_tx) = match send_input {
+        true => {
+            let (data, input_tx) = send_input_channel();
+            (Some(data), Some(input_tx))
+        }
+        false => (None, None),
+    };
 
-    tokio::task::spawn(async move {
-        axum::serve(
-            tokio::net::TcpListener::bind("0.0.0.0:1337").await.unwrap(),
-            Router::new().route("/", post(move |offer: String| whip_handler(tx, offer))),
-        )
-        .await
-        .unwrap();
-    });
+    let sessions =
+        SessionManager::new(tx, audio_tx, stats.clone(), config, data, auth, shutdown_rx);
+    serve(sessions, &server).await?;
+    render_video(rx, audio_rx, stats, input_tx);
+
+    Ok(())
+}
 
-    render_video(rx);
+/// 在后台运行 WHIP/WHEP 服务端, 配置了证书时使用 HTTPS
+async fn serve(sessions: SessionManager, server: &ServerArgs) -> Result<JoinHandle<()>> {
+    let router = sessions.router(&server.allow_origin)?;
+    let listen = server.listen;
+    let handle = match (&server.tls_cert, &server.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key).await?;
+            println!("Listening for WHIP Requests on https://{}", listen);
+            tokio::task::spawn(async move {
+                if let Err(err) = axum_server::bind_rustls(listen, tls)
+                    .serve(router.into_make_service())
+                    .await
+                {
+                    error!("WHIP server error: {:?}", err);
+                }
+            })
+        }
+        _ => {
+            let listener = tokio::net::TcpListener::bind(listen).await?;
+            println!("Listening for WHIP Requests on http://{}", listen);
+            tokio::task::spawn(async move {
+                if let Err(err) = axum::serve(listener, router).await {
+                    error!("WHIP server error: {:?}", err);
+                }
+            })
+        }
+    };
+
+    Ok(handle)
+}
+
+/// 作为 SFU 转发推流, 直到 Ctrl-C
+async fn restream(
+    config: ClientConfig,
+    server: ServerArgs,
+    auth: Option<Auth>,
+    stats: Stats,
+) -> Result<()> {
+    let (_shutdown_tx, mut shutdown_rx) = shutdown_channel();
+    let sessions = SessionManager::restream(stats, config, auth, shutdown_rx.clone());
+    let handle = serve(sessions, &server).await?;
+
+    tokio::select! {
+        res = handle => res?,
+        _ = shutdown_rx.wait_for(|shutdown| *shutdown) => {}
+    }
+
+    Ok(())
 }
 
-async fn play_whep(url: String, token: Option<String>) -> Result<()> {
+async fn play_whep(
+    url: String,
+    token: Option<String>,
+    config: ClientConfig,
+    send_input: bool,
+    stats: Stats,
+    reconnect: ReconnectConfig,
+) -> Result<()> {
     // mpsc: Multi-Producer Single-Consumer
     // 多生产者, 单消费者, 用于在不同的线程之间传递数据
     let (tx, rx): (
         mpsc::Sender<ffmpeg_next::frame::Video>,
         mpsc::Receiver<ffmpeg_next::frame::Video>,
     ) = mpsc::channel();
+    let (audio_tx, audio_rx) = mpsc::channel();
+    let (shutdown_tx, shutdown_rx) = shutdown_channel();
+
+    let (data, input_tx) = match (send_input, &config.data_channel) {
+        (true, _) => {
+            let (data, input_tx) = send_input_channel();
+            (Some(data), Some(input_tx))
+        }
+        (false, Some(_)) => (Some(data_channel_stdio()), None),
+        (false, None) => (None, None),
+    };
+    let recv_task = whip::subscribe_as_client(
+        tx,
+        audio_tx,
+        stats.clone(),
+        url,
+        token,
+        config,
+        reconnect,
+        data,
+        shutdown_rx,
+    );
+    render_video(rx, audio_rx, stats, input_tx);
 
-    whip::subscribe_as_client(tx, &url, token).await;
-    render_video(rx);
+    // 播放窗口关闭后通知接收任务退出, 并等待它结束会话
+    shutdown_tx.send_replace(true);
+    recv_task.await?;
 
     Ok(())
 }
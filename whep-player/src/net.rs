@@ -0,0 +1,585 @@
+use crate::ice_server;
+use futures::future::select_all;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+};
+use tracing::{debug, error, info, warn};
+use whep_player::SocketMode;
+
+/// 本地 UDP socket
+/// * 双栈: 只绑定一个 `[::]:0` 的 socket, IPv4 的数据以 IPv4-mapped IPv6 地址收发
+/// * 分地址族: IPv4 和 IPv6 各绑定一个 socket, 端口不同
+/// * 共享端口: 使用 [UdpMux] 的 socket, 只接收分发给这个会话的数据
+pub struct Sockets {
+    sockets: Arc<Vec<UdpSocket>>,
+    dual_stack: bool,
+    muxed: Option<Muxed>,
+}
+
+/// 共享端口上属于一个会话的部分, 会话结束 (drop) 时注销
+struct Muxed {
+    mux: UdpMux,
+    id: u64,
+    rx: tokio::sync::Mutex<UnboundedReceiver<(Vec<u8>, SocketAddr)>>,
+}
+
+impl Drop for Muxed {
+    fn drop(&mut self) {
+        self.mux.remove(self.id);
+    }
+}
+
+impl Sockets {
+    pub fn bind(mode: SocketMode) -> io::Result<Self> {
+        Self::bind_port(mode, 0)
+    }
+
+    /// 绑定指定的端口, 0 表示由系统分配
+    fn bind_port(mode: SocketMode, port: u16) -> io::Result<Self> {
+        let v4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+        let v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+        let sockets = match mode {
+            SocketMode::DualStack => match bind_socket(v6, false) {
+                Ok(socket) => {
+                    return Ok(Self {
+                        sockets: Arc::new(vec![socket]),
+                        dual_stack: true,
+                        muxed: None,
+                    });
+                }
+                Err(e) => {
+                    // 系统没有 IPv6 时退化为只有 IPv4
+                    warn!("Failed to bind dual-stack socket: {:?}", e);
+                    vec![bind_socket(v4, false)?]
+                }
+            },
+            SocketMode::PerFamily => {
+                let mut sockets = vec![bind_socket(v4, false)?];
+                match bind_socket(v6, true) {
+                    Ok(socket) => sockets.push(socket),
+                    Err(e) => warn!("Failed to bind IPv6 socket: {:?}", e),
+                }
+                sockets
+            }
+        };
+
+        Ok(Self {
+            sockets: Arc::new(sockets),
+            dual_stack: false,
+            muxed: None,
+        })
+    }
+
+    /// 是否与其他会话共享端口
+    pub fn is_muxed(&self) -> bool {
+        self.muxed.is_some()
+    }
+
+    /// 共享端口时, 把发往这个 ice-ufrag 的连通性检查分发给这个会话
+    pub fn route_ufrag(&self, ufrag: &str) {
+        if let Some(muxed) = &self.muxed {
+            muxed.mux.route_ufrag(ufrag, muxed.id);
+        }
+    }
+
+    /// 负责收发某个地址族数据的 socket
+    fn socket_for(&self, ip: IpAddr) -> Option<&UdpSocket> {
+        if self.dual_stack {
+            return self.sockets.first();
+        }
+
+        self.sockets.iter().find(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv4() == ip.is_ipv4())
+        })
+    }
+
+    /// 某个地址族的本地端口, 没有对应的 socket 时返回 None
+    pub fn port(&self, ip: IpAddr) -> Option<u16> {
+        self.socket_for(ip)?
+            .local_addr()
+            .ok()
+            .map(|addr| addr.port())
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let Some(socket) = self.socket_for(target.ip()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no socket for {}", target),
+            ));
+        };
+
+        // 共享端口时, 对端回复的数据 (比如连通性检查的响应) 也要分发给这个会话
+        if let Some(muxed) = &self.muxed {
+            muxed.mux.route_addr(target, muxed.id);
+        }
+
+        // 双栈 socket 发往 IPv4 地址时需要使用 IPv4-mapped IPv6 地址
+        let target = match target {
+            SocketAddr::V4(v4) if self.dual_stack => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            _ => target,
+        };
+
+        socket.send_to(buf, target).await
+    }
+
+    /// 从任意一个 socket 接收数据
+    /// * 可以安全地被取消 (用于 `tokio::time::timeout`)
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if let Some(muxed) = &self.muxed {
+            let Some((data, source)) = muxed.rx.lock().await.recv().await else {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "shared UDP port closed",
+                ));
+            };
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            return Ok((n, source));
+        }
+
+        let (n, source) = if self.sockets.len() == 1 {
+            self.sockets[0].recv_from(buf).await?
+        } else {
+            loop {
+                let (ready, index, _) = select_all(
+                    self.sockets
+                        .iter()
+                        .map(|socket| Box::pin(socket.readable())),
+                )
+                .await;
+                ready?;
+                match self.sockets[index].try_recv_from(buf) {
+                    Ok(res) => break res,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        // 双栈 socket 收到的 IPv4 数据, 来源是 IPv4-mapped IPv6 地址
+        Ok((
+            n,
+            SocketAddr::new(source.ip().to_canonical(), source.port()),
+        ))
+    }
+}
+
+/// 所有会话共享的 UDP 端口
+/// * 收到的 STUN Binding 请求按照 USERNAME 中的 ice-ufrag 分发, 并记住来源地址
+/// * 其他数据 (DTLS / SRTP) 按照来源地址分发
+#[derive(Debug, Clone)]
+pub struct UdpMux {
+    sockets: Arc<Vec<UdpSocket>>,
+    dual_stack: bool,
+    routes: Arc<Mutex<Routes>>,
+}
+
+#[derive(Debug, Default)]
+struct Routes {
+    next_id: u64,
+    sessions: HashMap<u64, UnboundedSender<(Vec<u8>, SocketAddr)>>,
+    ufrags: HashMap<String, u64>,
+    addrs: HashMap<SocketAddr, u64>,
+}
+
+impl UdpMux {
+    /// 绑定端口, 并开始接收和分发数据
+    pub fn bind(mode: SocketMode, port: u16) -> io::Result<Self> {
+        let sockets = Sockets::bind_port(mode, port)?;
+        let mux = Self {
+            sockets: sockets.sockets.clone(),
+            dual_stack: sockets.dual_stack,
+            routes: Arc::new(Mutex::new(Routes::default())),
+        };
+        info!("all sessions share UDP port {}", port);
+
+        let dispatcher = mux.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; 2000];
+            loop {
+                match sockets.recv_from(&mut buf).await {
+                    Ok((n, source)) => dispatcher.dispatch(&buf[..n], source),
+                    // Windows 上对端不可达的 ICMP 会使下一次 recv 失败, 忽略
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                    Err(e) => {
+                        error!("shared UDP port recv error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(mux)
+    }
+
+    /// 一个新会话使用的 Sockets, 与其他会话共享同一个端口
+    pub fn sockets(&self) -> Sockets {
+        let (tx, rx) = unbounded_channel();
+        let mut routes = self.routes.lock().unwrap();
+        let id = routes.next_id;
+        routes.next_id += 1;
+        routes.sessions.insert(id, tx);
+
+        Sockets {
+            sockets: self.sockets.clone(),
+            dual_stack: self.dual_stack,
+            muxed: Some(Muxed {
+                mux: self.clone(),
+                id,
+                rx: tokio::sync::Mutex::new(rx),
+            }),
+        }
+    }
+
+    fn route_ufrag(&self, ufrag: &str, id: u64) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.ufrags.insert(ufrag.to_string(), id);
+    }
+
+    fn route_addr(&self, addr: SocketAddr, id: u64) {
+        self.routes.lock().unwrap().addrs.insert(addr, id);
+    }
+
+    fn remove(&self, id: u64) {
+        let mut routes = self.routes.lock().unwrap();
+        routes.sessions.remove(&id);
+        routes.ufrags.retain(|_, session| *session != id);
+        routes.addrs.retain(|_, session| *session != id);
+    }
+
+    fn dispatch(&self, data: &[u8], source: SocketAddr) {
+        let mut routes = self.routes.lock().unwrap();
+        // 对端的地址在连通性检查之前是未知的, 之后也可能变化 (比如换了候选者对)
+        let id = match ice_server::binding_request_ufrag(data)
+            .and_then(|ufrag| routes.ufrags.get(&ufrag).copied())
+        {
+            Some(id) => {
+                routes.addrs.insert(source, id);
+                id
+            }
+            None => match routes.addrs.get(&source) {
+                Some(id) => *id,
+                None => {
+                    debug!("drop packet from {}: no session", source);
+                    return;
+                }
+            },
+        };
+
+        if let Some(tx) = routes.sessions.get(&id) {
+            let _ = tx.send((data.to_vec(), source));
+        }
+    }
+}
+
+fn bind_socket(addr: SocketAddr, only_v6: bool) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    info!("local socket address: {:?}", socket.local_addr());
+    Ok(socket)
+}
+
+/// 网卡过滤规则
+/// * 网卡名, 支持以 `*` 结尾的前缀匹配, 比如 `docker0`, `veth*`
+/// * CIDR, 比如 `172.17.0.0/16`, `fd00::/8`, 单个 IP 等价于最长前缀
+#[derive(Debug, Clone)]
+pub enum InterfaceFilter {
+    Name(String),
+    Cidr(IpAddr, u8),
+}
+
+impl FromStr for InterfaceFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = s.split_once('/').unwrap_or((s, ""));
+        let Ok(ip) = IpAddr::from_str(ip) else {
+            return Ok(Self::Name(s.to_string()));
+        };
+
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max_prefix,
+            prefix => prefix.parse()?,
+        };
+        if prefix > max_prefix {
+            anyhow::bail!("Invalid CIDR prefix {}", s);
+        }
+
+        Ok(Self::Cidr(ip, prefix))
+    }
+}
+
+/// NAT 1:1 映射, 把公网地址作为 host 候选者发布
+/// * 只有公网 IP 时, 映射同一地址族的所有网卡地址, 比如 `203.0.113.7`
+/// * `公网 IP/私有 IP` 只映射这个网卡地址, 比如 `203.0.113.7/10.0.0.5`
+#[derive(Debug, Clone)]
+pub struct NatMapping {
+    pub public: IpAddr,
+    pub private: Option<IpAddr>,
+}
+
+impl FromStr for NatMapping {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (public, private): (IpAddr, Option<IpAddr>) = match s.split_once('/') {
+            Some((public, private)) => (public.parse()?, Some(private.parse()?)),
+            None => (s.parse()?, None),
+        };
+        if private.is_some_and(|private| private.is_ipv4() != public.is_ipv4()) {
+            anyhow::bail!("Public and private IP of different families: {}", s);
+        }
+
+        Ok(Self { public, private })
+    }
+}
+
+impl NatMapping {
+    pub fn maps(&self, ip: IpAddr) -> bool {
+        match self.private {
+            Some(private) => private == ip,
+            None => self.public.is_ipv4() == ip.is_ipv4(),
+        }
+    }
+}
+
+/// 不能从公网直接访问的地址: 私有地址, 回环地址, 链路本地地址
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip4) => ip4.is_private() || ip4.is_loopback() || ip4.is_link_local(),
+        IpAddr::V6(ip6) => {
+            // fc00::/7 唯一本地地址, fe80::/10 链路本地地址
+            (ip6.segments()[0] & 0xfe00) == 0xfc00
+                || (ip6.segments()[0] & 0xffc0) == 0xfe80
+                || ip6.is_loopback()
+        }
+    }
+}
+
+impl InterfaceFilter {
+    pub fn matches(&self, name: &str, ip: IpAddr) -> bool {
+        match self {
+            Self::Name(pattern) => match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            },
+            Self::Cidr(network, prefix) => match (ip, network) {
+                (IpAddr::V4(ip), IpAddr::V4(network)) => {
+                    let mask = u32::MAX.checked_shl(32 - *prefix as u32).unwrap_or(0);
+                    u32::from(ip) & mask == u32::from(*network) & mask
+                }
+                (IpAddr::V6(ip), IpAddr::V6(network)) => {
+                    let mask = u128::MAX.checked_shl(128 - *prefix as u32).unwrap_or(0);
+                    u128::from(ip) & mask == u128::from(*network) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    /// 只带 USERNAME 的 STUN Binding 请求, 足够 UdpMux 分发
+    fn binding_request(username: &str) -> Vec<u8> {
+        let mut attr = vec![];
+        attr.extend(0x0006u16.to_be_bytes());
+        attr.extend((username.len() as u16).to_be_bytes());
+        attr.extend(username.as_bytes());
+        attr.resize(attr.len().next_multiple_of(4), 0);
+
+        let mut message = vec![];
+        message.extend(0x0001u16.to_be_bytes());
+        message.extend((attr.len() as u16).to_be_bytes());
+        message.extend(0x2112_A442u32.to_be_bytes());
+        message.extend([7; 12]);
+        message.extend(attr);
+        message
+    }
+
+    async fn peer() -> UdpSocket {
+        UdpSocket::bind((LOCALHOST, 0)).await.unwrap()
+    }
+
+    async fn recv(sockets: &Sockets) -> Option<(Vec<u8>, SocketAddr)> {
+        let mut buf = vec![0; 2000];
+        let (n, source) =
+            tokio::time::timeout(Duration::from_millis(200), sockets.recv_from(&mut buf))
+                .await
+                .ok()?
+                .unwrap();
+        Some((buf[..n].to_vec(), source))
+    }
+
+    #[tokio::test]
+    async fn udp_mux_routes_by_ufrag_and_address() {
+        let mux = UdpMux::bind(SocketMode::PerFamily, 0).unwrap();
+        let a = mux.sockets();
+        let b = mux.sockets();
+        assert!(a.is_muxed() && b.is_muxed());
+        a.route_ufrag("ua");
+        b.route_ufrag("ub");
+        let port = a.port(LOCALHOST).unwrap();
+        assert_eq!(b.port(LOCALHOST), Some(port));
+        let target = SocketAddr::new(LOCALHOST, port);
+
+        // 连通性检查按照 USERNAME 中的本地 ufrag 分发, 并记住对端的地址
+        let peer_b = peer().await;
+        let request = binding_request("ub:remote");
+        peer_b.send_to(&request, target).await.unwrap();
+        let source = peer_b.local_addr().unwrap();
+        assert_eq!(recv(&b).await, Some((request, source)));
+        peer_b.send_to(b"dtls", target).await.unwrap();
+        assert_eq!(recv(&b).await, Some((b"dtls".to_vec(), source)));
+        assert_eq!(recv(&a).await, None);
+
+        // 会话主动发送的对端, 回复的数据也分发给这个会话
+        let peer_a = peer().await;
+        let peer_a_addr = peer_a.local_addr().unwrap();
+        a.send_to(b"ping", peer_a_addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (n, from) = peer_a.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..n], from.port()), (&b"ping"[..], port));
+        peer_a.send_to(b"pong", target).await.unwrap();
+        assert_eq!(recv(&a).await, Some((b"pong".to_vec(), peer_a_addr)));
+
+        // 未知的 ufrag 和地址被丢弃
+        let stranger = peer().await;
+        stranger
+            .send_to(&binding_request("unknown:remote"), target)
+            .await
+            .unwrap();
+        stranger.send_to(b"data", target).await.unwrap();
+        assert_eq!(recv(&a).await, None);
+        assert_eq!(recv(&b).await, None);
+    }
+
+    #[tokio::test]
+    async fn udp_mux_removes_routes_on_drop() {
+        let mux = UdpMux::bind(SocketMode::PerFamily, 0).unwrap();
+        let a = mux.sockets();
+        let b = mux.sockets();
+        a.route_ufrag("ua");
+        b.route_ufrag("ub");
+        let target = SocketAddr::new(LOCALHOST, a.port(LOCALHOST).unwrap());
+        let peer = peer().await;
+        peer.send_to(&binding_request("ub:remote"), target)
+            .await
+            .unwrap();
+        assert!(recv(&b).await.is_some());
+
+        drop(b);
+        {
+            let routes = mux.routes.lock().unwrap();
+            assert_eq!(routes.sessions.len(), 1);
+            assert_eq!(routes.ufrags.keys().collect::<Vec<_>>(), vec!["ua"]);
+            assert!(routes.addrs.is_empty());
+        }
+
+        // 同一个对端之后的数据不会分发给其他会话
+        peer.send_to(&binding_request("ub:remote"), target)
+            .await
+            .unwrap();
+        peer.send_to(b"dtls", target).await.unwrap();
+        assert_eq!(recv(&a).await, None);
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn private_addresses() {
+        for private in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.255",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.10.20",
+            "::1",
+            "fc00::1",
+            "fd12:3456::1",
+            "fe80::1",
+            "febf::1",
+        ] {
+            assert!(is_private(ip(private)), "{private}");
+        }
+        for public in [
+            "8.8.8.8",
+            "172.32.0.1",
+            "203.0.113.7",
+            "2001:db8::1",
+            "fec0::1",
+            "fbff::1",
+        ] {
+            assert!(!is_private(ip(public)), "{public}");
+        }
+    }
+
+    #[test]
+    fn nat_mapping() {
+        let all: NatMapping = "203.0.113.7".parse().unwrap();
+        assert_eq!((all.public, all.private), (ip("203.0.113.7"), None));
+        assert!(all.maps(ip("10.0.0.5")) && all.maps(ip("192.168.1.2")));
+        assert!(!all.maps(ip("fd00::1")));
+
+        let one: NatMapping = "203.0.113.7/10.0.0.5".parse().unwrap();
+        assert_eq!(one.private, Some(ip("10.0.0.5")));
+        assert!(one.maps(ip("10.0.0.5")));
+        assert!(!one.maps(ip("10.0.0.6")));
+
+        assert!("2001:db8::1/10.0.0.5".parse::<NatMapping>().is_err());
+        assert!("example.com".parse::<NatMapping>().is_err());
+    }
+
+    #[test]
+    fn interface_filter() {
+        let filter = |s: &str| s.parse::<InterfaceFilter>().unwrap();
+        assert!(filter("docker0").matches("docker0", ip("172.17.0.1")));
+        assert!(!filter("docker0").matches("docker01", ip("172.17.0.1")));
+        assert!(filter("veth*").matches("veth1234", ip("10.0.0.1")));
+        assert!(!filter("veth*").matches("eth0", ip("10.0.0.1")));
+
+        let cidr = filter("172.17.0.0/16");
+        assert!(cidr.matches("eth0", ip("172.17.3.4")));
+        assert!(!cidr.matches("eth0", ip("172.18.0.1")));
+        assert!(!cidr.matches("eth0", ip("fd00::1")));
+        // 单个 IP 只匹配它自己, /0 匹配同一地址族的所有地址
+        assert!(filter("10.0.0.5").matches("eth0", ip("10.0.0.5")));
+        assert!(!filter("10.0.0.5").matches("eth0", ip("10.0.0.6")));
+        assert!(filter("0.0.0.0/0").matches("eth0", ip("8.8.8.8")));
+        assert!(filter("fd00::/8").matches("eth0", ip("fd12::1")));
+        assert!(!filter("fd00::/8").matches("eth0", ip("fe80::1")));
+
+        assert!("10.0.0.0/33".parse::<InterfaceFilter>().is_err());
+        assert!("fd00::/129".parse::<InterfaceFilter>().is_err());
+        assert!("10.0.0.0/x".parse::<InterfaceFilter>().is_err());
+    }
+}
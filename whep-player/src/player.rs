@@ -1,9 +1,94 @@
+use crate::client::ChannelMessage;
+use crate::input::{InputEvent, MouseButton};
+use crate::stats::Stats;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::mouse;
 use sdl2::pixels::PixelFormatEnum;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, TryRecvError};
+use tokio::sync::mpsc::UnboundedSender;
+use tracing::warn;
 
-pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
+// 音频队列积压超过 200ms (48kHz 双声道 f32) 时清空, 避免延迟越来越大
+const MAX_QUEUED_AUDIO_BYTES: u32 = 48000 * 2 * 4 / 5;
+
+/// 把播放窗口中的鼠标键盘事件转换为发给推流端的输入事件
+/// * 窗口坐标按照画面的尺寸缩放, 推流端再按照采集的分辨率缩放
+fn input_event(event: &Event, window: (u32, u32), frame: (u32, u32)) -> Option<InputEvent> {
+    let scale = |x: i32, y: i32| {
+        (
+            (x.max(0) as u64 * frame.0 as u64 / window.0.max(1) as u64) as u32,
+            (y.max(0) as u64 * frame.1 as u64 / window.1.max(1) as u64) as u32,
+        )
+    };
+    let button = |button: mouse::MouseButton| match button {
+        mouse::MouseButton::Left => Some(MouseButton::Left),
+        mouse::MouseButton::Middle => Some(MouseButton::Middle),
+        mouse::MouseButton::Right => Some(MouseButton::Right),
+        mouse::MouseButton::X1 => Some(MouseButton::X1),
+        mouse::MouseButton::X2 => Some(MouseButton::X2),
+        mouse::MouseButton::Unknown => None,
+    };
+
+    match *event {
+        Event::MouseMotion { x, y, .. } => {
+            let (x, y) = scale(x, y);
+            Some(InputEvent::MouseMove {
+                x,
+                y,
+                width: frame.0,
+                height: frame.1,
+            })
+        }
+        Event::MouseButtonDown { mouse_btn, .. } => Some(InputEvent::MouseButton {
+            button: button(mouse_btn)?,
+            pressed: true,
+        }),
+        Event::MouseButtonUp { mouse_btn, .. } => Some(InputEvent::MouseButton {
+            button: button(mouse_btn)?,
+            pressed: false,
+        }),
+        Event::MouseWheel { x, y, .. } => Some(InputEvent::MouseWheel { dx: x, dy: y }),
+        // 按住按键时的自动重复由推流端的系统产生
+        Event::KeyDown {
+            scancode: Some(scancode),
+            repeat: false,
+            ..
+        } => Some(InputEvent::Key {
+            scancode: scancode as u32,
+            pressed: true,
+        }),
+        Event::KeyUp {
+            scancode: Some(scancode),
+            ..
+        } => Some(InputEvent::Key {
+            scancode: scancode as u32,
+            pressed: false,
+        }),
+        _ => None,
+    }
+}
+
+/// 是否关闭播放窗口
+/// * 转发输入时 Escape 也要发给推流端, 只能关闭窗口退出
+fn quits(event: &Event, forwarding_input: bool) -> bool {
+    match event {
+        Event::Quit { .. } => true,
+        Event::KeyDown {
+            keycode: Some(Keycode::Escape),
+            ..
+        } => !forwarding_input,
+        _ => false,
+    }
+}
+
+pub fn render_video(
+    rx: mpsc::Receiver<ffmpeg_next::frame::Video>,
+    audio_rx: mpsc::Receiver<Vec<f32>>,
+    stats: Stats,
+    input: Option<UnboundedSender<ChannelMessage>>,
+) {
     match rx.recv() {
         Ok(first_frame) => {
             let sdl_context = sdl2::init().unwrap();
@@ -16,57 +101,104 @@ pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
 
             let mut canvas = window.into_canvas().build().unwrap();
             let mut event_pump = sdl_context.event_pump().unwrap();
+
+            // 没有音频设备时只播放视频
+            let audio_queue: Option<AudioQueue<f32>> = sdl_context
+                .audio()
+                .and_then(|audio| {
+                    audio.open_queue(
+                        None,
+                        &AudioSpecDesired {
+                            freq: Some(48000),
+                            channels: Some(2),
+                            samples: Some(960),
+                        },
+                    )
+                })
+                .inspect(|queue| queue.resume())
+                .inspect_err(|e| warn!("Failed to open audio device: {}", e))
+                .ok();
             let texture_creator = canvas.texture_creator();
-            let mut texture = texture_creator
-                .create_texture_streaming(PixelFormatEnum::IYUV, first_frame.width(), first_frame.height())
-                .map_err(|e| e.to_string())
-                .expect("No error");
-
-            let buffer_size: i32;
-            unsafe {
-                buffer_size = ffmpeg_sys_next::av_image_get_buffer_size(
-                    first_frame.format().into(),
-                    first_frame.width() as i32,
-                    first_frame.height() as i32,
-                    32,
-                );
+            let create_texture = |(width, height): (u32, u32)| {
+                texture_creator
+                    .create_texture_streaming(PixelFormatEnum::IYUV, width, height)
+                    .map_err(|e| e.to_string())
+                    .expect("No error")
             };
+            let mut frame_size = (first_frame.width(), first_frame.height());
+            let mut texture = create_texture(frame_size);
 
+            // 在标题中显示连接状态, 方便在监控画面上看出是否正在重连
+            let mut state_rx = stats.subscribe();
+            let mut state = None;
 
             'running: loop {
+                let mut disconnected = false;
+                let current = state_rx.borrow_and_update().connection;
+                if state != Some(current) {
+                    state = Some(current);
+                    let _ = canvas
+                        .window_mut()
+                        .set_title(&format!("bitwhip - {}", current));
+                }
                 for event in event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. }
-                        | Event::KeyDown {
-                            keycode: Some(Keycode::Escape),
-                            ..
-                        } => break 'running,
-                        _ => {}
+                    if quits(&event, input.is_some()) {
+                        break 'running;
+                    }
+                    if let Some(input) = &input
+                        && let Some(event) = input_event(&event, canvas.window().size(), frame_size)
+                    {
+                        let _ = input.send(event.to_message());
                     }
                 }
 
-                texture
-                    .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                        match rx.try_recv() {
-                            Ok(frame) => {
-                                unsafe {
-                                    let frame_ptr = *frame.as_ptr();
-                                    ffmpeg_sys_next::av_image_copy_to_buffer(
-                                        buffer.as_mut_ptr(),
-                                        buffer_size,
-                                        frame_ptr.data.as_ptr() as *mut _,
-                                        frame_ptr.linesize.as_ptr() as *mut _,
-                                        frame.format().into(),
-                                        frame_ptr.width,
-                                        frame_ptr.height,
-                                        32,
-                                    );
-                                }
-                            }
-                            Err(_err) => {}
+                match rx.try_recv() {
+                    Ok(frame) => {
+                        // 切换推流端或者 simulcast 层后画面的尺寸会变化, 需要重新创建纹理
+                        if (frame.width(), frame.height()) != frame_size {
+                            frame_size = (frame.width(), frame.height());
+                            texture = create_texture(frame_size);
                         }
-                    })
-                .expect("texture copy");
+                        texture
+                            .with_lock(None, |buffer: &mut [u8], _pitch: usize| unsafe {
+                                let frame_ptr = *frame.as_ptr();
+                                let buffer_size = ffmpeg_sys_next::av_image_get_buffer_size(
+                                    frame.format().into(),
+                                    frame_ptr.width,
+                                    frame_ptr.height,
+                                    32,
+                                );
+                                ffmpeg_sys_next::av_image_copy_to_buffer(
+                                    buffer.as_mut_ptr(),
+                                    buffer_size,
+                                    frame_ptr.data.as_ptr() as *mut _,
+                                    frame_ptr.linesize.as_ptr() as *mut _,
+                                    frame.format().into(),
+                                    frame_ptr.width,
+                                    frame_ptr.height,
+                                    32,
+                                );
+                            })
+                            .expect("texture copy");
+                        stats.frame_rendered();
+                    }
+                    Err(TryRecvError::Disconnected) => disconnected = true,
+                    Err(TryRecvError::Empty) => {}
+                }
+
+                while let Ok(samples) = audio_rx.try_recv() {
+                    if let Some(queue) = &audio_queue {
+                        if queue.size() > MAX_QUEUED_AUDIO_BYTES {
+                            queue.clear();
+                        }
+                        let _ = queue.queue_audio(&samples);
+                    }
+                }
+
+                // 所有会话都已经退出 (断开连接或者 Ctrl-C)
+                if disconnected {
+                    break 'running;
+                }
 
                 canvas.clear();
                 canvas.copy(&texture, None, None).expect("No error");
@@ -76,3 +208,188 @@ pub fn render_video(rx: mpsc::Receiver<ffmpeg_next::frame::Video>) {
         Err(_err) => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::{Mod, Scancode};
+    use sdl2::mouse::{MouseState, MouseWheelDirection};
+
+    fn motion(x: i32, y: i32) -> Event {
+        Event::MouseMotion {
+            timestamp: 0,
+            window_id: 1,
+            which: 0,
+            mousestate: MouseState::from_sdl_state(0),
+            x,
+            y,
+            xrel: 0,
+            yrel: 0,
+        }
+    }
+
+    fn button(mouse_btn: mouse::MouseButton, pressed: bool) -> Event {
+        if pressed {
+            Event::MouseButtonDown {
+                timestamp: 0,
+                window_id: 1,
+                which: 0,
+                mouse_btn,
+                clicks: 1,
+                x: 0,
+                y: 0,
+            }
+        } else {
+            Event::MouseButtonUp {
+                timestamp: 0,
+                window_id: 1,
+                which: 0,
+                mouse_btn,
+                clicks: 1,
+                x: 0,
+                y: 0,
+            }
+        }
+    }
+
+    fn key(keycode: Keycode, scancode: Option<Scancode>, pressed: bool, repeat: bool) -> Event {
+        if pressed {
+            Event::KeyDown {
+                timestamp: 0,
+                window_id: 1,
+                keycode: Some(keycode),
+                scancode,
+                keymod: Mod::NOMOD,
+                repeat,
+            }
+        } else {
+            Event::KeyUp {
+                timestamp: 0,
+                window_id: 1,
+                keycode: Some(keycode),
+                scancode,
+                keymod: Mod::NOMOD,
+                repeat,
+            }
+        }
+    }
+
+    #[test]
+    fn mouse_scaled_from_window_to_frame() {
+        let frame = (1280, 720);
+        let moved = |x, y| InputEvent::MouseMove {
+            x,
+            y,
+            width: 1280,
+            height: 720,
+        };
+        // 窗口比画面小或者大时按比例缩放, 窗口外的负坐标取 0
+        assert_eq!(
+            input_event(&motion(320, 180), (640, 360), frame),
+            Some(moved(640, 360))
+        );
+        assert_eq!(
+            input_event(&motion(960, 540), (1920, 1080), frame),
+            Some(moved(640, 360))
+        );
+        assert_eq!(
+            input_event(&motion(-10, 1079), (1920, 1080), frame),
+            Some(moved(0, 719))
+        );
+        // 窗口的尺寸为 0 时不会除以 0
+        assert!(input_event(&motion(1, 1), (0, 0), frame).is_some());
+
+        assert_eq!(
+            input_event(&button(mouse::MouseButton::Right, true), (640, 360), frame),
+            Some(InputEvent::MouseButton {
+                button: MouseButton::Right,
+                pressed: true
+            })
+        );
+        assert_eq!(
+            input_event(&button(mouse::MouseButton::X1, false), (640, 360), frame),
+            Some(InputEvent::MouseButton {
+                button: MouseButton::X1,
+                pressed: false
+            })
+        );
+        assert_eq!(
+            input_event(
+                &button(mouse::MouseButton::Unknown, true),
+                (640, 360),
+                frame
+            ),
+            None
+        );
+
+        let wheel = Event::MouseWheel {
+            timestamp: 0,
+            window_id: 1,
+            which: 0,
+            x: 0,
+            y: -2,
+            direction: MouseWheelDirection::Normal,
+            precise_x: 0.0,
+            precise_y: -2.0,
+            mouse_x: 0,
+            mouse_y: 0,
+        };
+        assert_eq!(
+            input_event(&wheel, (640, 360), frame),
+            Some(InputEvent::MouseWheel { dx: 0, dy: -2 })
+        );
+    }
+
+    #[test]
+    fn keys_use_scancodes_without_repeats() {
+        let size = (640, 360);
+        assert_eq!(
+            input_event(&key(Keycode::A, Some(Scancode::A), true, false), size, size),
+            Some(InputEvent::Key {
+                scancode: 4,
+                pressed: true
+            })
+        );
+        assert_eq!(
+            input_event(&key(Keycode::A, Some(Scancode::A), true, true), size, size),
+            None
+        );
+        assert_eq!(
+            input_event(
+                &key(Keycode::A, Some(Scancode::A), false, false),
+                size,
+                size
+            ),
+            Some(InputEvent::Key {
+                scancode: 4,
+                pressed: false
+            })
+        );
+        assert_eq!(
+            input_event(&key(Keycode::A, None, true, false), size, size),
+            None
+        );
+    }
+
+    #[test]
+    fn escape_quits_only_without_input() {
+        let escape = key(Keycode::Escape, Some(Scancode::Escape), true, false);
+        assert!(quits(&escape, false));
+        assert!(!quits(&escape, true));
+        // 转发输入时 Escape 发给推流端
+        assert_eq!(
+            input_event(&escape, (640, 360), (640, 360)),
+            Some(InputEvent::Key {
+                scancode: 41,
+                pressed: true
+            })
+        );
+
+        assert!(quits(&Event::Quit { timestamp: 0 }, true));
+        assert!(!quits(
+            &key(Keycode::Q, Some(Scancode::Q), true, false),
+            false
+        ));
+        assert!(!quits(&motion(0, 0), false));
+    }
+}
@@ -0,0 +1,269 @@
+use crate::stats::Stats;
+use rand::Rng;
+use serde::Serialize;
+use std::{fmt, time::Duration};
+use tokio::sync::watch;
+use tracing::info;
+
+/// 会话的连接状态
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub enum ConnectionState {
+    #[default]
+    Connecting,
+    Connected,
+    Reconnecting(u32), // 第几次重连
+    GaveUp,
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "Connecting"),
+            Self::Connected => write!(f, "Connected"),
+            Self::Reconnecting(attempt) => write!(f, "Reconnecting (attempt {})", attempt),
+            Self::GaveUp => write!(f, "Gave up"),
+        }
+    }
+}
+
+/// 一次会话结束的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// 收到退出信号, 播放窗口或者编码器已经关闭, 或者重连也无法恢复的错误
+    Stopped,
+    /// 连接失败或者断开, connected 表示断开前是否已经连接成功过
+    Lost { connected: bool },
+}
+
+/// 重连的配置
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    pub max_attempts: Option<u32>, // 连续重连的最大次数, None 表示一直重连, 0 表示不重连
+    pub min_delay: Duration,       // 第一次重连前的等待时间
+    pub max_delay: Duration,       // 等待时间指数增长的上限
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            min_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// 指数退避
+/// * 第 n 次重连的等待时间为 min_delay * 2^n, 不超过 max_delay
+/// * 加上随机抖动 (实际等待 [delay/2, delay]), 避免大量客户端同时重连
+struct Backoff {
+    config: ReconnectConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_attempts
+            .is_some_and(|max| self.attempt >= max)
+        {
+            return None;
+        }
+
+        let delay = self
+            .config
+            .min_delay
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.config.max_delay);
+        self.attempt += 1;
+
+        let half = delay / 2;
+        Some(half + rand::thread_rng().gen_range(Duration::ZERO..=half))
+    }
+}
+
+/// 重连的监督者
+/// * 每次会话结束后决定是否重连, 并通过 Stats 报告连接状态
+pub struct Supervisor {
+    backoff: Backoff,
+    stats: Stats,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Supervisor {
+    pub fn new(config: ReconnectConfig, stats: Stats, shutdown: watch::Receiver<bool>) -> Self {
+        Self {
+            backoff: Backoff { config, attempt: 0 },
+            stats,
+            shutdown,
+        }
+    }
+
+    /// 开始建立连接
+    pub fn connecting(&self) {
+        let state = match self.backoff.attempt {
+            0 => ConnectionState::Connecting,
+            attempt => ConnectionState::Reconnecting(attempt),
+        };
+        self.stats.set_state(state);
+    }
+
+    /// 会话结束后决定是否重连, 需要重连时等待退避时间后返回 true
+    pub async fn should_reconnect(&mut self, end: SessionEnd) -> bool {
+        let connected = match end {
+            SessionEnd::Stopped => return false,
+            SessionEnd::Lost { connected } => connected,
+        };
+        if *self.shutdown.borrow() {
+            return false;
+        }
+        // 连接成功过, 重新开始计算重连次数
+        if connected {
+            self.backoff.attempt = 0;
+        }
+
+        let Some(delay) = self.backoff.next_delay() else {
+            info!(
+                "giving up after {} reconnect attempts",
+                self.backoff.attempt
+            );
+            self.stats.set_state(ConnectionState::GaveUp);
+            return false;
+        };
+        info!(
+            "reconnecting in {:?} (attempt {})",
+            delay, self.backoff.attempt
+        );
+        self.stats
+            .set_state(ConnectionState::Reconnecting(self.backoff.attempt));
+
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => true,
+            _ = self.shutdown.changed() => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_attempts: Option<u32>) -> ReconnectConfig {
+        ReconnectConfig {
+            max_attempts,
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay() {
+        let mut backoff = Backoff {
+            config: config(None),
+            attempt: 0,
+        };
+        // 抖动后的等待时间在 [delay/2, delay] 之间
+        for expected in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay().unwrap().as_millis();
+            assert!(
+                (expected / 2..=expected).contains(&delay),
+                "{delay}ms not in [{}, {expected}]ms",
+                expected / 2
+            );
+        }
+        assert_eq!(backoff.attempt, 6);
+
+        // 次数很大时不会溢出
+        backoff.attempt = 100;
+        assert!(backoff.next_delay().unwrap() <= Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn backoff_stops_after_max_attempts() {
+        let mut backoff = Backoff {
+            config: config(Some(2)),
+            attempt: 0,
+        };
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(backoff.next_delay(), None);
+
+        let mut never = Backoff {
+            config: config(Some(0)),
+            attempt: 0,
+        };
+        assert_eq!(never.next_delay(), None);
+    }
+
+    #[tokio::test]
+    async fn supervisor_resets_after_connected() {
+        let stats = Stats::new();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut supervisor = Supervisor::new(
+            ReconnectConfig {
+                max_attempts: Some(2),
+                min_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(2),
+            },
+            stats.clone(),
+            shutdown_rx,
+        );
+
+        assert!(!supervisor.should_reconnect(SessionEnd::Stopped).await);
+
+        let lost = SessionEnd::Lost { connected: false };
+        assert!(supervisor.should_reconnect(lost).await);
+        supervisor.connecting();
+        assert_eq!(
+            stats.subscribe().borrow().connection,
+            ConnectionState::Reconnecting(1)
+        );
+        assert!(supervisor.should_reconnect(lost).await);
+        assert!(!supervisor.should_reconnect(lost).await);
+        assert_eq!(
+            stats.subscribe().borrow().connection,
+            ConnectionState::GaveUp
+        );
+
+        // 断开前连接成功过, 重新开始计算次数
+        assert!(
+            supervisor
+                .should_reconnect(SessionEnd::Lost { connected: true })
+                .await
+        );
+        assert_eq!(supervisor.backoff.attempt, 1);
+    }
+
+    #[tokio::test]
+    async fn supervisor_stops_on_shutdown() {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut supervisor = Supervisor::new(
+            ReconnectConfig {
+                max_attempts: None,
+                min_delay: Duration::from_secs(60),
+                max_delay: Duration::from_secs(60),
+            },
+            Stats::new(),
+            shutdown_rx,
+        );
+
+        // 等待退避时间的过程中收到退出信号
+        let stop = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            shutdown_tx.send(true).unwrap();
+        };
+        let (reconnect, _) = tokio::join!(
+            supervisor.should_reconnect(SessionEnd::Lost { connected: true }),
+            stop
+        );
+        assert!(!reconnect);
+
+        // 已经收到退出信号后不再重连
+        assert!(
+            !supervisor
+                .should_reconnect(SessionEnd::Lost { connected: true })
+                .await
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::client::{Client, WebrtcEvent};
+use crate::reconnect::SessionEnd;
+use std::sync::Arc;
+use str0m::media::{KeyframeRequestKind, MediaData, Rid};
+use tokio::sync::{
+    broadcast::{self, error::TryRecvError},
+    mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+    watch,
+};
+use tracing::{debug, error, info, warn};
+
+/// 每个流缓存的媒体数据个数, 观众落后更多时丢弃并重新从关键帧开始
+const MEDIA_CAPACITY: usize = 512;
+
+/// 一个推流端的流, 由观看同一个流的观众共享
+#[derive(Clone)]
+pub struct Broadcast {
+    media: broadcast::Sender<Arc<MediaData>>, // 推流端收到的媒体数据, 每个观众各自转发
+    keyframe: UnboundedSender<KeyframeRequestKind>, // 观众的关键帧请求, 由推流端的会话转发给推流端
+    sent: Arc<watch::Sender<()>>,             // 每次发送媒体数据后变化, 唤醒等待 socket 的观众
+}
+
+impl Broadcast {
+    pub fn new() -> (Self, UnboundedReceiver<KeyframeRequestKind>) {
+        let (keyframe, keyframe_rx) = unbounded_channel();
+        let broadcast = Self {
+            media: broadcast::channel(MEDIA_CAPACITY).0,
+            keyframe,
+            sent: Arc::new(watch::channel(()).0),
+        };
+        (broadcast, keyframe_rx)
+    }
+
+    /// 一个新的观众
+    /// * 观众不持有媒体数据的发送端, 推流端离开后观众的接收端随之关闭
+    pub fn subscribe(
+        &self,
+    ) -> (
+        broadcast::Receiver<Arc<MediaData>>,
+        watch::Receiver<()>,
+        UnboundedSender<KeyframeRequestKind>,
+    ) {
+        (
+            self.media.subscribe(),
+            self.sent.subscribe(),
+            self.keyframe.clone(),
+        )
+    }
+}
+
+/// 接收推流端的媒体数据交给观众, 并把观众的关键帧请求转发给推流端, 直到会话结束
+/// * 观众收到的媒体数据不带 rid, 推流端使用 simulcast 时只转发第一个收到的层
+pub async fn publish(
+    mut client: Client,
+    broadcast: Broadcast,
+    mut keyframe_rx: UnboundedReceiver<KeyframeRequestKind>,
+    mut shutdown: watch::Receiver<bool>,
+) -> SessionEnd {
+    let mut layer: Option<Rid> = None;
+    let mut connected = false;
+
+    loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            _ = shutdown.changed() => {
+                info!("shutdown");
+                return SessionEnd::Stopped;
+            }
+        };
+
+        match event {
+            Ok(WebrtcEvent::Connected) => connected = true,
+            Ok(WebrtcEvent::Media(media)) => {
+                // 没有观众时发送失败, 直接丢弃
+                if media.rid.is_none_or(|rid| *layer.get_or_insert(rid) == rid)
+                    && broadcast.media.send(Arc::new(media)).is_ok()
+                {
+                    broadcast.sent.send_replace(());
+                }
+            }
+            Ok(WebrtcEvent::Disconnected) => {
+                info!("publisher disconnected");
+                return SessionEnd::Lost { connected };
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("error: {:?}", err);
+                return SessionEnd::Lost { connected };
+            }
+        }
+
+        // 多个观众同时请求时只需要一个关键帧
+        let mut request = None;
+        while let Ok(kind) = keyframe_rx.try_recv() {
+            request = Some(kind);
+        }
+        if let Some(kind) = request.filter(|_| connected) {
+            if let Err(err) = client.request_keyframe(layer, kind) {
+                debug!("request keyframe error: {:?}", err);
+            }
+        }
+    }
+}
+
+/// 把推流端的媒体数据转发给一个观众, 直到会话结束或者推流端离开
+/// * 推流端发送媒体数据后通过 sent 唤醒 recv, 媒体数据在两次 recv 之间转发
+pub async fn view(
+    mut client: Client,
+    mut media_rx: broadcast::Receiver<Arc<MediaData>>,
+    sent: watch::Receiver<()>,
+    keyframe: UnboundedSender<KeyframeRequestKind>,
+    mut shutdown: watch::Receiver<bool>,
+) -> SessionEnd {
+    let mut connected = false;
+    client.wake_on(sent);
+
+    loop {
+        // recv 不能被取消, 只和 shutdown 一起等待, 取消后会话随之结束
+        let event = tokio::select! {
+            event = client.recv() => event,
+            _ = shutdown.changed() => {
+                info!("shutdown");
+                return SessionEnd::Stopped;
+            }
+        };
+
+        match event {
+            Ok(WebrtcEvent::Connected) => {
+                connected = true;
+                // 新的观众要从关键帧开始解码
+                let _ = keyframe.send(KeyframeRequestKind::Pli);
+            }
+            Ok(WebrtcEvent::KeyframeRequest(request)) => {
+                let _ = keyframe.send(request.kind);
+            }
+            Ok(WebrtcEvent::Disconnected) => {
+                info!("viewer disconnected");
+                return SessionEnd::Lost { connected };
+            }
+            Ok(_) => {}
+            Err(err) => {
+                error!("error: {:?}", err);
+                return SessionEnd::Lost { connected };
+            }
+        }
+
+        loop {
+            match media_rx.try_recv() {
+                Ok(media) if connected => match client.forward(&media) {
+                    Ok(true) => {}
+                    Ok(false) => debug!("codec {:?} not negotiated with the viewer", media.params),
+                    Err(err) => debug!("forward error: {:?}", err),
+                },
+                Ok(_) => {}
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(skipped)) => {
+                    warn!("viewer lagged behind, {} media dropped", skipped);
+                    let _ = keyframe.send(KeyframeRequestKind::Pli);
+                }
+                Err(TryRecvError::Closed) => {
+                    info!("publisher left");
+                    return SessionEnd::Stopped;
+                }
+            }
+        }
+    }
+}
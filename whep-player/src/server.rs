@@ -0,0 +1,1146 @@
+use crate::auth::{self, Auth, AuthError, Grant};
+use crate::client::{ChannelMessage, Client, ClientConfig, SDP_FRAG_CONTENT_TYPE, parse_sdp_frag};
+use crate::restream::{self, Broadcast};
+use crate::stats::Stats;
+use crate::whip::{self, DataChannel};
+use anyhow::Result;
+use axum::{
+    Router,
+    extract::{Path, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{patch, post},
+};
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+use str0m::{Candidate, channel::ChannelData};
+use tokio::sync::{
+    mpsc::{UnboundedSender, unbounded_channel},
+    watch,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{error, info, warn};
+
+/// 会话资源 URL 的前缀, POST 成功后在 Location 中返回 /session/{id}
+const SESSION_PATH: &str = "/session";
+
+/// WHIP 端点接受的请求类型, 通过 OPTIONS 的 Accept-Post 告诉对端
+const SDP_CONTENT_TYPE: &str = "application/sdp";
+
+/// Accept-Post 头 (RFC 5023 / W3C LDP), http 中没有定义
+const ACCEPT_POST: &str = "accept-post";
+
+/// 一个 WHIP/WHEP 会话 (推流端或者观众)
+struct Session {
+    id: String,
+    grant: Option<Grant>, // 创建会话时使用的 token, PATCH / DELETE 时必须带上同一个
+    etag: String,         // PATCH 时对端通过 If-Match 带上
+    ufrag: Option<String>, // 对端 offer 中的 ice-ufrag, trickle 的片段必须与它一致
+    candidates: UnboundedSender<Candidate>, // 对端 trickle 过来的候选者, 交给会话的 Client
+    input: Option<UnboundedSender<ChannelMessage>>, // 转发给这个推流端的输入事件
+    shutdown: watch::Sender<bool>, // DELETE 或者退出时结束会话
+}
+
+/// 会话收到的媒体交给谁
+#[derive(Clone)]
+enum Sink {
+    /// 解码后在播放窗口显示
+    Player {
+        tx: mpsc::Sender<ffmpeg_next::frame::Video>,
+        audio_tx: mpsc::Sender<Vec<f32>>,
+        incoming: Option<UnboundedSender<ChannelData>>, // 所有推流端数据通道上收到的消息
+    },
+    /// 不解码, 转发给观看同一个流的 WHEP 观众, 以流的名字为 key
+    Restream(Arc<Mutex<HashMap<String, Broadcast>>>),
+}
+
+/// 创建好的会话, 还没有开始接收
+struct Accepted {
+    client: Client,
+    id: String,
+    etag: String,
+    answer: String,
+    shutdown: watch::Receiver<bool>,
+}
+
+/// WHIP 服务端的会话管理
+/// * 每个会话有自己的资源 URL, 可以通过 DELETE 结束, 通过 PATCH trickle 候选者 (RFC 9725)
+/// * 播放时可以同时有多个推流端, 播放窗口显示最新的会话; 它结束后回到之前仍在推流的会话
+/// * restream 时推流到 /whip/{stream} 的流可以被任意多个观众通过 /whep/{stream} 观看
+/// * 会话的接收循环退出 (ICE 失败, 对端断开, DELETE) 后从列表中移除
+#[derive(Clone)]
+pub struct SessionManager {
+    sessions: Arc<Mutex<Vec<Session>>>, // 按照创建的顺序, 播放时最后一个是正在显示的会话
+    sink: Sink,
+    stats: Stats, // 正在显示的会话的统计, 每个会话的统计在自己的 Stats 中
+    config: ClientConfig,
+    auth: Option<Arc<Auth>>,         // 为空时不做鉴权
+    shutdown: watch::Receiver<bool>, // 退出时结束所有会话, 不再接受新的会话
+}
+
+impl SessionManager {
+    /// 接收推流并在播放窗口显示, data 为空时不向推流端转发输入事件
+    pub fn new(
+        tx: mpsc::Sender<ffmpeg_next::frame::Video>,
+        audio_tx: mpsc::Sender<Vec<f32>>,
+        stats: Stats,
+        config: ClientConfig,
+        data: Option<DataChannel>,
+        auth: Option<Auth>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let sink = Sink::Player {
+            tx,
+            audio_tx,
+            incoming: data.as_ref().map(|data| data.incoming.clone()),
+        };
+        let manager = Self::with_sink(sink, stats, config, auth, shutdown);
+
+        // 播放端的输入事件只发给正在显示的会话
+        if let Some(mut data) = data {
+            let sessions = manager.sessions.clone();
+            tokio::spawn(async move {
+                while let Some(message) = data.outgoing.recv().await {
+                    let sessions = sessions.lock().unwrap();
+                    if let Some(input) = sessions.last().and_then(|s| s.input.as_ref()) {
+                        let _ = input.send(message);
+                    }
+                }
+            });
+        }
+
+        manager
+    }
+
+    /// 把推流原样转发给观众, 不解码也不显示
+    pub fn restream(
+        stats: Stats,
+        config: ClientConfig,
+        auth: Option<Auth>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let sink = Sink::Restream(Arc::new(Mutex::new(HashMap::new())));
+        Self::with_sink(sink, stats, config, auth, shutdown)
+    }
+
+    fn with_sink(
+        sink: Sink,
+        stats: Stats,
+        config: ClientConfig,
+        auth: Option<Auth>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        let manager = Self {
+            sessions: Arc::new(Mutex::new(vec![])),
+            sink,
+            stats,
+            config,
+            auth: auth.map(Arc::new),
+            shutdown: shutdown.clone(),
+        };
+
+        let sessions = manager.sessions.clone();
+        let mut shutdown = shutdown;
+        tokio::spawn(async move {
+            if shutdown.wait_for(|shutdown| *shutdown).await.is_ok() {
+                for session in sessions.lock().unwrap().iter() {
+                    session.shutdown.send_replace(true);
+                }
+            }
+        });
+
+        manager
+    }
+
+    /// allow_origins 为空时允许任意网页推流
+    pub fn router(self, allow_origins: &[String]) -> Result<Router> {
+        let router = match self.sink {
+            Sink::Player { .. } => Router::new().route("/", post(create_session)),
+            Sink::Restream(_) => Router::new()
+                .route("/whip/:stream", post(publish_stream))
+                .route("/whep/:stream", post(view_stream)),
+        };
+
+        Ok(router
+            .route(
+                &format!("{}/:id", SESSION_PATH),
+                patch(trickle_session).delete(delete_session),
+            )
+            .layer(cors(allow_origins)?)
+            .layer(middleware::from_fn(accept_post))
+            .with_state(self))
+    }
+
+    /// 创建 Client 接受对端的 offer, 并登记会话
+    async fn accept(
+        &self,
+        offer: String,
+        grant: Option<Grant>,
+        input: Option<UnboundedSender<ChannelMessage>>,
+    ) -> Result<Accepted, Response> {
+        if *self.shutdown.borrow() {
+            return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+        }
+        let mut client = Client::new(&self.config).await.map_err(|err| {
+            error!("create client error: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?;
+        let answer = client.accept_whip_request(offer.clone()).map_err(|err| {
+            warn!("accept offer error: {:?}", err);
+            (StatusCode::BAD_REQUEST, "invalid offer").into_response()
+        })?;
+
+        let id = format!("{:016x}", rand::random::<u64>());
+        let etag = format!("\"{:016x}\"", rand::random::<u64>());
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let session = Session {
+            id: id.clone(),
+            grant,
+            etag: etag.clone(),
+            ufrag: parse_sdp_frag(&offer).0.map(|creds| creds.ufrag),
+            candidates: client.remote_candidates(),
+            input,
+            shutdown: shutdown_tx,
+        };
+        self.sessions.lock().unwrap().push(session);
+        info!("session {} created", id);
+
+        Ok(Accepted {
+            client,
+            id,
+            etag,
+            answer,
+            shutdown: shutdown_rx,
+        })
+    }
+
+    /// 接受推流并在播放窗口显示, 返回会话的 id, ETag 和 answer
+    async fn create(
+        &self,
+        offer: String,
+        grant: Option<Grant>,
+    ) -> Result<(String, String, String), Response> {
+        let Sink::Player {
+            tx,
+            audio_tx,
+            incoming,
+        } = &self.sink
+        else {
+            return Err(StatusCode::NOT_FOUND.into_response());
+        };
+        let (data, input) = match incoming {
+            Some(incoming) => {
+                let (input, outgoing) = unbounded_channel();
+                let data = DataChannel {
+                    incoming: incoming.clone(),
+                    outgoing,
+                };
+                (Some(data), Some(input))
+            }
+            None => (None, None),
+        };
+        let Accepted {
+            client,
+            id,
+            etag,
+            answer,
+            shutdown,
+        } = self.accept(offer, grant, input).await?;
+
+        // 每个会话解码到自己的通道, 只有正在显示的会话的帧交给播放窗口
+        let (session_tx, rx) = mpsc::channel();
+        let (session_audio_tx, audio_rx) = mpsc::channel();
+        self.forward(&id, rx, tx.clone());
+        self.forward(&id, audio_rx, audio_tx.clone());
+
+        let stats = Stats::new();
+        self.show_stats(&id, &stats);
+
+        let manager = self.clone();
+        let session_id = id.clone();
+        tokio::spawn(async move {
+            let end = whip::subscribe_as_server(
+                client,
+                session_tx,
+                session_audio_tx,
+                stats,
+                &manager.config,
+                data,
+                shutdown,
+            )
+            .await;
+            info!("session {} ended: {:?}", session_id, end);
+            manager.remove(&session_id);
+        });
+
+        Ok((id, etag, answer))
+    }
+
+    /// 接受推流到 stream 的推流端, 同一个流同时只能有一个推流端
+    async fn publish(
+        &self,
+        stream: String,
+        offer: String,
+        grant: Option<Grant>,
+    ) -> Result<(String, String, String), Response> {
+        let Sink::Restream(streams) = &self.sink else {
+            return Err(StatusCode::NOT_FOUND.into_response());
+        };
+        // 先占住流的名字, 接受 offer 失败时再释放
+        let (broadcast, keyframe_rx) = Broadcast::new();
+        match streams.lock().unwrap().entry(stream.clone()) {
+            Entry::Occupied(_) => {
+                return Err((StatusCode::CONFLICT, "stream already published").into_response());
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(broadcast.clone());
+            }
+        }
+        let Accepted {
+            client,
+            id,
+            etag,
+            answer,
+            shutdown,
+        } = match self.accept(offer, grant, None).await {
+            Ok(accepted) => accepted,
+            Err(response) => {
+                streams.lock().unwrap().remove(&stream);
+                return Err(response);
+            }
+        };
+        info!("stream {} published by session {}", stream, id);
+
+        let manager = self.clone();
+        let streams = streams.clone();
+        let session_id = id.clone();
+        tokio::spawn(async move {
+            let end = restream::publish(client, broadcast, keyframe_rx, shutdown).await;
+            info!("stream {} session {} ended: {:?}", stream, session_id, end);
+            // 流的发送端都释放后观众随之结束
+            streams.lock().unwrap().remove(&stream);
+            manager.remove(&session_id);
+        });
+
+        Ok((id, etag, answer))
+    }
+
+    /// 接受观看 stream 的观众
+    async fn view(
+        &self,
+        stream: String,
+        offer: String,
+        grant: Option<Grant>,
+    ) -> Result<(String, String, String), Response> {
+        let Sink::Restream(streams) = &self.sink else {
+            return Err(StatusCode::NOT_FOUND.into_response());
+        };
+        let Some((media_rx, sent, keyframe)) =
+            streams.lock().unwrap().get(&stream).map(|b| b.subscribe())
+        else {
+            return Err((StatusCode::NOT_FOUND, "stream not published").into_response());
+        };
+        let Accepted {
+            client,
+            id,
+            etag,
+            answer,
+            shutdown,
+        } = self.accept(offer, grant, None).await?;
+        info!("stream {} viewed by session {}", stream, id);
+
+        let manager = self.clone();
+        let session_id = id.clone();
+        tokio::spawn(async move {
+            let end = restream::view(client, media_rx, sent, keyframe, shutdown).await;
+            info!("stream {} session {} ended: {:?}", stream, session_id, end);
+            manager.remove(&session_id);
+        });
+
+        Ok((id, etag, answer))
+    }
+
+    /// 在单独的线程中把会话的帧转发给播放窗口
+    /// * 播放窗口关闭后停止接收, 会话的发送失败后随之退出
+    fn forward<T: Send + 'static>(&self, id: &str, rx: mpsc::Receiver<T>, tx: mpsc::Sender<T>) {
+        let sessions = self.sessions.clone();
+        let id = id.to_string();
+        thread::spawn(move || {
+            while let Ok(item) = rx.recv() {
+                let displayed = sessions.lock().unwrap().last().is_some_and(|s| s.id == id);
+                if displayed && tx.send(item).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// 每个会话有自己的统计, 正在显示的会话的统计同步到播放器和统计文件使用的 Stats
+    /// * 会话结束, Stats 被释放后退出
+    fn show_stats(&self, id: &str, stats: &Stats) {
+        let sessions = self.sessions.clone();
+        let shown = self.stats.clone();
+        let id = id.to_string();
+        let mut rx = stats.subscribe();
+        tokio::spawn(async move {
+            while rx.changed().await.is_ok() {
+                let displayed = sessions.lock().unwrap().last().is_some_and(|s| s.id == id);
+                if displayed {
+                    shown.show_session(&rx.borrow_and_update());
+                }
+            }
+        });
+    }
+
+    fn remove(&self, id: &str) -> Option<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let index = sessions.iter().position(|s| s.id == id)?;
+        Some(sessions.remove(index))
+    }
+}
+
+async fn create_session(
+    State(manager): State<SessionManager>,
+    uri: Uri,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    match check_offer(&manager, &uri, &headers) {
+        Ok(grant) => created(&manager, manager.create(offer, grant).await),
+        Err(response) => response,
+    }
+}
+
+async fn publish_stream(
+    State(manager): State<SessionManager>,
+    Path(stream): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    match check_offer(&manager, &uri, &headers) {
+        Ok(grant) => created(&manager, manager.publish(stream, offer, grant).await),
+        Err(response) => response,
+    }
+}
+
+async fn view_stream(
+    State(manager): State<SessionManager>,
+    Path(stream): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    offer: String,
+) -> Response {
+    match check_offer(&manager, &uri, &headers) {
+        Ok(grant) => created(&manager, manager.view(stream, offer, grant).await),
+        Err(response) => response,
+    }
+}
+
+/// 检查创建会话的请求的鉴权和类型, 返回请求使用的 token
+fn check_offer(
+    manager: &SessionManager,
+    uri: &Uri,
+    headers: &HeaderMap,
+) -> Result<Option<Grant>, Response> {
+    let grant = match &manager.auth {
+        Some(auth) => Some(auth.authorize(headers, uri.path()).map_err(|err| {
+            warn!("unauthorized request to {}: {:?}", uri.path(), err);
+            err.into_response()
+        })?),
+        None => None,
+    };
+    if !content_type_is(headers, SDP_CONTENT_TYPE) {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            [(ACCEPT_POST, SDP_CONTENT_TYPE)],
+        )
+            .into_response());
+    }
+
+    Ok(grant)
+}
+
+/// 会话创建成功时返回 answer 和会话的资源 URL
+fn created(
+    manager: &SessionManager,
+    result: Result<(String, String, String), Response>,
+) -> Response {
+    let (id, etag, answer) = match result {
+        Ok(session) => session,
+        Err(response) => return response,
+    };
+
+    let mut response = (
+        StatusCode::CREATED,
+        [
+            (header::CONTENT_TYPE, SDP_CONTENT_TYPE.to_string()),
+            (header::LOCATION, format!("{}/{}", SESSION_PATH, id)),
+            (header::ETAG, etag),
+        ],
+        answer,
+    )
+        .into_response();
+    // 把服务端使用的 STUN/TURN 服务器告诉对端
+    for server in &manager.config.ice_servers {
+        if let Ok(link) = HeaderValue::from_str(&server.to_link()) {
+            response.headers_mut().append(header::LINK, link);
+        }
+    }
+    response
+}
+
+/// 对 WHIP 端点的 OPTIONS 请求, 返回 WHIP 端点接受的请求类型
+/// * CorsLayer 直接响应所有 OPTIONS 请求 (包括不是预检的), 请求到不了路由, 只能在它的响应上添加
+/// * STUN/TURN 服务器的凭据只在创建会话后下发, 不在这里返回
+async fn accept_post(request: Request, next: Next) -> Response {
+    let whip_options =
+        request.method() == Method::OPTIONS && !request.uri().path().starts_with(SESSION_PATH);
+    let mut response = next.run(request).await;
+    if whip_options {
+        response.headers_mut().insert(
+            HeaderName::from_static(ACCEPT_POST),
+            HeaderValue::from_static(SDP_CONTENT_TYPE),
+        );
+    }
+    response
+}
+
+async fn delete_session(
+    State(manager): State<SessionManager>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    {
+        let sessions = manager.sessions.lock().unwrap();
+        let Some(session) = sessions.iter().find(|s| s.id == id) else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        if let Err(err) = check_token(session, &headers) {
+            return err.into_response();
+        }
+    }
+    let Some(session) = manager.remove(&id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    info!("session {} deleted", id);
+    session.shutdown.send_replace(true);
+    StatusCode::OK.into_response()
+}
+
+/// Trickle ICE: 把对端的候选者交给会话
+/// * 不支持 ICE restart, 对端会在连接断开后重新发起 WHIP 请求
+async fn trickle_session(
+    State(manager): State<SessionManager>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    frag: String,
+) -> Response {
+    let sessions = manager.sessions.lock().unwrap();
+    let Some(session) = sessions.iter().find(|s| s.id == id) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Err(err) = check_token(session, &headers) {
+        return err.into_response();
+    }
+    if !content_type_is(&headers, SDP_FRAG_CONTENT_TYPE) {
+        return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response();
+    }
+    let if_match = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok());
+    if if_match.is_some_and(|v| v != "*" && v != session.etag) {
+        return StatusCode::PRECONDITION_FAILED.into_response();
+    }
+
+    let (creds, candidates) = parse_sdp_frag(&frag);
+    if creds.is_some_and(|creds| Some(creds.ufrag) != session.ufrag) {
+        warn!("session {} ice restart not supported", id);
+        return StatusCode::NOT_IMPLEMENTED.into_response();
+    }
+    for candidate in candidates {
+        let _ = session.candidates.send(candidate);
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// 让网页中的 WHIP 客户端 (浏览器) 可以跨域推流
+/// * 预检请求由 CorsLayer 直接响应, 响应中的 Location / ETag / Link 需要暴露给网页
+fn cors(allow_origins: &[String]) -> Result<CorsLayer> {
+    let allow_origin = match allow_origins {
+        [] => AllowOrigin::any(),
+        origins => AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin))
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::POST, Method::PATCH, Method::DELETE, Method::OPTIONS])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::IF_MATCH,
+        ])
+        .expose_headers([
+            header::LOCATION,
+            header::ETAG,
+            header::LINK,
+            HeaderName::from_static(ACCEPT_POST),
+        ]))
+}
+
+/// 对会话资源的请求必须带上创建会话时的 token
+fn check_token(session: &Session, headers: &HeaderMap) -> Result<(), AuthError> {
+    let Some(expected) = session.grant.as_ref().map(Grant::token) else {
+        return Ok(());
+    };
+    match auth::bearer(headers) {
+        None => Err(AuthError::Missing),
+        Some(token) if auth::constant_time_eq(token, expected) => Ok(()),
+        Some(_) => Err(AuthError::Forbidden("token doesn't own the session")),
+    }
+}
+
+/// Content-Type 是否为 mime (忽略参数), 没有 Content-Type 时也接受
+fn content_type_is(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| {
+            v.split(';')
+                .next()
+                .is_some_and(|v| v.trim().eq_ignore_ascii_case(mime))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::WebrtcEvent;
+    use crate::ice_server::IceServer;
+    use axum::{body::Body, http::Request};
+    use bytes::Bytes;
+    use std::{
+        collections::HashSet,
+        time::{Duration, Instant},
+    };
+    use str0m::{
+        Rtc,
+        media::{Direction, KeyframeRequestKind, MediaKind},
+    };
+    use tower::ServiceExt;
+    use whep_player::AuthArgs;
+
+    /// 只使用回环地址, 测试不依赖机器上的网卡
+    fn config() -> ClientConfig {
+        ClientConfig {
+            include_loopback: true,
+            allow_interfaces: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        }
+    }
+
+    fn restream(auth: Option<Auth>) -> (SessionManager, Router, watch::Sender<bool>) {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let manager = SessionManager::restream(Stats::new(), config(), auth, shutdown_rx);
+        let router = manager.clone().router(&[]).unwrap();
+        (manager, router, shutdown_tx)
+    }
+
+    fn static_token(token: &str) -> Auth {
+        Auth::new(&AuthArgs {
+            auth_tokens: vec![token.to_string()],
+            jwt_secret: None,
+            jwt_public_key: None,
+        })
+        .unwrap()
+        .unwrap()
+    }
+
+    fn offer(direction: Direction) -> String {
+        let mut rtc = Rtc::new();
+        rtc.add_local_candidate(Candidate::host("127.0.0.1:5000".parse().unwrap(), "udp").unwrap());
+        let mut change = rtc.sdp_api();
+        change.add_media(MediaKind::Video, direction, None, None);
+        let (offer, _) = change.apply().unwrap();
+        offer.to_sdp_string()
+    }
+
+    async fn send(
+        router: &Router,
+        method: Method,
+        path: &str,
+        headers: &[(HeaderName, &str)],
+        body: String,
+    ) -> Response {
+        let mut request = Request::builder().method(method).uri(path);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        router
+            .clone()
+            .oneshot(request.body(Body::from(body)).unwrap())
+            .await
+            .unwrap()
+    }
+
+    async fn publish(router: &Router, stream: &str, token: Option<&str>) -> Response {
+        let bearer = token.map(|token| format!("Bearer {}", token));
+        let mut headers = vec![(header::CONTENT_TYPE, SDP_CONTENT_TYPE)];
+        if let Some(bearer) = &bearer {
+            headers.push((header::AUTHORIZATION, bearer.as_str()));
+        }
+        let path = format!("/whip/{}", stream);
+        send(
+            router,
+            Method::POST,
+            &path,
+            &headers,
+            offer(Direction::SendOnly),
+        )
+        .await
+    }
+
+    fn header_str<'a>(response: &'a Response, name: HeaderName) -> &'a str {
+        response.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_have_unique_location() {
+        let (manager, router, _shutdown) = restream(None);
+
+        let publisher = publish(&router, "live", None).await;
+        assert_eq!(publisher.status(), StatusCode::CREATED);
+        assert_eq!(
+            header_str(&publisher, header::CONTENT_TYPE),
+            SDP_CONTENT_TYPE
+        );
+        let headers = [(header::CONTENT_TYPE, SDP_CONTENT_TYPE)];
+        let viewer = send(
+            &router,
+            Method::POST,
+            "/whep/live",
+            &headers,
+            offer(Direction::RecvOnly),
+        )
+        .await;
+        assert_eq!(viewer.status(), StatusCode::CREATED);
+
+        let (publisher, viewer) = (
+            header_str(&publisher, header::LOCATION),
+            header_str(&viewer, header::LOCATION),
+        );
+        assert!(publisher.starts_with("/session/"));
+        assert!(viewer.starts_with("/session/"));
+        assert_ne!(publisher, viewer);
+        assert_eq!(manager.sessions.lock().unwrap().len(), 2);
+
+        // 同一个流只能有一个推流端, 没有推流的流不能观看
+        assert_eq!(
+            publish(&router, "live", None).await.status(),
+            StatusCode::CONFLICT
+        );
+        let missing = send(
+            &router,
+            Method::POST,
+            "/whep/other",
+            &headers,
+            offer(Direction::RecvOnly),
+        )
+        .await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn wrong_offer_content_type() {
+        let (_manager, router, _shutdown) = restream(None);
+        let headers = [(header::CONTENT_TYPE, "application/json")];
+        let response = send(
+            &router,
+            Method::POST,
+            "/whip/live",
+            &headers,
+            offer(Direction::SendOnly),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            header_str(&response, HeaderName::from_static(ACCEPT_POST)),
+            SDP_CONTENT_TYPE
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_checks_session_token() {
+        let (manager, router, _shutdown) = restream(Some(static_token("owner")));
+        let created = publish(&router, "live", Some("owner")).await;
+        assert_eq!(created.status(), StatusCode::CREATED);
+        let location = header_str(&created, header::LOCATION).to_string();
+
+        let missing = send(&router, Method::DELETE, &location, &[], String::new()).await;
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let wrong = [(header::AUTHORIZATION, "Bearer other")];
+        let wrong = send(&router, Method::DELETE, &location, &wrong, String::new()).await;
+        assert_eq!(wrong.status(), StatusCode::FORBIDDEN);
+        assert_eq!(manager.sessions.lock().unwrap().len(), 1);
+
+        let owner = [(header::AUTHORIZATION, "Bearer owner")];
+        let deleted = send(&router, Method::DELETE, &location, &owner, String::new()).await;
+        assert_eq!(deleted.status(), StatusCode::OK);
+        assert!(manager.sessions.lock().unwrap().is_empty());
+        let again = send(&router, Method::DELETE, &location, &owner, String::new()).await;
+        assert_eq!(again.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn patch_trickles_candidates() {
+        let (_manager, router, _shutdown) = restream(None);
+        let created = publish(&router, "live", None).await;
+        let location = header_str(&created, header::LOCATION).to_string();
+        let etag = header_str(&created, header::ETAG).to_string();
+
+        let candidate = "a=candidate:1 1 udp 2130706431 127.0.0.1 50000 typ host\r\n".to_string();
+        let frag = [(header::CONTENT_TYPE, SDP_FRAG_CONTENT_TYPE)];
+        let trickled = send(&router, Method::PATCH, &location, &frag, candidate.clone()).await;
+        assert_eq!(trickled.status(), StatusCode::NO_CONTENT);
+
+        let matched = [
+            (header::CONTENT_TYPE, SDP_FRAG_CONTENT_TYPE),
+            (header::IF_MATCH, etag.as_str()),
+        ];
+        let matched = send(
+            &router,
+            Method::PATCH,
+            &location,
+            &matched,
+            candidate.clone(),
+        )
+        .await;
+        assert_eq!(matched.status(), StatusCode::NO_CONTENT);
+
+        let stale = [
+            (header::CONTENT_TYPE, SDP_FRAG_CONTENT_TYPE),
+            (header::IF_MATCH, "\"stale\""),
+        ];
+        let stale = send(&router, Method::PATCH, &location, &stale, candidate.clone()).await;
+        assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+
+        let restart = "a=ice-ufrag:changed\r\na=ice-pwd:0123456789abcdef012345\r\n".to_string();
+        let restart = send(&router, Method::PATCH, &location, &frag, restart).await;
+        assert_eq!(restart.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let json = [(header::CONTENT_TYPE, "application/json")];
+        let json = send(&router, Method::PATCH, &location, &json, candidate.clone()).await;
+        assert_eq!(json.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let unknown = send(&router, Method::PATCH, "/session/unknown", &frag, candidate).await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn ended_sessions_are_removed() {
+        let (manager, router, shutdown) = restream(None);
+        assert_eq!(
+            publish(&router, "a", None).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(
+            publish(&router, "b", None).await.status(),
+            StatusCode::CREATED
+        );
+        assert_eq!(manager.sessions.lock().unwrap().len(), 2);
+
+        // 退出时所有会话结束, 会话的任务把自己从列表中移除
+        shutdown.send_replace(true);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !manager.sessions.lock().unwrap().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("sessions removed");
+
+        // 流随推流端一起释放, 退出后不再接受新的会话
+        let Sink::Restream(streams) = &manager.sink else {
+            unreachable!()
+        };
+        assert!(streams.lock().unwrap().is_empty());
+        assert_eq!(
+            publish(&router, "a", None).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    fn preflight(origin: &str) -> [(HeaderName, &str); 3] {
+        [
+            (header::ORIGIN, origin),
+            (header::ACCESS_CONTROL_REQUEST_METHOD, "POST"),
+            (
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "authorization, content-type",
+            ),
+        ]
+    }
+
+    #[tokio::test]
+    async fn preflight_allows_any_origin_by_default() {
+        let (_manager, router, _shutdown) = restream(None);
+        let headers = preflight("https://a.example");
+        let response = send(
+            &router,
+            Method::OPTIONS,
+            "/whip/live",
+            &headers,
+            String::new(),
+        )
+        .await;
+
+        assert!(response.status().is_success());
+        assert_eq!(
+            header_str(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "*"
+        );
+        let methods = header_str(&response, header::ACCESS_CONTROL_ALLOW_METHODS);
+        assert!(
+            methods.contains("POST") && methods.contains("PATCH") && methods.contains("DELETE")
+        );
+        let allowed = header_str(&response, header::ACCESS_CONTROL_ALLOW_HEADERS);
+        assert!(allowed.contains("authorization") && allowed.contains("content-type"));
+    }
+
+    #[tokio::test]
+    async fn preflight_checks_allowed_origins() {
+        let (manager, _router, _shutdown) = restream(None);
+        let router = manager.router(&["https://a.example".to_string()]).unwrap();
+
+        let headers = preflight("https://a.example");
+        let allowed = send(
+            &router,
+            Method::OPTIONS,
+            "/whip/live",
+            &headers,
+            String::new(),
+        )
+        .await;
+        assert_eq!(
+            header_str(&allowed, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            "https://a.example"
+        );
+
+        let headers = preflight("https://b.example");
+        let denied = send(
+            &router,
+            Method::OPTIONS,
+            "/whip/live",
+            &headers,
+            String::new(),
+        )
+        .await;
+        assert!(
+            denied
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn options_returns_accept_post() {
+        let (_manager, router, _shutdown) = restream(None);
+        let response = send(&router, Method::OPTIONS, "/whip/live", &[], String::new()).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            header_str(&response, HeaderName::from_static(ACCEPT_POST)),
+            SDP_CONTENT_TYPE
+        );
+
+        // 预检的响应中也有 Accept-Post, 会话资源不接受 POST
+        let headers = preflight("https://a.example");
+        let response = send(
+            &router,
+            Method::OPTIONS,
+            "/whep/live",
+            &headers,
+            String::new(),
+        )
+        .await;
+        assert_eq!(
+            header_str(&response, HeaderName::from_static(ACCEPT_POST)),
+            SDP_CONTENT_TYPE
+        );
+        let response = send(&router, Method::OPTIONS, "/session/1", &[], String::new()).await;
+        assert!(response.headers().get(ACCEPT_POST).is_none());
+    }
+
+    #[tokio::test]
+    async fn created_exposes_session_headers() {
+        let (_manager, router, _shutdown) = restream(None);
+        let headers = [
+            (header::ORIGIN, "https://a.example"),
+            (header::CONTENT_TYPE, SDP_CONTENT_TYPE),
+        ];
+        let response = send(
+            &router,
+            Method::POST,
+            "/whip/live",
+            &headers,
+            offer(Direction::SendOnly),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        let exposed = header_str(&response, header::ACCESS_CONTROL_EXPOSE_HEADERS);
+        let exposed: Vec<&str> = exposed.split(',').map(|name| name.trim()).collect();
+        for name in ["location", "etag", "link", ACCEPT_POST] {
+            assert!(
+                exposed.contains(&name),
+                "{} not exposed: {:?}",
+                name,
+                exposed
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn created_links_ice_servers() {
+        let (_shutdown, shutdown_rx) = watch::channel(false);
+        let config = ClientConfig {
+            ice_servers: vec![
+                IceServer::parse("stun:stun.example.com", None, None).unwrap(),
+                IceServer::parse(
+                    "turn:turn.example.com:3478?transport=udp",
+                    Some("user".to_string()),
+                    Some("pa\"ss".to_string()),
+                )
+                .unwrap(),
+            ],
+            ..config()
+        };
+        let manager = SessionManager::restream(Stats::new(), config, None, shutdown_rx);
+        let session = ("1".to_string(), "\"e\"".to_string(), "v=0".to_string());
+        let response = created(&manager, Ok(session));
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(header_str(&response, header::LOCATION), "/session/1");
+        assert_eq!(header_str(&response, header::ETAG), "\"e\"");
+        let links: Vec<&str> = response
+            .headers()
+            .get_all(header::LINK)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                "<stun:stun.example.com>; rel=\"ice-server\"",
+                "<turn:turn.example.com:3478?transport=udp>; rel=\"ice-server\"; \
+                 username=\"user\"; credential=\"pa\\\"ss\"; credential-type=\"password\"",
+            ]
+        );
+    }
+
+    /// 推流端发送的一帧 H264: SPS, PPS 和 IDR
+    const FRAME: &[u8] = &[
+        0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1f, 0xda, 0x01, 0x40, 0x16, 0xe8, 0x06, 0xd0, 0xa1, 0x35,
+        0, 0, 0, 1, 0x68, 0xce, 0x06, 0xe2, 0, 0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x33, 0xff,
+    ];
+    const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+    #[tokio::test]
+    async fn restream_fans_out_and_relays_keyframe_requests() {
+        let (_manager, router, _shutdown) = restream(None);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        // 推流端: 按帧率发送, 通过 wake 在两次 recv 之间发送, 收到的关键帧请求交给 keyframe_rx
+        let mut publisher = Client::new(&config()).await.unwrap();
+        publisher
+            .send_whip_request(&format!("{}/whip/live", url), &None, Direction::SendOnly)
+            .await
+            .unwrap();
+        let (tick, wake) = watch::channel(());
+        publisher.wake_on(wake);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FRAME_INTERVAL);
+            while tick.send(()).is_ok() {
+                interval.tick().await;
+            }
+        });
+        let (keyframe_tx, mut keyframe_rx) = unbounded_channel();
+        let publisher = tokio::spawn(async move {
+            let mut connected = false;
+            let mut sent = Instant::now();
+            let mut pts = Duration::ZERO;
+            while let Ok(event) = publisher.recv().await {
+                match event {
+                    WebrtcEvent::Connected => connected = true,
+                    WebrtcEvent::KeyframeRequest(request) => {
+                        let _ = keyframe_tx.send(request.kind);
+                    }
+                    _ => {}
+                }
+                if connected && sent.elapsed() >= FRAME_INTERVAL {
+                    sent = Instant::now();
+                    pts += FRAME_INTERVAL;
+                    publisher
+                        .send_video(0, Bytes::from_static(FRAME), pts)
+                        .unwrap();
+                }
+            }
+        });
+
+        // 两个观众观看同一个流, 收到媒体数据后请求关键帧
+        let (media_tx, mut media_rx) = unbounded_channel();
+        let mut viewers = vec![];
+        for index in 0..2 {
+            let mut viewer = Client::new(&config()).await.unwrap();
+            viewer
+                .send_whip_request(&format!("{}/whep/live", url), &None, Direction::RecvOnly)
+                .await
+                .unwrap();
+            let media_tx = media_tx.clone();
+            viewers.push(tokio::spawn(async move {
+                let mut received = false;
+                while let Ok(event) = viewer.recv().await {
+                    if let WebrtcEvent::Media(_) = event
+                        && !received
+                    {
+                        received = true;
+                        let _ = viewer.request_keyframe(None, KeyframeRequestKind::Pli);
+                        let _ = media_tx.send(index);
+                    }
+                }
+            }));
+        }
+
+        let mut received = HashSet::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while received.len() < 2 {
+                received.insert(media_rx.recv().await.unwrap());
+            }
+        })
+        .await
+        .expect("both viewers received media");
+
+        // 观众的关键帧请求经过服务端转发给推流端
+        tokio::time::timeout(Duration::from_secs(10), async {
+            keyframe_rx.recv().await.unwrap();
+        })
+        .await
+        .expect("keyframe request relayed to the publisher");
+
+        publisher.abort();
+        for viewer in viewers {
+            viewer.abort();
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    cmp::Reverse,
+    time::{Duration, Instant},
+};
+use str0m::{
+    media::{Mid, Rid},
+    stats::MediaIngressStats,
+};
+use tracing::info;
+
+/// 推流时各个 simulcast 层的 RID, 从高到低依次为原始分辨率, 1/2 和 1/4
+pub const RIDS: [&str; 3] = ["f", "h", "q"];
+
+/// WHEP layer 扩展 (draft-murillo-whep 的 Layer Selection) 在 Link 头中的 rel
+pub const LAYER_LINK_REL: &str = "urn:ietf:params:whep:ext:core:layer";
+
+/// 丢包率超过这个比例的统计周期视为丢包
+const LOSSY_FRACTION: f32 = 0.05;
+
+/// 丢包率低于这个比例的统计周期视为没有丢包
+const CLEAN_FRACTION: f32 = 0.01;
+
+/// 连续多少个丢包的统计周期 (每个 2 秒) 后降一层
+const STEP_DOWN_PERIODS: u32 = 2;
+
+/// 连续多少个没有丢包的统计周期后升一层
+const STEP_UP_PERIODS: u32 = 5;
+
+/// 超过这个时间没有收到数据的层认为已经停止发送 (比如推流端暂停了这一层)
+const LAYER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 按照 m-line 把 SDP 分成若干段, 第一段是会话级别的属性
+fn sections(sdp: &str) -> Vec<Vec<&str>> {
+    let mut sections: Vec<Vec<&str>> = vec![vec![]];
+    for line in sdp.lines().filter(|l| !l.is_empty()) {
+        if line.starts_with("m=") {
+            sections.push(vec![]);
+        }
+        sections.last_mut().unwrap().push(line);
+    }
+    sections
+}
+
+/// 在 offer 的视频 m-line 中声明发送的 simulcast 层 (RFC 8853)
+/// * 每一层通过 RTP 头扩展中的 rid 区分, 去掉 str0m 生成的 ssrc, 避免 SFU 把它当成唯一的一层
+pub fn with_simulcast(sdp: &str, mid: Mid, rids: &[Rid]) -> String {
+    let mid_line = format!("a=mid:{}", mid);
+    let mut lines: Vec<String> = vec![];
+    for section in sections(sdp) {
+        if !section.contains(&mid_line.as_str()) {
+            lines.extend(section.iter().map(|l| l.to_string()));
+            continue;
+        }
+
+        lines.extend(
+            section
+                .iter()
+                .filter(|l| !l.starts_with("a=ssrc:") && !l.starts_with("a=ssrc-group:"))
+                .map(|l| l.to_string()),
+        );
+        for rid in rids {
+            lines.push(format!("a=rid:{} send", rid));
+        }
+        lines.push(format!(
+            "a=simulcast:send {}",
+            rids.iter()
+                .map(|rid| rid.to_string())
+                .collect::<Vec<_>>()
+                .join(";")
+        ));
+    }
+
+    lines.join("\r\n") + "\r\n"
+}
+
+/// answer 的视频 m-line 是否接受了 simulcast
+pub fn answer_accepts_simulcast(sdp: &str, mid: Mid) -> bool {
+    let mid_line = format!("a=mid:{}", mid);
+    sections(sdp).iter().any(|section| {
+        section.contains(&mid_line.as_str())
+            && section.iter().any(|l| l.starts_with("a=simulcast:"))
+    })
+}
+
+/// 拉流时选择播放的 simulcast 层
+/// * 指定了层时固定使用它, 否则根据视频的丢包率自动切换: 持续丢包时降一层, 持续没有丢包时升一层
+/// * 对端直接发送多个层 (媒体数据带 rid) 时在本地丢弃其他层; 经过 SFU 时通过 WHEP layer 扩展请求 SFU 切换
+/// * 本地选择时可以选的是正在收到的层, 按照码率从高到低排列, 对端的 rid 不一定是 f/h/q;
+///   选择的层没有收到 (不存在或者暂停了) 时换成正在收到的最高的一层
+pub struct LayerSelector {
+    rids: Vec<Rid>,         // 经过 SFU 时可以选择的层, 从高到低
+    fixed: Option<Rid>,     // 指定的层
+    index: usize,           // 选择的是可以选择的层中的第几层, 0 为最高的一层
+    current: Rid,           // 正在播放的层
+    local: Vec<LocalLayer>, // 收到过的带 rid 的层, 按照码率从高到低
+    lossy: u32,             // 连续丢包的统计周期数
+    clean: u32,             // 连续没有丢包的统计周期数
+}
+
+/// 对端直接发送的一层
+struct LocalLayer {
+    rid: Rid,
+    received: Instant,             // 最后一次收到媒体数据的时间
+    bytes: Option<(u64, Instant)>, // 上一次接收统计中的字节数和时间
+    bitrate: u64,                  // bit/s
+}
+
+impl LayerSelector {
+    pub fn new(layer: Option<&str>) -> Self {
+        let fixed = layer.map(Rid::from);
+        let rids = match fixed {
+            Some(rid) => vec![rid],
+            None => RIDS.iter().map(|rid| Rid::from(*rid)).collect(),
+        };
+        Self {
+            current: rids[0],
+            rids,
+            fixed,
+            index: 0,
+            local: vec![],
+            lossy: 0,
+            clean: 0,
+        }
+    }
+
+    pub fn current(&self) -> Rid {
+        self.current
+    }
+
+    /// 是否指定了播放的层
+    pub fn fixed(&self) -> Option<Rid> {
+        self.fixed
+    }
+
+    /// 本地选择时的层, 请求关键帧时需要带上
+    pub fn local_rid(&self) -> Option<Rid> {
+        (!self.local.is_empty()).then_some(self.current)
+    }
+
+    /// 是否解码这个层的媒体数据
+    pub fn accept(&mut self, rid: Option<Rid>) -> bool {
+        self.accept_at(rid, Instant::now())
+    }
+
+    fn accept_at(&mut self, rid: Option<Rid>, now: Instant) -> bool {
+        let Some(rid) = rid else {
+            return true;
+        };
+        let seen = match self.local.iter_mut().find(|layer| layer.rid == rid) {
+            Some(layer) => {
+                layer.received = now;
+                true
+            }
+            None => {
+                info!("simulcast layer seen: {}", rid);
+                self.local.push(LocalLayer {
+                    rid,
+                    received: now,
+                    bytes: None,
+                    bitrate: 0,
+                });
+                self.sort();
+                false
+            }
+        };
+        // 新的一层, 正在播放的层停止了, 或者指定的层恢复了, 都需要重新选择
+        let stale = !self.local.iter().any(|layer| {
+            layer.rid == self.current
+                && now.saturating_duration_since(layer.received) < LAYER_TIMEOUT
+        });
+        if !seen || stale || (self.fixed == Some(rid) && rid != self.current) {
+            self.select(now);
+        }
+        rid == self.current
+    }
+
+    /// 根据视频的接收统计调整选择的层, 返回切换后的层
+    pub fn ingress(&mut self, stats: &MediaIngressStats) -> Option<Rid> {
+        if let Some(rid) = stats.rid {
+            self.received_bytes(rid, stats.bytes, stats.timestamp);
+        }
+        self.report(stats.rid, stats.loss, Instant::now())
+    }
+
+    /// 根据接收统计中的字节数更新这一层的码率, 重新按照码率排列
+    fn received_bytes(&mut self, rid: Rid, bytes: u64, timestamp: Instant) {
+        let Some(layer) = self.local.iter_mut().find(|layer| layer.rid == rid) else {
+            return;
+        };
+        if let Some((last_bytes, last)) = layer.bytes {
+            let elapsed = timestamp.saturating_duration_since(last).as_secs_f64();
+            if elapsed > 0.0 {
+                layer.bitrate = (bytes.saturating_sub(last_bytes) as f64 * 8.0 / elapsed) as u64;
+            }
+        }
+        layer.bytes = Some((bytes, timestamp));
+        self.sort();
+    }
+
+    /// 按照码率从高到低排列, 还不知道码率时 f/h/q 按照 RIDS 的顺序, 其他的按照收到的顺序
+    fn sort(&mut self) {
+        self.local.sort_by_key(|layer| {
+            let known = RIDS.iter().position(|rid| Rid::from(*rid) == layer.rid);
+            (Reverse(layer.bitrate), known.unwrap_or(RIDS.len()))
+        });
+    }
+
+    /// 某一层 (rid 为空时为唯一的一层) 一个统计周期的丢包率
+    fn report(&mut self, rid: Option<Rid>, loss: Option<f32>, now: Instant) -> Option<Rid> {
+        let previous = self.current;
+        self.select(now);
+        if self.fixed.is_some() || rid.is_some_and(|rid| rid != self.current) {
+            return (self.current != previous).then_some(self.current);
+        }
+        let Some(loss) = loss else {
+            return (self.current != previous).then_some(self.current);
+        };
+
+        let layers = self.layers(now).len();
+        if loss > LOSSY_FRACTION {
+            self.clean = 0;
+            self.lossy += 1;
+            if self.lossy >= STEP_DOWN_PERIODS && self.index + 1 < layers {
+                self.switch(self.index + 1, now);
+                info!(
+                    "packet loss {:.1}%, switch down to layer {}",
+                    loss * 100.0,
+                    self.current
+                );
+            }
+        } else if loss < CLEAN_FRACTION {
+            self.lossy = 0;
+            self.clean += 1;
+            if self.clean >= STEP_UP_PERIODS && self.index > 0 {
+                self.switch(self.index - 1, now);
+                info!("no packet loss, switch up to layer {}", self.current);
+            }
+        } else {
+            self.lossy = 0;
+            self.clean = 0;
+        }
+
+        (self.current != previous).then_some(self.current)
+    }
+
+    /// 可以选择的层, 从高到低
+    /// * 本地选择时为正在收到的层, 还没有收到过带 rid 的数据时为请求 SFU 转发的层
+    fn layers(&self, now: Instant) -> Vec<Rid> {
+        if self.local.is_empty() {
+            return self.rids.clone();
+        }
+        self.local
+            .iter()
+            .filter(|layer| now.saturating_duration_since(layer.received) < LAYER_TIMEOUT)
+            .map(|layer| layer.rid)
+            .collect()
+    }
+
+    /// 按照 index 重新选择播放的层
+    /// * 指定的层没有收到时, 或者层数变少时, 换成正在收到的层中最高 (或者最低) 的一层
+    fn select(&mut self, now: Instant) {
+        let layers = self.layers(now);
+        if layers.is_empty() {
+            return;
+        }
+        self.index = self.index.min(layers.len() - 1);
+        let rid = match self.fixed {
+            Some(rid) if layers.contains(&rid) => rid,
+            _ => layers[self.index],
+        };
+        if rid != self.current {
+            info!("simulcast layer {} selected", rid);
+            self.current = rid;
+        }
+    }
+
+    fn switch(&mut self, index: usize, now: Instant) {
+        self.index = index;
+        self.lossy = 0;
+        self.clean = 0;
+        self.select(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFER: &str = "v=0\r\n\
+        o=- 1 2 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 111\r\n\
+        a=mid:0\r\n\
+        a=ssrc:1111 cname:audio\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96 97\r\n\
+        a=mid:1\r\n\
+        a=rtpmap:96 H264/90000\r\n\
+        a=ssrc-group:FID 2222 3333\r\n\
+        a=ssrc:2222 cname:video\r\n\
+        a=ssrc:3333 cname:video\r\n";
+
+    fn rids() -> Vec<Rid> {
+        RIDS.iter().map(|rid| Rid::from(*rid)).collect()
+    }
+
+    #[test]
+    fn with_simulcast_declares_layers() {
+        let sdp = with_simulcast(OFFER, Mid::from("1"), &rids());
+        let sections = sections(&sdp);
+        assert_eq!(sections.len(), 3);
+
+        // 其他 m-line 不变
+        assert_eq!(
+            sections[1],
+            vec![
+                "m=audio 9 UDP/TLS/RTP/SAVPF 111",
+                "a=mid:0",
+                "a=ssrc:1111 cname:audio"
+            ]
+        );
+        assert_eq!(
+            sections[2],
+            vec![
+                "m=video 9 UDP/TLS/RTP/SAVPF 96 97",
+                "a=mid:1",
+                "a=rtpmap:96 H264/90000",
+                "a=rid:f send",
+                "a=rid:h send",
+                "a=rid:q send",
+                "a=simulcast:send f;h;q",
+            ]
+        );
+        assert!(sdp.ends_with("a=simulcast:send f;h;q\r\n"));
+        assert!(!answer_accepts_simulcast(OFFER, Mid::from("1")));
+        assert!(answer_accepts_simulcast(&sdp, Mid::from("1")));
+        assert!(!answer_accepts_simulcast(&sdp, Mid::from("0")));
+    }
+
+    #[test]
+    fn fixed_layer() {
+        let now = Instant::now();
+        let mut layers = LayerSelector::new(Some("h"));
+        assert_eq!(layers.fixed(), Some(Rid::from("h")));
+        assert_eq!(layers.local_rid(), None);
+        for _ in 0..10 {
+            assert_eq!(layers.report(None, Some(0.5), now), None);
+        }
+        assert_eq!(layers.current(), Rid::from("h"));
+
+        assert!(layers.accept_at(None, now));
+        assert!(layers.accept_at(Some(Rid::from("h")), now));
+        assert!(!layers.accept_at(Some(Rid::from("f")), now));
+        assert_eq!(layers.local_rid(), Some(Rid::from("h")));
+
+        // 指定的层暂停时播放最高的一层, 恢复后换回来
+        let later = now + Duration::from_millis(1500);
+        assert!(layers.accept_at(Some(Rid::from("f")), later));
+        assert_eq!(layers.current(), Rid::from("f"));
+        assert!(layers.accept_at(Some(Rid::from("h")), later));
+        assert!(!layers.accept_at(Some(Rid::from("f")), later));
+    }
+
+    #[test]
+    fn switch_by_packet_loss() {
+        let now = Instant::now();
+        let mut layers = LayerSelector::new(None);
+        assert_eq!(layers.fixed(), None);
+        assert_eq!(layers.current(), Rid::from("f"));
+
+        // 连续两个周期丢包后降一层, 中间的丢包率重新计数
+        assert_eq!(layers.report(None, Some(0.1), now), None);
+        assert_eq!(layers.report(None, Some(0.03), now), None);
+        assert_eq!(layers.report(None, Some(0.1), now), None);
+        assert_eq!(layers.report(None, Some(0.1), now), Some(Rid::from("h")));
+        // 没有丢包率的周期不计数
+        assert_eq!(layers.report(None, None, now), None);
+        assert_eq!(layers.report(None, Some(0.1), now), None);
+        assert_eq!(layers.report(None, Some(0.1), now), Some(Rid::from("q")));
+        // 已经是最低的一层
+        assert_eq!(layers.report(None, Some(0.1), now), None);
+        assert_eq!(layers.report(None, Some(0.1), now), None);
+
+        // 其他层的统计不影响选择
+        for _ in 0..10 {
+            assert_eq!(layers.report(Some(Rid::from("f")), Some(0.0), now), None);
+        }
+        for _ in 0..4 {
+            assert_eq!(layers.report(None, Some(0.0), now), None);
+        }
+        assert_eq!(layers.report(None, Some(0.0), now), Some(Rid::from("h")));
+    }
+
+    #[test]
+    fn local_switch_between_received_layers() {
+        let now = Instant::now();
+        let mut layers = LayerSelector::new(None);
+        // 先收到低的一层时先播放它, 收到 f 后换成 f
+        assert!(layers.accept_at(Some(Rid::from("q")), now));
+        assert!(layers.accept_at(Some(Rid::from("f")), now));
+        assert!(!layers.accept_at(Some(Rid::from("q")), now));
+        assert_eq!(layers.local_rid(), Some(Rid::from("f")));
+
+        // 没有收到 h, 丢包时直接降到 q
+        let f = Some(Rid::from("f"));
+        assert_eq!(layers.report(f, Some(0.2), now), None);
+        assert_eq!(layers.report(f, Some(0.2), now), Some(Rid::from("q")));
+        assert!(layers.accept_at(Some(Rid::from("q")), now));
+        assert!(!layers.accept_at(Some(Rid::from("f")), now));
+
+        // f 暂停后不能再升到 f
+        let later = now + Duration::from_millis(1500);
+        assert!(layers.accept_at(Some(Rid::from("q")), later));
+        let q = Some(Rid::from("q"));
+        for _ in 0..10 {
+            assert_eq!(layers.report(q, Some(0.0), later), None);
+        }
+        assert_eq!(layers.current(), Rid::from("q"));
+    }
+
+    #[test]
+    fn local_layers_with_other_rids() {
+        let now = Instant::now();
+        let mut layers = LayerSelector::new(None);
+        let (low, mid, high) = (Rid::from("0"), Rid::from("1"), Rid::from("2"));
+
+        // 没有 f 时播放收到的层, 不会丢弃所有的数据
+        assert!(layers.accept_at(Some(low), now));
+        assert!(!layers.accept_at(Some(mid), now));
+        assert!(!layers.accept_at(Some(high), now));
+        assert_eq!(layers.current(), low);
+
+        // 知道码率后按码率排列, 选择最高的一层
+        let later = now + Duration::from_secs(1);
+        for (rid, bitrate) in [(low, 100_000), (mid, 400_000), (high, 1_600_000)] {
+            layers.received_bytes(rid, 0, now);
+            layers.received_bytes(rid, bitrate / 8, later);
+        }
+        assert_eq!(layers.report(Some(low), Some(0.0), now), Some(high));
+        assert!(layers.accept_at(Some(high), now));
+        assert!(!layers.accept_at(Some(low), now));
+
+        // 丢包时逐层降低
+        for rid in [mid, low] {
+            assert_eq!(layers.report(layers.local_rid(), Some(0.2), now), None);
+            assert_eq!(layers.report(layers.local_rid(), Some(0.2), now), Some(rid));
+        }
+
+        // 最高的一层暂停时, 指定它也会播放正在收到的最高的一层
+        let mut fixed = LayerSelector::new(Some("2"));
+        assert!(fixed.accept_at(Some(high), now));
+        assert!(!fixed.accept_at(Some(mid), now));
+        let paused = now + Duration::from_secs(2);
+        assert!(fixed.accept_at(Some(mid), paused));
+        assert!(!fixed.accept_at(Some(low), paused));
+        assert_eq!(fixed.local_rid(), Some(mid));
+    }
+}
@@ -0,0 +1,60 @@
+use super::AudioSource;
+use anyhow::{Result, anyhow};
+use ffmpeg_next::{
+    codec::Context as CodecContext, decoder, device, format, frame, media::Type as MediaType,
+};
+
+/// 通过 libavdevice 采集音频设备
+/// * 设备使用 `<format>:<device>` 表示, 比如 `dshow:audio=Stereo Mix`, `pulse:default`, `alsa:hw:0`
+pub struct DeviceAudio {
+    input: format::context::Input,
+    stream_index: usize,
+    decoder: decoder::Audio,
+}
+
+impl DeviceAudio {
+    pub fn new(spec: &str) -> Result<Self> {
+        let (name, device) = spec
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid audio device {}, expected <format>:<device>", spec))?;
+        let input_format = device::input::audio()
+            .find(|f| f.name() == name)
+            .ok_or_else(|| anyhow!("Missing audio input device format {}", name))?;
+
+        let input = format::open(device, &input_format)?.input();
+        let stream = input
+            .streams()
+            .best(MediaType::Audio)
+            .ok_or_else(|| anyhow!("No audio stream in {}", spec))?;
+        let stream_index = stream.index();
+        let decoder = CodecContext::from_parameters(stream.parameters())?
+            .decoder()
+            .audio()?;
+
+        Ok(Self {
+            input,
+            stream_index,
+            decoder,
+        })
+    }
+}
+
+impl AudioSource for DeviceAudio {
+    fn get_frame(&mut self) -> Result<frame::Audio> {
+        let mut frame = frame::Audio::empty();
+        loop {
+            if self.decoder.receive_frame(&mut frame).is_ok() {
+                return Ok(frame);
+            }
+
+            let (stream, packet) = self
+                .input
+                .packets()
+                .next()
+                .ok_or_else(|| anyhow!("Audio device closed"))?;
+            if stream.index() == self.stream_index {
+                self.decoder.send_packet(&packet)?;
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use super::Source;
-use anyhow::{anyhow, Result};
+use anyhow::{Result, anyhow};
 use ffmpeg_next::{
     filter::{self, Graph},
     frame,
@@ -7,20 +7,38 @@ use ffmpeg_next::{
 
 pub struct DisplayDuplicator {
     graph: Graph,
+    frame_rate: i32,
 }
 
 impl DisplayDuplicator {
-    pub fn new() -> Result<Self> {
+    pub fn new(frame_rate: i32) -> Result<Self> {
+        Ok(Self {
+            graph: Self::graph(frame_rate, 1)?,
+            frame_rate,
+        })
+    }
+
+    /// divisor 大于 1 时把画面下载到内存中缩小, 输出的是软件帧而不是 D3D11 纹理
+    fn graph(frame_rate: i32, divisor: u32) -> Result<Graph> {
         let mut graph = filter::Graph::new();
 
         let buffer_sink = filter::find("buffersink")
             .ok_or_else(|| anyhow!("Failed to find buffersink filter"))?;
 
         graph.add(&buffer_sink, "out", "")?;
-        graph.input("out", 0)?.parse("ddagrab=0:framerate=60")?;
+        if divisor > 1 {
+            graph.input("out", 0)?.parse(&format!(
+                "ddagrab=0:framerate={1},hwdownload,format=bgra,scale=trunc(iw/{0}/2)*2:trunc(ih/{0}/2)*2",
+                divisor, frame_rate
+            ))?;
+        } else {
+            graph
+                .input("out", 0)?
+                .parse(&format!("ddagrab=0:framerate={}", frame_rate))?;
+        }
         graph.validate()?;
 
-        Ok(Self { graph })
+        Ok(graph)
     }
 }
 
@@ -31,4 +49,10 @@ impl Source for DisplayDuplicator {
 
         Ok(frame)
     }
+
+    fn set_downscale(&mut self, divisor: u32) -> Result<bool> {
+        self.graph = Self::graph(self.frame_rate, divisor)?;
+
+        Ok(true)
+    }
 }
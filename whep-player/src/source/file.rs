@@ -0,0 +1,323 @@
+use super::Source;
+use crate::encoder::Scaler;
+use anyhow::{Result, anyhow, bail};
+use ffmpeg_next::{
+    Packet, Rational, codec::Context as CodecContext, codec::Id as CodecId, decoder, ffi, format,
+    frame, media::Type as MediaType,
+};
+use std::{
+    ptr, thread,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// 超过这个时间的 pts 跳变认为时间戳不连续 (比如直播流断开后重连), 重新开始计时
+const MAX_PTS_JUMP: Duration = Duration::from_secs(1);
+
+/// 直接发送时最多缓存的数据包数, 用于改为转码时从最近的 IDR 开始解码
+/// * 超过时 (IDR 间隔太长) 丢弃, 改为转码后到下一个 IDR 之前的画面可能不完整
+const MAX_GOP_PACKETS: usize = 600;
+
+/// H264 baseline profile, constrained baseline 还带有 constraint_set1 标志
+const H264_PROFILE_BASELINE: i32 = 66;
+const H264_CONSTRAINT_SET1: i32 = 1 << 9;
+
+/// 按照时间戳实时输出, 第一个时间戳对应开始输出的时刻
+struct Pacer {
+    anchor: Option<(Instant, Duration)>,
+}
+
+impl Pacer {
+    fn wait(&mut self, pts: Duration) {
+        let now = Instant::now();
+        let (start, first) = *self.anchor.get_or_insert((now, pts));
+        let target = start + pts.saturating_sub(first);
+        if pts < first || target > now + MAX_PTS_JUMP {
+            self.anchor = Some((now, pts));
+            return;
+        }
+
+        // 解码或者读取跟不上时不等待, 尽快追上
+        if target > now {
+            thread::sleep(target - now);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.anchor = None;
+    }
+}
+
+/// 通过 libavformat 读取的媒体文件或者输入 URL (RTSP / RTMP / SRT 等)
+/// * `-` 表示从标准输入读取, 比如 y4m 管道
+/// * 按照 pts 实时输出, 可以 seek 的输入 (文件) 结束后从头开始
+pub struct FileInput {
+    input: format::context::Input,
+    url: String,
+    stream_index: usize,
+    time_base: Rational,
+    frame_rate: Option<i32>,
+    decoder: decoder::Video,
+    pacer: Pacer,
+    divisor: u32,
+    scaler: Scaler,
+}
+
+impl FileInput {
+    pub fn open(url: &str) -> Result<Self> {
+        let url = if url == "-" { "pipe:0" } else { url };
+        let input = format::input(&url)?;
+        let stream = input
+            .streams()
+            .best(MediaType::Video)
+            .ok_or_else(|| anyhow!("No video stream in {}", url))?;
+        let stream_index = stream.index();
+        let time_base = stream.time_base();
+        let avg_frame_rate = stream.avg_frame_rate();
+        let frame_rate = (avg_frame_rate.numerator() > 0 && avg_frame_rate.denominator() > 0)
+            .then(|| (f64::from(avg_frame_rate).round() as i32).max(1));
+        let decoder = CodecContext::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+        info!(
+            "input {}: {:?} {}x{}, {:?} fps",
+            url,
+            decoder.id(),
+            decoder.width(),
+            decoder.height(),
+            frame_rate
+        );
+
+        Ok(Self {
+            input,
+            url: url.to_string(),
+            stream_index,
+            time_base,
+            frame_rate,
+            decoder,
+            pacer: Pacer { anchor: None },
+            divisor: 1,
+            scaler: Scaler::new(),
+        })
+    }
+
+    /// 输入的视频可以不经过解码和编码直接发送
+    /// * 只直接发送 level 已知的 H264 constrained baseline (带 constraint_set1), 推流时按照输入的 profile-level-id 声明
+    /// * 有 B 帧, 没有 constraint_set1 的 baseline 或者其他 profile 时需要转码
+    pub fn can_pass_through(&self) -> bool {
+        let Some(stream) = self.input.stream(self.stream_index) else {
+            return false;
+        };
+        let parameters = stream.parameters();
+        let (profile, video_delay) = unsafe {
+            let parameters = &*parameters.as_ptr();
+            (parameters.profile, parameters.video_delay)
+        };
+
+        parameters.id() == CodecId::H264
+            && profile == H264_PROFILE_BASELINE | H264_CONSTRAINT_SET1
+            && video_delay == 0
+            && self.profile_level_id().is_some()
+    }
+
+    /// 解码后画面的宽高
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.decoder.width(), self.decoder.height())
+    }
+
+    /// 直接发送时输入码流的 profile-level-id, level 未知时为 None
+    /// * FFmpeg 的 profile 只保留了 constraint_set1, 有这个标志时按 constrained baseline (42e0) 声明
+    pub fn profile_level_id(&self) -> Option<u32> {
+        let stream = self.input.stream(self.stream_index)?;
+        let (profile, level) = unsafe {
+            let parameters = &*stream.parameters().as_ptr();
+            (parameters.profile, parameters.level)
+        };
+        if !(1..=0xff).contains(&level) {
+            return None;
+        }
+
+        let profile_iop = if profile & H264_CONSTRAINT_SET1 != 0 {
+            0xe0
+        } else {
+            0
+        };
+        let profile_idc = (profile & 0xff) as u32;
+        Some((profile_idc << 16) | (profile_iop << 8) | level as u32)
+    }
+
+    /// 时间基为输入流的时间戳对应的时长, 负数按 0 处理
+    fn duration(&self, ts: i64) -> Duration {
+        Duration::from_secs_f64(ts.max(0) as f64 * f64::from(self.time_base))
+    }
+
+    /// 读取下一个视频数据包, 可以 seek 的输入结束后从头开始
+    fn read_packet(&mut self) -> Result<Packet> {
+        loop {
+            let next = self
+                .input
+                .packets()
+                .next()
+                .map(|(stream, packet)| (stream.index(), packet));
+            match next {
+                Some((index, packet)) if index == self.stream_index => return Ok(packet),
+                Some(_) => continue,
+                None => {
+                    let seekable = unsafe {
+                        let pb = (*self.input.as_ptr()).pb;
+                        !pb.is_null() && (*pb).seekable != 0
+                    };
+                    if !seekable {
+                        bail!("Input {} ended", self.url);
+                    }
+
+                    info!("input {} ended, looping", self.url);
+                    self.input.seek(0, ..)?;
+                    self.decoder.flush();
+                    self.pacer.reset();
+                }
+            }
+        }
+    }
+}
+
+impl Source for FileInput {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        while self.decoder.receive_frame(&mut frame).is_err() {
+            let packet = self.read_packet()?;
+            // 直播流中损坏的数据包只丢弃, 不结束推流
+            if let Err(e) = self.decoder.send_packet(&packet) {
+                warn!("decode {} error: {:?}", self.url, e);
+            }
+        }
+
+        if let Some(pts) = frame.timestamp() {
+            let pts = self.duration(pts);
+            self.pacer.wait(pts);
+        }
+
+        if self.divisor > 1 {
+            let format = frame.format();
+            return self.scaler.scale(&frame, self.divisor, format);
+        }
+
+        Ok(frame)
+    }
+
+    fn set_downscale(&mut self, divisor: u32) -> Result<bool> {
+        self.divisor = divisor;
+
+        Ok(true)
+    }
+
+    fn frame_rate(&self) -> Option<i32> {
+        self.frame_rate
+    }
+}
+
+/// 不解码, 把输入的 H264 数据包转换为 Annex B 格式后原样输出
+/// * MP4 / MKV 中的 H264 使用长度前缀, SPS / PPS 在 extradata 中, 由 h264_mp4toannexb 转换
+/// * 已经是 Annex B 的输入 (TS, 裸 H264) 不做改变
+/// * 缓存最近一个 IDR 开始的输入数据包, 需要改为转码时交给解码器
+pub struct H264Packets {
+    input: FileInput,
+    bsf: Bsf,
+    gop: Vec<Packet>,
+}
+
+/// drop 时释放的 bitstream filter
+struct Bsf(*mut ffi::AVBSFContext);
+
+impl H264Packets {
+    pub fn new(input: FileInput) -> Result<Self> {
+        let mut bsf = ptr::null_mut();
+        unsafe {
+            let filter = ffi::av_bsf_get_by_name(c"h264_mp4toannexb".as_ptr());
+            if filter.is_null() {
+                bail!("Missing bitstream filter h264_mp4toannexb");
+            }
+            check(ffi::av_bsf_alloc(filter, &mut bsf), "av_bsf_alloc")?;
+        }
+        // 之后出错时由 Drop 释放 bsf
+        let packets = Self {
+            input,
+            bsf: Bsf(bsf),
+            gop: vec![],
+        };
+
+        let stream = packets
+            .input
+            .input
+            .stream(packets.input.stream_index)
+            .ok_or_else(|| anyhow!("Missing video stream in {}", packets.input.url))?;
+        unsafe {
+            check(
+                ffi::avcodec_parameters_copy((*bsf).par_in, stream.parameters().as_ptr()),
+                "avcodec_parameters_copy",
+            )?;
+            (*bsf).time_base_in = packets.input.time_base.into();
+            check(ffi::av_bsf_init(bsf), "av_bsf_init")?;
+        }
+
+        Ok(packets)
+    }
+
+    /// 下一个 Annex B 格式的数据包, 按照 pts 实时输出
+    pub fn next(&mut self) -> Result<Packet> {
+        let mut packet = Packet::empty();
+        loop {
+            if unsafe { ffi::av_bsf_receive_packet(self.bsf.0, packet.as_mut_ptr()) } >= 0 {
+                return Ok(packet);
+            }
+
+            let mut input = self.input.read_packet()?;
+            if let Some(pts) = input.pts() {
+                let pts = self.input.duration(pts);
+                self.input.pacer.wait(pts);
+            }
+            if input.is_key() || self.gop.len() >= MAX_GOP_PACKETS {
+                self.gop.clear();
+            }
+            self.gop.push(input.clone());
+            check(
+                unsafe { ffi::av_bsf_send_packet(self.bsf.0, input.as_mut_ptr()) },
+                "av_bsf_send_packet",
+            )?;
+        }
+    }
+
+    /// 停止直接发送, 返回可以解码的输入
+    /// * 从最近的 IDR 开始解码到已经发送的位置, 丢弃这些画面, 之后 get_frame 从下一个数据包开始输出
+    pub fn into_input(self) -> Result<FileInput> {
+        let Self { mut input, gop, .. } = self;
+        for packet in &gop {
+            if let Err(e) = input.decoder.send_packet(packet) {
+                warn!("decode {} error: {:?}", input.url, e);
+            }
+        }
+        let mut frame = frame::Video::empty();
+        while input.decoder.receive_frame(&mut frame).is_ok() {}
+        info!(
+            "decoded {} buffered packets of {} for transcoding",
+            gop.len(),
+            input.url
+        );
+
+        Ok(input)
+    }
+}
+
+impl Drop for Bsf {
+    fn drop(&mut self) {
+        unsafe { ffi::av_bsf_free(&mut self.0) };
+    }
+}
+
+fn check(ret: i32, function: &str) -> Result<()> {
+    if ret < 0 {
+        bail!("{function} failed: {ret}");
+    }
+
+    Ok(())
+}
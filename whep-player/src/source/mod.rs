@@ -1,9 +1,29 @@
 use anyhow::Result;
-use ffmpeg_next::frame::video::Video;
+use ffmpeg_next::frame::{audio::Audio, video::Video};
 
+pub mod audio;
 #[cfg(target_os = "windows")]
 pub mod dxdup;
+pub mod file;
+pub mod testsrc;
+#[cfg(target_os = "linux")]
+pub mod x11grab;
 
 pub trait Source {
     fn get_frame(&mut self) -> Result<Video>;
+
+    /// 输出的宽高除以 divisor, 用于带宽不足时降低分辨率
+    /// * 返回 false 表示不支持缩小
+    fn set_downscale(&mut self, _divisor: u32) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// 画面来源自身的帧率 (比如媒体文件), 没有时使用命令行指定的帧率
+    fn frame_rate(&self) -> Option<i32> {
+        None
+    }
+}
+
+pub trait AudioSource {
+    fn get_frame(&mut self) -> Result<Audio>;
 }
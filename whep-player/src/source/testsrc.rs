@@ -0,0 +1,114 @@
+use super::{AudioSource, Source};
+use crate::encoder::{OPUS_SAMPLE_RATE, Scaler};
+use anyhow::{Result, anyhow};
+use ffmpeg_next::{
+    filter::{self, Graph},
+    frame,
+};
+use tracing::warn;
+use whep_player::VideoSource;
+
+/// 左上角的当前时间和帧序号
+const OVERLAY: &str = "drawtext=text='%{localtime} frame %{n}':fontsize=32:fontcolor=white:box=1:boxcolor=black@0.6:boxborderw=8:x=16:y=16";
+
+/// 用 lavfi 的 testsrc2 / smptebars 生成的测试画面, 用于没有显示器的机器 (比如 CI)
+/// * 画面上叠加当前时间和帧序号, 方便在播放端估计延迟和发现丢帧
+/// * realtime 滤镜按照帧率输出, 与真实的采集一样
+pub struct TestPattern {
+    graph: Graph,
+    divisor: u32,
+    scaler: Scaler,
+}
+
+impl TestPattern {
+    pub fn new(pattern: VideoSource, (width, height): (u32, u32), frame_rate: i32) -> Result<Self> {
+        let name = match pattern {
+            VideoSource::Smptebars => "smptebars",
+            _ => "testsrc2",
+        };
+        let source = format!(
+            "{}=size={}x{}:rate={},format=yuv420p",
+            name, width, height, frame_rate
+        );
+        // drawtext 需要 FFmpeg 编译时启用 libfreetype, 没有时只输出测试画面
+        let graph = match video_graph(&format!("{},{},realtime", source, OVERLAY)) {
+            Ok(graph) => graph,
+            Err(e) => {
+                warn!("Failed to draw clock on the test pattern: {:?}", e);
+                video_graph(&format!("{},realtime", source))?
+            }
+        };
+
+        Ok(Self {
+            graph,
+            divisor: 1,
+            scaler: Scaler::new(),
+        })
+    }
+}
+
+fn video_graph(spec: &str) -> Result<Graph> {
+    let mut graph = filter::Graph::new();
+
+    let buffer_sink =
+        filter::find("buffersink").ok_or_else(|| anyhow!("Failed to find buffersink filter"))?;
+
+    graph.add(&buffer_sink, "out", "")?;
+    graph.input("out", 0)?.parse(spec)?;
+    graph.validate()?;
+
+    Ok(graph)
+}
+
+impl Source for TestPattern {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        self.graph.get("out").unwrap().sink().frame(&mut frame)?;
+
+        // 缩小时不重建滤镜, 帧序号保持连续
+        if self.divisor > 1 {
+            let format = frame.format();
+            return self.scaler.scale(&frame, self.divisor, format);
+        }
+
+        Ok(frame)
+    }
+
+    fn set_downscale(&mut self, divisor: u32) -> Result<bool> {
+        self.divisor = divisor;
+
+        Ok(true)
+    }
+}
+
+/// lavfi 的 sine 生成的正弦波, 与测试画面一起使用
+pub struct SineAudio {
+    graph: Graph,
+}
+
+impl SineAudio {
+    pub fn new(frequency: u32) -> Result<Self> {
+        let mut graph = filter::Graph::new();
+
+        let buffer_sink = filter::find("abuffersink")
+            .ok_or_else(|| anyhow!("Failed to find abuffersink filter"))?;
+
+        graph.add(&buffer_sink, "out", "")?;
+        graph.input("out", 0)?.parse(&format!(
+            "sine=frequency={}:sample_rate={},arealtime",
+            frequency, OPUS_SAMPLE_RATE
+        ))?;
+        graph.validate()?;
+
+        Ok(Self { graph })
+    }
+}
+
+impl AudioSource for SineAudio {
+    fn get_frame(&mut self) -> Result<frame::Audio> {
+        let mut frame = frame::Audio::empty();
+        self.graph.get("out").unwrap().sink().frame(&mut frame)?;
+
+        Ok(frame)
+    }
+}
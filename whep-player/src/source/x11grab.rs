@@ -0,0 +1,81 @@
+use super::Source;
+use crate::encoder::Scaler;
+use anyhow::{Result, anyhow};
+use ffmpeg_next::{
+    Dictionary, codec::Context as CodecContext, decoder, device, format, frame,
+    media::Type as MediaType,
+};
+
+/// 通过 libavdevice 的 x11grab 采集 X 服务器的画面
+/// * 使用 DISPLAY 环境变量中的显示器, 没有设置时为 `:0`
+/// * 输出内存中的 BGR0 画面, 缩小时用 swscale 处理
+pub struct X11Grab {
+    input: format::context::Input,
+    stream_index: usize,
+    decoder: decoder::Video,
+    divisor: u32,
+    scaler: Scaler,
+}
+
+impl X11Grab {
+    pub fn new(frame_rate: i32) -> Result<Self> {
+        let display = std::env::var("DISPLAY").unwrap_or_else(|_| ":0".to_string());
+        let input_format = device::input::video()
+            .find(|f| f.name() == "x11grab")
+            .ok_or_else(|| anyhow!("Missing video input device format x11grab"))?;
+
+        let mut options = Dictionary::new();
+        options.set("framerate", &frame_rate.to_string());
+        options.set("draw_mouse", "1");
+        let input = format::open_with(&display, &input_format, options)?.input();
+        let stream = input
+            .streams()
+            .best(MediaType::Video)
+            .ok_or_else(|| anyhow!("No video stream in X display {}", display))?;
+        let stream_index = stream.index();
+        let decoder = CodecContext::from_parameters(stream.parameters())?
+            .decoder()
+            .video()?;
+
+        Ok(Self {
+            input,
+            stream_index,
+            decoder,
+            divisor: 1,
+            scaler: Scaler::new(),
+        })
+    }
+}
+
+impl Source for X11Grab {
+    fn get_frame(&mut self) -> Result<frame::Video> {
+        let mut frame = frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut frame).is_ok() {
+                break;
+            }
+
+            let (stream, packet) = self
+                .input
+                .packets()
+                .next()
+                .ok_or_else(|| anyhow!("X11 capture closed"))?;
+            if stream.index() == self.stream_index {
+                self.decoder.send_packet(&packet)?;
+            }
+        }
+
+        if self.divisor > 1 {
+            let format = frame.format();
+            return self.scaler.scale(&frame, self.divisor, format);
+        }
+
+        Ok(frame)
+    }
+
+    fn set_downscale(&mut self, divisor: u32) -> Result<bool> {
+        self.divisor = divisor;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,452 @@
+use crate::reconnect::ConnectionState;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use str0m::{
+    media::{MediaData, Mid},
+    stats::{MediaEgressStats, MediaIngressStats, PeerStats},
+};
+use tokio::sync::watch;
+use tracing::{error, info};
+use whep_player::StatsFormat;
+
+// 计算抖动时 network_time 的参照时间点
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Client::recv 产生的 str0m 统计数据
+#[derive(Debug)]
+pub enum StatsEvent {
+    Peer(PeerStats),
+    Ingress(MediaIngressStats),
+    Egress(MediaEgressStats),
+}
+
+/// 整个连接的统计
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PeerSnapshot {
+    pub bytes_rx: u64,
+    pub bytes_tx: u64,
+    pub rx_bitrate: u64, // bit/s
+    pub tx_bitrate: u64, // bit/s
+    pub ingress_loss: Option<f32>,
+    pub egress_loss: Option<f32>,
+    #[serde(skip)]
+    timestamp: Option<Instant>,
+}
+
+/// 某个媒体流 (mid + rid) 的收发统计
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MediaSnapshot {
+    pub mid: String,
+    pub rid: Option<String>,
+    pub bytes: u64,
+    pub packets: u64,
+    pub bitrate: u64, // bit/s
+    pub nacks: u64,
+    pub plis: u64,
+    pub firs: u64,
+    pub loss: Option<f32>,   // 丢包率 (0.0 ~ 1.0)
+    pub rtt_ms: Option<f32>, // 往返时延
+    #[serde(skip)]
+    timestamp: Option<Instant>,
+}
+
+/// 解码器的计数
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DecoderSnapshot {
+    pub frames_decoded: u64,
+    pub decode_errors: u64,
+    pub audio_packets_decoded: u64,
+    pub jitter_ms: f64, // 视频到达时间的抖动 (RFC 3550 6.4.1)
+    #[serde(skip)]
+    last_transit: Option<f64>,
+}
+
+/// 播放器的计数
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RendererSnapshot {
+    pub frames_rendered: u64,
+}
+
+/// 某一时刻的完整统计
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StatsSnapshot {
+    pub timestamp_ms: u64, // unix 时间戳
+    pub connection: ConnectionState,
+    pub peer: PeerSnapshot,
+    pub ingress: Vec<MediaSnapshot>,
+    pub egress: Vec<MediaSnapshot>,
+    pub decoder: DecoderSnapshot,
+    pub renderer: RendererSnapshot,
+}
+
+/// 统计数据的发布端
+/// * 收发循环、解码器和播放器共享同一个 Stats, 各自更新自己的部分
+/// * 调用者通过 `subscribe` 得到的 watch::Receiver 读取最新的快照
+#[derive(Clone)]
+pub struct Stats(Arc<watch::Sender<StatsSnapshot>>);
+
+impl Stats {
+    pub fn new() -> Self {
+        Self(Arc::new(watch::channel(StatsSnapshot::default()).0))
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<StatsSnapshot> {
+        self.0.subscribe()
+    }
+
+    pub fn update(&self, event: StatsEvent) {
+        self.0.send_modify(|snapshot| {
+            snapshot.timestamp_ms = unix_millis();
+            match event {
+                StatsEvent::Peer(stats) => {
+                    let peer = &mut snapshot.peer;
+                    if let Some(last) = peer.timestamp {
+                        peer.rx_bitrate =
+                            bitrate(peer.bytes_rx, stats.bytes_rx, last, stats.timestamp);
+                        peer.tx_bitrate =
+                            bitrate(peer.bytes_tx, stats.bytes_tx, last, stats.timestamp);
+                    }
+                    peer.bytes_rx = stats.bytes_rx;
+                    peer.bytes_tx = stats.bytes_tx;
+                    peer.ingress_loss = stats.ingress_loss_fraction;
+                    peer.egress_loss = stats.egress_loss_fraction;
+                    peer.timestamp = Some(stats.timestamp);
+                }
+                StatsEvent::Ingress(stats) => {
+                    let media = media_entry(
+                        &mut snapshot.ingress,
+                        stats.mid,
+                        stats.rid.map(|r| r.to_string()),
+                    );
+                    media.update(stats.bytes, stats.packets, stats.timestamp);
+                    media.nacks = stats.nacks;
+                    media.plis = stats.plis;
+                    media.firs = stats.firs;
+                    media.loss = stats.loss;
+                    media.rtt_ms = stats.rtt;
+                }
+                StatsEvent::Egress(stats) => {
+                    let media = media_entry(
+                        &mut snapshot.egress,
+                        stats.mid,
+                        stats.rid.map(|r| r.to_string()),
+                    );
+                    media.update(stats.bytes, stats.packets, stats.timestamp);
+                    media.nacks = stats.nacks;
+                    media.plis = stats.plis;
+                    media.firs = stats.firs;
+                    media.loss = stats.loss;
+                    media.rtt_ms = stats.rtt;
+                }
+            }
+        });
+    }
+
+    pub fn set_state(&self, state: ConnectionState) {
+        self.0.send_if_modified(|snapshot| {
+            if snapshot.connection == state {
+                return false;
+            }
+            info!("connection state: {}", state);
+            snapshot.connection = state;
+            true
+        });
+    }
+
+    /// 收到一帧视频数据, 更新到达时间的抖动
+    pub fn video_received(&self, media: &MediaData) {
+        let transit = media
+            .network_time
+            .duration_since(*EPOCH.get_or_init(Instant::now))
+            .as_secs_f64()
+            - media.time.as_seconds();
+        self.0.send_modify(|snapshot| {
+            let decoder = &mut snapshot.decoder;
+            if let Some(last) = decoder.last_transit {
+                let d = (transit - last).abs() * 1000.0;
+                decoder.jitter_ms += (d - decoder.jitter_ms) / 16.0;
+            }
+            decoder.last_transit = Some(transit);
+        });
+    }
+
+    pub fn frames_decoded(&self, frames: usize) {
+        self.0
+            .send_modify(|snapshot| snapshot.decoder.frames_decoded += frames as u64);
+    }
+
+    pub fn decode_error(&self) {
+        self.0
+            .send_modify(|snapshot| snapshot.decoder.decode_errors += 1);
+    }
+
+    pub fn audio_decoded(&self) {
+        self.0
+            .send_modify(|snapshot| snapshot.decoder.audio_packets_decoded += 1);
+    }
+
+    pub fn frame_rendered(&self) {
+        self.0
+            .send_modify(|snapshot| snapshot.renderer.frames_rendered += 1);
+    }
+
+    /// 换成某个会话的连接和解码统计, 保留播放器的计数
+    /// * 服务端每个会话有自己的 Stats, 正在显示的会话的统计同步到播放器使用的 Stats
+    pub fn show_session(&self, session: &StatsSnapshot) {
+        self.0.send_modify(|snapshot| {
+            *snapshot = StatsSnapshot {
+                renderer: snapshot.renderer.clone(),
+                ..session.clone()
+            };
+        });
+    }
+}
+
+impl MediaSnapshot {
+    fn update(&mut self, bytes: u64, packets: u64, timestamp: Instant) {
+        if let Some(last) = self.timestamp {
+            self.bitrate = bitrate(self.bytes, bytes, last, timestamp);
+        }
+        self.bytes = bytes;
+        self.packets = packets;
+        self.timestamp = Some(timestamp);
+    }
+}
+
+fn media_entry(list: &mut Vec<MediaSnapshot>, mid: Mid, rid: Option<String>) -> &mut MediaSnapshot {
+    let mid = mid.to_string();
+    let index = match list.iter().position(|m| m.mid == mid && m.rid == rid) {
+        Some(index) => index,
+        None => {
+            list.push(MediaSnapshot {
+                mid,
+                rid,
+                ..Default::default()
+            });
+            list.len() - 1
+        }
+    };
+    &mut list[index]
+}
+
+fn bitrate(last_bytes: u64, bytes: u64, last: Instant, now: Instant) -> u64 {
+    let elapsed = now.saturating_duration_since(last).as_secs_f64();
+    if elapsed <= 0.0 {
+        return 0;
+    }
+    (bytes.saturating_sub(last_bytes) as f64 * 8.0 / elapsed) as u64
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+const CSV_HEADER: &str = "timestamp_ms,bytes_rx,bytes_tx,rx_bitrate,tx_bitrate,ingress_loss,egress_loss,rtt_ms,nacks,plis,firs,frames_decoded,decode_errors,audio_packets_decoded,jitter_ms,frames_rendered,connection";
+
+impl StatsSnapshot {
+    /// CSV 中的一行, 各个媒体流的计数汇总在一起
+    fn csv_row(&self) -> String {
+        let media = self.ingress.iter().chain(self.egress.iter());
+        let rtt_ms = media
+            .clone()
+            .filter_map(|m| m.rtt_ms)
+            .fold(None, |max: Option<f32>, rtt| {
+                Some(max.map_or(rtt, |m| m.max(rtt)))
+            });
+        let (nacks, plis, firs) = media.fold((0, 0, 0), |(nacks, plis, firs), m| {
+            (nacks + m.nacks, plis + m.plis, firs + m.firs)
+        });
+        let optional = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or_default();
+
+        format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{:.3},{},{:?}",
+            self.timestamp_ms,
+            self.peer.bytes_rx,
+            self.peer.bytes_tx,
+            self.peer.rx_bitrate,
+            self.peer.tx_bitrate,
+            optional(self.peer.ingress_loss),
+            optional(self.peer.egress_loss),
+            optional(rtt_ms),
+            nacks,
+            plis,
+            firs,
+            self.decoder.frames_decoded,
+            self.decoder.decode_errors,
+            self.decoder.audio_packets_decoded,
+            self.decoder.jitter_ms,
+            self.renderer.frames_rendered,
+            self.connection,
+        )
+    }
+}
+
+/// 按固定间隔把最新的统计写入文件
+/// * json: 每行一个 JSON 对象 (JSON Lines)
+/// * csv: 第一行为表头, 之后每行一条汇总记录
+pub async fn write_periodically(
+    path: PathBuf,
+    format: StatsFormat,
+    interval: Duration,
+    stats: watch::Receiver<StatsSnapshot>,
+) {
+    let file = match File::create(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Failed to create stats file {:?}: {:?}", path, e);
+            return;
+        }
+    };
+    info!("writing stats to {:?} every {:?}", path, interval);
+
+    let mut writer = BufWriter::new(file);
+    if format == StatsFormat::Csv {
+        let _ = writeln!(writer, "{}", CSV_HEADER);
+    }
+
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let snapshot = stats.borrow().clone();
+        let line = match format {
+            StatsFormat::Json => match serde_json::to_string(&snapshot) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("Failed to serialize stats: {:?}", e);
+                    continue;
+                }
+            },
+            StatsFormat::Csv => snapshot.csv_row(),
+        };
+        if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+            error!("Failed to write stats file {:?}: {:?}", path, e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bitrate_from_byte_counters() {
+        let start = Instant::now();
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bitrate(1000, 63_500, start, later), 1_000_000);
+        // 时间没有前进, 或者计数回退 (重连后的新会话从 0 开始)
+        assert_eq!(bitrate(1000, 2000, start, start), 0);
+        assert_eq!(bitrate(1000, 2000, later, start), 0);
+        assert_eq!(bitrate(5000, 1000, start, later), 0);
+    }
+
+    #[test]
+    fn media_entry_per_mid_and_rid() {
+        let mut list = vec![];
+        let start = Instant::now();
+        media_entry(&mut list, Mid::from("0"), Some("h".to_string())).update(1000, 1, start);
+        media_entry(&mut list, Mid::from("0"), Some("l".to_string())).update(500, 1, start);
+        media_entry(&mut list, Mid::from("0"), Some("h".to_string())).update(
+            26_000,
+            20,
+            start + Duration::from_millis(200),
+        );
+        assert_eq!(list.len(), 2);
+
+        let high = &list[0];
+        assert_eq!(
+            (high.rid.as_deref(), high.bytes, high.packets),
+            (Some("h"), 26_000, 20)
+        );
+        assert_eq!(high.bitrate, 1_000_000);
+        // 第一次更新没有上一次的计数, 不计算码率
+        assert_eq!((list[1].rid.as_deref(), list[1].bitrate), (Some("l"), 0));
+    }
+
+    #[test]
+    fn csv_row_matches_header() {
+        let snapshot = StatsSnapshot {
+            timestamp_ms: 1000,
+            connection: ConnectionState::Reconnecting(2),
+            peer: PeerSnapshot {
+                bytes_rx: 10,
+                bytes_tx: 20,
+                rx_bitrate: 80,
+                tx_bitrate: 160,
+                ingress_loss: Some(0.25),
+                ..Default::default()
+            },
+            ingress: vec![
+                MediaSnapshot {
+                    nacks: 1,
+                    plis: 2,
+                    rtt_ms: Some(12.5),
+                    ..Default::default()
+                },
+                MediaSnapshot {
+                    nacks: 2,
+                    firs: 1,
+                    rtt_ms: Some(30.0),
+                    ..Default::default()
+                },
+            ],
+            egress: vec![MediaSnapshot {
+                nacks: 3,
+                plis: 1,
+                ..Default::default()
+            }],
+            decoder: DecoderSnapshot {
+                frames_decoded: 7,
+                decode_errors: 1,
+                audio_packets_decoded: 9,
+                jitter_ms: 1.23456,
+                ..Default::default()
+            },
+            renderer: RendererSnapshot { frames_rendered: 5 },
+        };
+
+        // 各个媒体流的 NACK / PLI / FIR 相加, RTT 取最大值, 没有的值留空
+        let row = snapshot.csv_row();
+        assert_eq!(
+            row,
+            "1000,10,20,80,160,0.25,,30,6,3,1,7,1,9,1.235,5,Reconnecting(2)"
+        );
+        assert_eq!(row.split(',').count(), CSV_HEADER.split(',').count());
+        assert_eq!(
+            StatsSnapshot::default().csv_row(),
+            "0,0,0,0,0,,,,0,0,0,0,0,0,0.000,0,Connecting"
+        );
+    }
+
+    #[test]
+    fn show_session_keeps_renderer() {
+        let (first, second, shown) = (Stats::new(), Stats::new(), Stats::new());
+        first.frames_decoded(3);
+        first.set_state(ConnectionState::Connected);
+        second.frames_decoded(10);
+        shown.frame_rendered();
+
+        // 会话之间的计数互不影响
+        shown.show_session(&first.subscribe().borrow());
+        let snapshot = shown.subscribe().borrow().clone();
+        assert_eq!(snapshot.decoder.frames_decoded, 3);
+        assert_eq!(snapshot.connection, ConnectionState::Connected);
+        assert_eq!(snapshot.renderer.frames_rendered, 1);
+
+        shown.show_session(&second.subscribe().borrow());
+        let snapshot = shown.subscribe().borrow().clone();
+        assert_eq!(snapshot.decoder.frames_decoded, 10);
+        assert_eq!(snapshot.connection, ConnectionState::Connecting);
+        assert_eq!(snapshot.renderer.frames_rendered, 1);
+    }
+}
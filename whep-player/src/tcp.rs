@@ -0,0 +1,376 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+use whep_player::SocketMode;
+
+// 主动连接对端 passive 候选者的超时
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// active 候选者的端口固定为 9 (RFC 6544 4.5)
+pub const ACTIVE_PORT: u16 = 9;
+
+/// 连接任务通知给 TcpTransport 的事件
+enum TcpEvent {
+    Connected {
+        remote: SocketAddr,
+        sender: UnboundedSender<Vec<u8>>,
+    },
+    Data {
+        remote: SocketAddr,
+        local: SocketAddr,
+        data: Vec<u8>,
+    },
+    Closed {
+        remote: SocketAddr,
+    },
+}
+
+/// ICE-TCP 传输 (RFC 6544)
+/// * passive: 在每个地址族上监听一个端口, 等待对端连接
+/// * active: 向对端的 passive 候选者发送数据时才建立连接
+/// * TCP 上的数据包使用 RFC 4571 分帧: 2 字节大端长度 + 数据
+pub struct TcpTransport {
+    listeners: Vec<SocketAddr>,
+    dual_stack: bool,
+    accept_tasks: Vec<JoinHandle<()>>,
+    connections: HashMap<SocketAddr, UnboundedSender<Vec<u8>>>, // 对端地址 -> 发送队列
+    events_tx: UnboundedSender<TcpEvent>,
+    events_rx: UnboundedReceiver<TcpEvent>,
+}
+
+impl TcpTransport {
+    pub fn listen(mode: SocketMode) -> io::Result<Self> {
+        let addrs: Vec<(SocketAddr, bool)> = match mode {
+            SocketMode::DualStack => vec![("[::]:0".parse().unwrap(), false)],
+            SocketMode::PerFamily => vec![
+                ("0.0.0.0:0".parse().unwrap(), false),
+                ("[::]:0".parse().unwrap(), true),
+            ],
+        };
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let mut listeners = vec![];
+        let mut accept_tasks = vec![];
+        for (addr, only_v6) in addrs {
+            let listener = match bind_listener(addr, only_v6) {
+                Ok(listener) => listener,
+                // 系统没有 IPv6 时只监听 IPv4
+                Err(e) if addr.is_ipv6() => {
+                    warn!("Failed to listen tcp on {}: {:?}", addr, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let local_addr = listener.local_addr()?;
+            info!("local tcp listener address: {}", local_addr);
+            listeners.push(local_addr);
+            accept_tasks.push(tokio::spawn(accept_loop(listener, events_tx.clone())));
+        }
+
+        Ok(Self {
+            dual_stack: mode == SocketMode::DualStack && !listeners.is_empty(),
+            listeners,
+            accept_tasks,
+            connections: HashMap::new(),
+            events_tx,
+            events_rx,
+        })
+    }
+
+    /// 某个地址族的 passive 监听端口
+    pub fn port(&self, ip: IpAddr) -> Option<u16> {
+        self.listeners
+            .iter()
+            .find(|addr| self.dual_stack || addr.is_ipv4() == ip.is_ipv4())
+            .map(|addr| addr.port())
+    }
+
+    /// 发送数据包, 到对端还没有连接时主动建立连接
+    pub fn send(&mut self, local: SocketAddr, remote: SocketAddr, data: &[u8]) {
+        if data.len() > u16::MAX as usize {
+            warn!("drop tcp packet to {}, too large: {}", remote, data.len());
+            return;
+        }
+
+        let sender = self
+            .connections
+            .entry(remote)
+            .or_insert_with(|| connect(local, remote, self.events_tx.clone()));
+        if sender.send(data.to_vec()).is_err() {
+            self.connections.remove(&remote);
+        }
+    }
+
+    /// 等待下一个数据包, 返回 (对端地址, 本地地址, 数据)
+    /// * 可以安全地被取消 (用于 `tokio::select!`)
+    pub async fn recv(&mut self) -> (SocketAddr, SocketAddr, Vec<u8>) {
+        loop {
+            // events_tx 一直被自己持有, 通道不会关闭
+            let event = self.events_rx.recv().await.expect("tcp events channel");
+            match event {
+                TcpEvent::Connected { remote, sender } => {
+                    info!("tcp connected from {}", remote);
+                    self.connections.insert(remote, sender);
+                }
+                TcpEvent::Data {
+                    remote,
+                    local,
+                    data,
+                } => return (remote, local, data),
+                TcpEvent::Closed { remote } => {
+                    info!("tcp connection to {} closed", remote);
+                    self.connections.remove(&remote);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for TcpTransport {
+    fn drop(&mut self) {
+        // 连接任务在发送队列关闭后会自己退出, 只需要停止监听
+        for task in &self.accept_tasks {
+            task.abort();
+        }
+    }
+}
+
+fn bind_listener(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(16)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+async fn accept_loop(listener: TcpListener, events_tx: UnboundedSender<TcpEvent>) {
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("tcp accept error: {:?}", e);
+                continue;
+            }
+        };
+        let remote = SocketAddr::new(remote.ip().to_canonical(), remote.port());
+        // 连接已经建立, 本地地址就是实际的网卡地址, 与 passive 候选者一致
+        let Ok(local) = stream.local_addr() else {
+            continue;
+        };
+        let local = SocketAddr::new(local.ip().to_canonical(), local.port());
+
+        let (sender, packets) = mpsc::unbounded_channel();
+        if events_tx
+            .send(TcpEvent::Connected { remote, sender })
+            .is_err()
+        {
+            break;
+        }
+        tokio::spawn(run_connection(
+            stream,
+            local,
+            remote,
+            packets,
+            events_tx.clone(),
+        ));
+    }
+}
+
+fn connect(
+    local: SocketAddr,
+    remote: SocketAddr,
+    events_tx: UnboundedSender<TcpEvent>,
+) -> UnboundedSender<Vec<u8>> {
+    let (sender, packets) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        debug!("tcp connecting to {}", remote);
+        match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(remote)).await {
+            Ok(Ok(stream)) => {
+                info!("tcp connected to {}", remote);
+                // active 候选者的地址是 (网卡地址, 9), 上报给 str0m 时使用候选者的地址
+                run_connection(stream, local, remote, packets, events_tx).await;
+            }
+            Ok(Err(e)) => {
+                debug!("tcp connect to {} error: {:?}", remote, e);
+                let _ = events_tx.send(TcpEvent::Closed { remote });
+            }
+            Err(_) => {
+                debug!("tcp connect to {} timed out", remote);
+                let _ = events_tx.send(TcpEvent::Closed { remote });
+            }
+        }
+    });
+
+    sender
+}
+
+async fn run_connection(
+    stream: TcpStream,
+    local: SocketAddr,
+    remote: SocketAddr,
+    mut packets: UnboundedReceiver<Vec<u8>>,
+    events_tx: UnboundedSender<TcpEvent>,
+) {
+    let _ = stream.set_nodelay(true);
+    let (mut reader, mut writer) = stream.into_split();
+
+    // 发送队列关闭 (TcpTransport 被释放) 后, writer 被关闭, 对端随之断开连接
+    tokio::spawn(async move {
+        while let Some(packet) = packets.recv().await {
+            if let Err(e) = writer.write_all(&frame(&packet)).await {
+                debug!("tcp write to {} error: {:?}", remote, e);
+                break;
+            }
+        }
+    });
+
+    while let Ok(data) = read_frame(&mut reader).await {
+        if events_tx
+            .send(TcpEvent::Data {
+                remote,
+                local,
+                data,
+            })
+            .is_err()
+        {
+            break;
+        }
+    }
+
+    let _ = events_tx.send(TcpEvent::Closed { remote });
+}
+
+/// RFC 4571 分帧: 2 字节大端长度 + 数据, 调用者保证数据不超过 65535 字节
+fn frame(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(packet.len() + 2);
+    frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    frame.extend_from_slice(packet);
+    frame
+}
+
+/// 读取一个 RFC 4571 帧, 连接断开 (包括断在帧的中间) 时返回错误
+async fn read_frame(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 2];
+    reader.read_exact(&mut len).await?;
+    let mut data = vec![0u8; u16::from_be_bytes(len) as usize];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// 补上 TCP 候选者的 tcptype (RFC 6544), 浏览器等实现要求必须带上
+/// * 端口为 9 的是 active 候选者, 其他的是 passive 候选者
+pub fn with_tcp_type(sdp: &str) -> String {
+    sdp.lines()
+        .map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let is_tcp_candidate = line.starts_with("a=candidate:")
+                && tokens.get(2).is_some_and(|t| t.eq_ignore_ascii_case("tcp"));
+            if !is_tcp_candidate || tokens.contains(&"tcptype") {
+                return line.to_string();
+            }
+
+            let tcp_type = match tokens.get(5).and_then(|p| p.parse::<u16>().ok()) {
+                Some(ACTIVE_PORT) => "active",
+                _ => "passive",
+            };
+            format!("{} tcptype {}", line, tcp_type)
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+        + "\r\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_prefixes_length() {
+        assert_eq!(frame(b"hello"), b"\x00\x05hello");
+        assert_eq!(frame(&[]), [0, 0]);
+        assert_eq!(&frame(&[1; 300])[..2], &[0x01, 0x2c]);
+    }
+
+    #[tokio::test]
+    async fn read_frames_from_stream() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        // 两个帧放在一次写入里, 第三个帧写到一半连接断开
+        let mut data = [frame(b"first"), frame(b""), frame(b"third")].concat();
+        data.truncate(data.len() - 2);
+        client.write_all(&data).await.unwrap();
+        drop(client);
+
+        assert_eq!(read_frame(&mut server).await.unwrap(), b"first");
+        assert_eq!(read_frame(&mut server).await.unwrap(), b"");
+        assert_eq!(
+            read_frame(&mut server).await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn tcp_type_for_candidates() {
+        let sdp = "v=0\r\n\
+                   a=candidate:1 1 TCP 1518280447 192.0.2.1 9 typ host\r\n\
+                   a=candidate:2 1 tcp 1518280447 192.0.2.1 50000 typ host\r\n\
+                   a=candidate:3 1 tcp 1518280447 192.0.2.1 50001 typ host tcptype so\r\n\
+                   a=candidate:4 1 udp 2130706431 192.0.2.1 9 typ host\r\n";
+        assert_eq!(
+            with_tcp_type(sdp),
+            "v=0\r\n\
+             a=candidate:1 1 TCP 1518280447 192.0.2.1 9 typ host tcptype active\r\n\
+             a=candidate:2 1 tcp 1518280447 192.0.2.1 50000 typ host tcptype passive\r\n\
+             a=candidate:3 1 tcp 1518280447 192.0.2.1 50001 typ host tcptype so\r\n\
+             a=candidate:4 1 udp 2130706431 192.0.2.1 9 typ host\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn passive_connection() {
+        let mut transport = TcpTransport::listen(SocketMode::PerFamily).unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let local = SocketAddr::new(ip, transport.port(ip).unwrap());
+
+        let mut stream = TcpStream::connect(local).await.unwrap();
+        stream.write_all(&frame(b"ping")).await.unwrap();
+        let (remote, received_local, data) = transport.recv().await;
+        assert_eq!(remote, stream.local_addr().unwrap());
+        assert_eq!(received_local, local);
+        assert_eq!(data, b"ping");
+
+        // 使用已经建立的连接回复
+        transport.send(local, remote, b"pong");
+        assert_eq!(read_frame(&mut stream).await.unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn active_connection() {
+        let mut transport = TcpTransport::listen(SocketMode::PerFamily).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = listener.local_addr().unwrap();
+        let local: SocketAddr = "127.0.0.1:9".parse().unwrap();
+
+        transport.send(local, remote, b"ping");
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_frame(&mut stream).await.unwrap(), b"ping");
+
+        // 对端的数据上报为发往 active 候选者的地址
+        stream.write_all(&frame(b"pong")).await.unwrap();
+        assert_eq!(transport.recv().await, (remote, local, b"pong".to_vec()));
+    }
+}
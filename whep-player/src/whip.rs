@@ -1,121 +1,514 @@
 use crate::EncodedPacket;
-use crate::client::{Client, WebrtcEvent};
+use crate::bitrate::{BitrateConfig, BitrateController};
+use crate::client::{ChannelMessage, Client, ClientConfig, WebrtcError, WebrtcEvent};
+use crate::decoder::{AudioDecoder, VideoDecoder};
+use crate::encoder::{EncoderControl, OPUS_SAMPLE_RATE};
+use crate::reconnect::{ConnectionState, ReconnectConfig, SessionEnd, Supervisor};
+use crate::simulcast::LayerSelector;
+use crate::stats::{Stats, StatsEvent};
 use bytes::Bytes;
-use ffmpeg_next;
-use futures::executor;
-use std::{sync::mpsc, time::Instant};
-use str0m::media::Direction as RtcDirection;
-use tokio::sync::mpsc::{UnboundedReceiver, error::TryRecvError};
-use tracing::{error, info};
+use ffmpeg_next::{self, Packet};
+use std::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use str0m::{
+    channel::ChannelData,
+    format::Codec,
+    media::{Direction as RtcDirection, KeyframeRequestKind, Rid},
+};
+use tokio::{
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender, error::TryRecvError},
+        watch,
+    },
+    task::JoinHandle,
+};
+use tracing::{debug, error, info, warn};
 
+/// 会话的数据通道与应用之间的消息通道
+/// * 重连后继续使用, 数据通道没有打开时要发送的消息会被丢弃
+pub struct DataChannel {
+    pub incoming: UnboundedSender<ChannelData>, // 数据通道上收到的消息
+    pub outgoing: UnboundedReceiver<ChannelMessage>, // 要在数据通道上发送的消息
+}
+
+/// 把收到的消息交给应用
+fn receive_channel_data(data: &Option<DataChannel>, message: ChannelData) {
+    if let Some(data) = data {
+        let _ = data.incoming.send(message);
+    }
+}
+
+/// 发送应用排队的数据通道消息
+fn send_channel_messages(client: &mut Client, data: &mut Option<DataChannel>) {
+    let Some(data) = data.as_mut() else {
+        return;
+    };
+    while let Ok(message) = data.outgoing.try_recv() {
+        if let Err(err) = client.send_data(&message) {
+            warn!("send data error: {:?}", err);
+        }
+    }
+}
+
+/// 创建 Client 并发送 WHIP/WHEP 请求
+/// * 协商不出可用的编解码器时重连也没有意义, 直接停止
+async fn connect(
+    url: &str,
+    token: &Option<String>,
+    config: &ClientConfig,
+    direction: RtcDirection,
+) -> Result<Client, SessionEnd> {
+    info!("creating client for {} with token: {:?}", url, token);
+
+    let lost = SessionEnd::Lost { connected: false };
+    let mut client = Client::new(config).await.map_err(|err| {
+        error!("create client error: {:?}", err);
+        lost
+    })?;
+    match client.send_whip_request(url, token, direction).await {
+        Ok(()) => Ok(client),
+        Err(err @ WebrtcError::NoCompatibleCodec(_)) => {
+            error!("connect error: {:?}", err);
+            Err(SessionEnd::Stopped)
+        }
+        Err(err) => {
+            error!("connect error: {:?}", err);
+            Err(lost)
+        }
+    }
+}
+
+/// 推流, 连接断开后按照 reconnect 的配置重连
+/// * 编码器在重连期间保持运行, 断线期间编码的数据会被丢弃
+/// * 对端请求关键帧 (PLI/FIR), (重新) 连接成功, 以及带宽估计变化时通过 control_tx 通知编码线程
+#[allow(clippy::too_many_arguments)]
 pub async fn publish(
     publish_url: &str,
     token: Option<String>,
+    config: &ClientConfig,
     mut packet_rx: UnboundedReceiver<EncodedPacket>,
+    mut audio_rx: Option<UnboundedReceiver<Packet>>,
+    control_tx: mpsc::Sender<EncoderControl>,
+    bitrate: BitrateConfig,
+    mut data: Option<DataChannel>,
+    stats: Stats,
+    reconnect: ReconnectConfig,
+    shutdown: watch::Receiver<bool>,
 ) {
-    info!(
-        "creating client to push to {} with token: {:?}",
-        publish_url, token
-    );
+    let mut supervisor = Supervisor::new(reconnect, stats.clone(), shutdown.clone());
+    loop {
+        supervisor.connecting();
+        let end = match connect(publish_url, &token, config, RtcDirection::SendOnly).await {
+            Ok(client) => {
+                publish_loop(
+                    client,
+                    &mut packet_rx,
+                    &mut audio_rx,
+                    &control_tx,
+                    BitrateController::new(bitrate),
+                    &mut data,
+                    &stats,
+                    shutdown.clone(),
+                )
+                .await
+            }
+            Err(end) => end,
+        };
+        if !supervisor.should_reconnect(end).await {
+            break;
+        }
+
+        // 断线期间编码的数据已经过时
+        while packet_rx.try_recv().is_ok() {}
+        if let Some(rx) = audio_rx.as_mut() {
+            while rx.try_recv().is_ok() {}
+        }
+    }
+}
 
-    let mut client = Client::new().await.unwrap();
-    client
-        .send_whip_request(&publish_url, &token, RtcDirection::SendOnly)
-        .await
-        .expect("should connect");
+async fn publish_loop(
+    mut client: Client,
+    packet_rx: &mut UnboundedReceiver<EncodedPacket>,
+    audio_rx: &mut Option<UnboundedReceiver<Packet>>,
+    control_tx: &mpsc::Sender<EncoderControl>,
+    mut bitrate: BitrateController,
+    data: &mut Option<DataChannel>,
+    stats: &Stats,
+    mut shutdown: watch::Receiver<bool>,
+) -> SessionEnd {
+    let mut connected = false;
+    let end = 'publish: loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            _ = shutdown.changed() => {
+                info!("shutdown");
+                break SessionEnd::Stopped;
+            }
+        };
 
-    loop {
-        match client.recv().await {
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
                     info!("disconnected");
-                    break;
+                    break SessionEnd::Lost { connected };
+                }
+                WebrtcEvent::Connected => {
+                    connected = true;
+                    stats.set_state(ConnectionState::Connected);
+                    // 新的会话需要从关键帧开始解码, 带宽估计也从初始值重新开始
+                    let _ = control_tx.send(EncoderControl::KeyFrame(None));
+                    for control in bitrate.initial() {
+                        let _ = control_tx.send(control);
+                    }
+                    client.set_bitrate(bitrate.target(), bitrate.max());
                 }
                 WebrtcEvent::Media(_) => {
                     panic!("Publisher incorrectly has incoming media");
                 }
-                WebrtcEvent::Continue => loop {
-                    let packet = packet_rx.try_recv();
-                    match packet {
-                        Err(TryRecvError::Empty | TryRecvError::Disconnected) => break,
-                        Ok(packet) => {
-                            let pts = Instant::now() - packet.1;
-                            if let Some(data) = packet.0.data() {
-                                client
-                                    .send_video(Bytes::copy_from_slice(data), pts)
-                                    .unwrap();
+                WebrtcEvent::KeyframeRequest(request) => {
+                    info!("keyframe requested: {:?} {:?}", request.rid, request.kind);
+                    // 编码线程退出时 packet 通道也会关闭, 在下面处理
+                    let layer = client.send_layer(request.rid);
+                    let _ = control_tx.send(EncoderControl::KeyFrame(layer));
+                }
+                WebrtcEvent::BandwidthEstimate(estimate) => {
+                    let controls = bitrate.estimate(estimate);
+                    if controls
+                        .iter()
+                        .any(|c| matches!(c, EncoderControl::Bitrate(_)))
+                    {
+                        client.set_bitrate(bitrate.target(), bitrate.max());
+                    }
+                    for control in controls {
+                        let _ = control_tx.send(control);
+                    }
+                }
+                WebrtcEvent::Stats(event) => stats.update(event),
+                WebrtcEvent::ChannelData(message) => receive_channel_data(data, message),
+                WebrtcEvent::Continue => {
+                    send_channel_messages(&mut client, data);
+                    loop {
+                        let packet = packet_rx.try_recv();
+                        match packet {
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => {
+                                info!("encoder stopped");
+                                break 'publish SessionEnd::Stopped;
+                            }
+                            Ok(packet) => {
+                                let pts = Instant::now() - packet.1;
+                                // 发送失败只丢弃这一帧, 连接的问题由 recv 报告
+                                if let Some(data) = packet.0.data()
+                                    && let Err(e) = client.send_video(
+                                        packet.2,
+                                        Bytes::copy_from_slice(data),
+                                        pts,
+                                    )
+                                {
+                                    warn!("drop video frame: {:?}", e);
+                                }
                             }
                         }
                     }
-                },
+
+                    // 音频采集失败时只停止发送音频, 视频继续推流
+                    while let Some(rx) = audio_rx.as_mut() {
+                        match rx.try_recv() {
+                            Err(TryRecvError::Empty) => break,
+                            Err(TryRecvError::Disconnected) => {
+                                info!("audio encoder stopped");
+                                *audio_rx = None;
+                            }
+                            Ok(packet) => {
+                                // 数据包的 pts 以采样数为单位
+                                let pts = Duration::from_micros(
+                                    packet.pts().unwrap_or(0) as u64 * 1_000_000
+                                        / OPUS_SAMPLE_RATE as u64,
+                                );
+                                if let Some(data) = packet.data()
+                                    && let Err(e) =
+                                        client.send_audio(Bytes::copy_from_slice(data), pts)
+                                {
+                                    warn!("drop audio packet: {:?}", e);
+                                }
+                            }
+                        }
+                    }
+                }
             },
             Err(err) => {
                 error!("error: {:?}", err);
-                break;
+                break SessionEnd::Lost { connected };
             }
         }
+    };
+
+    if let Err(err) = client.close().await {
+        error!("close session error: {:?}", err);
     }
+    end
 }
 
-pub async fn decode_recv_loop(mut client: Client, tx: mpsc::Sender<ffmpeg_next::frame::Video>) {
-    let codec = ffmpeg_next::decoder::find_by_name("h264").expect("H264 Decoder Available");
-    let context = ffmpeg_next::codec::context::Context::new_with_codec(codec);
-    let mut decoder = context.decoder().video().expect("Decoder init correctly");
+/// 两次关键帧请求之间的最小间隔, 避免丢包严重时向对端发送大量请求
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(500);
 
-    loop {
-        match client.recv().await {
+/// 解码出问题时向对端请求关键帧
+/// * 开始播放或者切换解码器后还没有收到关键帧
+/// * 数据不连续 (中间有帧丢失), 解码出错, 或者解码出的画面缺少参考帧
+/// * 收到关键帧之前按照最小间隔重复请求
+struct KeyframeRequester {
+    waiting: bool,
+    last_request: Option<Instant>,
+}
+
+impl KeyframeRequester {
+    fn new() -> Self {
+        Self {
+            waiting: true,
+            last_request: None,
+        }
+    }
+
+    fn need(&mut self, reason: &str) {
+        if !self.waiting {
+            info!("keyframe needed: {}", reason);
+        }
+        self.waiting = true;
+    }
+
+    fn frame_decoded(&mut self, frame: &ffmpeg_next::frame::Video) {
+        if frame.is_corrupt() {
+            self.need("corrupt frame");
+        } else if frame.is_key() {
+            self.waiting = false;
+        }
+    }
+
+    fn poll(&mut self, client: &mut Client, rid: Option<Rid>) {
+        if !self.waiting
+            || self
+                .last_request
+                .is_some_and(|last| last.elapsed() < KEYFRAME_REQUEST_INTERVAL)
+        {
+            return;
+        }
+
+        self.last_request = Some(Instant::now());
+        if let Err(err) = client.request_keyframe(rid, KeyframeRequestKind::Pli) {
+            debug!("request keyframe error: {:?}", err);
+        }
+    }
+}
+
+pub async fn decode_recv_loop(
+    mut client: Client,
+    tx: &mpsc::Sender<ffmpeg_next::frame::Video>,
+    audio_tx: &mpsc::Sender<Vec<f32>>,
+    stats: &Stats,
+    layer: Option<&str>,
+    data: &mut Option<DataChannel>,
+    mut shutdown: watch::Receiver<bool>,
+) -> SessionEnd {
+    let mut layers = LayerSelector::new(layer);
+    let mut audio_decoder = AudioDecoder::new().expect("Opus Decoder Available");
+    // 第一次收到视频数据时才能确定 payload type, 对端切换编解码器时重新创建解码器
+    let mut decoder: Option<VideoDecoder> = None;
+    let mut keyframe = KeyframeRequester::new();
+    let mut connected = false;
+
+    let end = 'recv: loop {
+        let event = tokio::select! {
+            event = client.recv() => event,
+            _ = shutdown.changed() => {
+                info!("shutdown");
+                break SessionEnd::Stopped;
+            }
+        };
+
+        match event {
             Ok(event) => match event {
                 WebrtcEvent::Disconnected => {
                     info!("disconnected");
-                    break;
+                    break SessionEnd::Lost { connected };
                 }
-                WebrtcEvent::Media(media) => {
-                    // Decoder failures may happen, ignore them
-                    match decoder.send_packet(&ffmpeg_next::Packet::borrow(&media.data)) {
-                        Err(_) => continue,
+                WebrtcEvent::Connected => {
+                    connected = true;
+                    stats.set_state(ConnectionState::Connected);
+                    // 经过 SFU 时请求转发指定的层, 对端直接发送多个层时在本地选择
+                    if let Some(rid) = layers.fixed() {
+                        select_layer(&mut client, rid).await;
+                    }
+                }
+                WebrtcEvent::Media(media) if media.params.spec().codec == Codec::Opus => {
+                    match audio_decoder.decode(&media.data) {
+                        // 播放窗口关闭时视频通道也会关闭, 由视频负责退出
+                        Ok(samples) if !samples.is_empty() => {
+                            stats.audio_decoded();
+                            let _ = audio_tx.send(samples);
+                        }
                         Ok(_) => {}
+                        Err(err) => debug!("audio decode error: {:?}", err),
+                    }
+                }
+                WebrtcEvent::Media(media) => {
+                    let previous = layers.local_rid();
+                    if !layers.accept(media.rid) {
+                        continue;
+                    }
+                    // 选择的层停止后换成了其他层, 需要从新的层的关键帧开始解码
+                    if previous.is_some() && layers.local_rid() != previous {
+                        keyframe.need("layer switch");
+                    }
+                    stats.video_received(&media);
+                    if decoder.as_ref().is_none_or(|d| d.pt() != media.pt) {
+                        decoder = match VideoDecoder::new(&media.params) {
+                            Ok(decoder) => {
+                                keyframe.need("new decoder");
+                                Some(decoder)
+                            }
+                            Err(err) => {
+                                error!("create decoder error: {:?}", err);
+                                continue;
+                            }
+                        };
+                    }
+                    let Some(decoder) = decoder.as_mut() else {
+                        continue;
                     };
 
-                    let mut frame = ffmpeg_next::frame::Video::empty();
-                    while decoder.receive_frame(&mut frame).is_ok() {
-                        tx.send(frame).expect("pushed");
-                        frame = ffmpeg_next::frame::Video::empty();
+                    if !media.contiguous {
+                        keyframe.need("frames lost");
+                    }
+
+                    // Decoder failures may happen, request a keyframe to recover
+                    let Ok(frames) = decoder.decode(&media.data) else {
+                        stats.decode_error();
+                        keyframe.need("decode error");
+                        keyframe.poll(&mut client, layers.local_rid());
+                        continue;
+                    };
+                    stats.frames_decoded(frames.len());
+                    for frame in &frames {
+                        keyframe.frame_decoded(frame);
+                    }
+                    keyframe.poll(&mut client, layers.local_rid());
+
+                    for frame in frames {
+                        if tx.send(frame).is_err() {
+                            // 播放窗口已经关闭
+                            info!("player closed");
+                            break 'recv SessionEnd::Stopped;
+                        }
+                    }
+                }
+                WebrtcEvent::Stats(event) => {
+                    let switched = match &event {
+                        StatsEvent::Ingress(ingress) if Some(ingress.mid) == client.video_mid() => {
+                            layers.ingress(ingress)
+                        }
+                        _ => None,
+                    };
+                    stats.update(event);
+                    if let Some(rid) = switched {
+                        if layers.local_rid().is_none() {
+                            select_layer(&mut client, rid).await;
+                        }
+                        keyframe.need("layer switch");
+                        keyframe.poll(&mut client, layers.local_rid());
                     }
                 }
+                // 只接收, 不会有对端请求关键帧, 也没有发送方向的带宽估计
+                WebrtcEvent::KeyframeRequest(_) | WebrtcEvent::BandwidthEstimate(_) => {}
+                WebrtcEvent::ChannelData(message) => receive_channel_data(data, message),
                 WebrtcEvent::Continue => {
                     info!("Continue");
+                    send_channel_messages(&mut client, data);
                 }
             },
             Err(err) => {
                 error!("error: {:?}", err);
-                break;
+                break SessionEnd::Lost { connected };
             }
         }
+    };
+
+    if let Err(err) = client.close().await {
+        error!("close session error: {:?}", err);
+    }
+    end
+}
+
+/// 请求 SFU 转发指定的 simulcast 层
+/// * 失败时继续播放当前的层
+async fn select_layer(client: &mut Client, rid: Rid) {
+    match client.select_layer(rid).await {
+        Ok(true) => info!("selected simulcast layer {}", rid),
+        Ok(false) => debug!("layer selection not supported by the server"),
+        Err(err) => error!("select layer error: {:?}", err),
     }
 }
 
-pub async fn subscribe_as_client(
+/// 作为 WHEP 客户端拉流, 连接断开后按照 reconnect 的配置重连
+/// * 重连期间 tx 一直保持打开, 播放窗口不会关闭; 放弃重连后 tx 被释放, 播放窗口随之退出
+#[allow(clippy::too_many_arguments)]
+pub fn subscribe_as_client(
     tx: mpsc::Sender<ffmpeg_next::frame::Video>,
-    publish_url: &str,
+    audio_tx: mpsc::Sender<Vec<f32>>,
+    stats: Stats,
+    publish_url: String,
     token: Option<String>,
-) {
-    let mut client = Client::new().await.unwrap();
-    client
-        .send_whip_request(&publish_url, &token, RtcDirection::RecvOnly)
-        .await
-        .expect("should connect");
-
+    config: ClientConfig,
+    reconnect: ReconnectConfig,
+    mut data: Option<DataChannel>,
+    shutdown: watch::Receiver<bool>,
+) -> JoinHandle<()> {
     tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
-    });
+        let mut supervisor = Supervisor::new(reconnect, stats.clone(), shutdown.clone());
+        loop {
+            supervisor.connecting();
+            let end = match connect(&publish_url, &token, &config, RtcDirection::RecvOnly).await {
+                Ok(client) => {
+                    decode_recv_loop(
+                        client,
+                        &tx,
+                        &audio_tx,
+                        &stats,
+                        config.simulcast_layer.as_deref(),
+                        &mut data,
+                        shutdown.clone(),
+                    )
+                    .await
+                }
+                Err(end) => end,
+            };
+            if !supervisor.should_reconnect(end).await {
+                break;
+            }
+        }
+    })
 }
 
-pub fn subscribe_as_server(tx: mpsc::Sender<ffmpeg_next::frame::Video>, offer: String) -> String {
-    let mut client = executor::block_on(Client::new()).expect("Ok");
-    let answer = client.accept_whip_request(offer).expect("Ok");
-    tokio::task::spawn(async move {
-        decode_recv_loop(client, tx).await;
-    });
-
-    answer
+/// 作为 WHIP 服务端接收一个会话, 直到会话结束
+/// * 作为服务端无法主动重连, 由对端重新发起 WHIP 请求
+pub async fn subscribe_as_server(
+    client: Client,
+    tx: mpsc::Sender<ffmpeg_next::frame::Video>,
+    audio_tx: mpsc::Sender<Vec<f32>>,
+    stats: Stats,
+    config: &ClientConfig,
+    mut data: Option<DataChannel>,
+    shutdown: watch::Receiver<bool>,
+) -> SessionEnd {
+    decode_recv_loop(
+        client,
+        &tx,
+        &audio_tx,
+        &stats,
+        config.simulcast_layer.as_deref(),
+        &mut data,
+        shutdown,
+    )
+    .await
 }